@@ -0,0 +1,23 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+	// Bake in which commit we were built from, builds outside of git just say unknown
+	let commit = Command::new("git")
+		.args(["rev-parse", "--short", "HEAD"])
+		.output()
+		.ok()
+		.filter(|o| o.status.success())
+		.and_then(|o| String::from_utf8(o.stdout).ok())
+		.map(|s| s.trim().to_owned())
+		.unwrap_or_else(|| "unknown".to_owned());
+	let built_at = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0);
+
+	println!("cargo:rustc-env=WATAME_COMMIT={}", commit);
+	println!("cargo:rustc-env=WATAME_BUILT_AT={}", built_at);
+	println!("cargo:rerun-if-changed=.git/HEAD");
+	println!("cargo:rerun-if-changed=.git/refs");
+}
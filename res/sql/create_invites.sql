@@ -0,0 +1,11 @@
+CREATE TABLE "invites"
+(
+    "code"          text NOT NULL,
+    "creator"       integer NOT NULL,
+    "create_date"   timestamp with time zone NOT NULL DEFAULT now(),
+    "used_by"       integer NULL,
+    "used_date"     timestamp with time zone NULL,
+    CONSTRAINT "pk_invitecode" PRIMARY KEY ( "code" ),
+    CONSTRAINT "fk_invite_creator" FOREIGN KEY ( "creator" ) REFERENCES "users" ( "id" ),
+    CONSTRAINT "fk_invite_used_by" FOREIGN KEY ( "used_by" ) REFERENCES "users" ( "id" )
+);
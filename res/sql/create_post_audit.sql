@@ -0,0 +1,18 @@
+CREATE TYPE auditaction AS ENUM (
+    'Delete', 'Restore', 'Edit', 'Lock', 'Unlock', 'Purge'
+);
+
+-- uid has no foreign key so entries keep naming whoever acted even once their
+-- account has been deleted
+CREATE TABLE "post_audit"
+(
+    "id"            bigserial NOT NULL,
+    "post_id"       bigint NOT NULL,
+    "uid"           integer NOT NULL,
+    "action"        auditaction NOT NULL,
+    "detail"        jsonb NOT NULL DEFAULT '{}',
+    "created"       timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT "pk_auditid" PRIMARY KEY ( "id" )
+);
+
+CREATE INDEX "idx_post_audit_post_id" ON "post_audit" USING btree (post_id, created);
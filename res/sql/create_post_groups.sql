@@ -0,0 +1,8 @@
+CREATE TABLE "post_groups"
+(
+    "id"            bigserial NOT NULL,
+    "poster"        integer NOT NULL,
+    "create_date"   timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT "pk_groupid" PRIMARY KEY ( "id" ),
+    CONSTRAINT "fk_group_poster" FOREIGN KEY ( "poster" ) REFERENCES "users" ( "id" )
+);
@@ -0,0 +1,12 @@
+-- What an upload sent with an Idempotency-Key answered, written in the same
+-- transaction as its posts so a retry can't make a duplicate once redis has let the
+-- key go
+CREATE TABLE "post_idempotency"
+(
+    "key"           text NOT NULL,
+    "response"      text NOT NULL,
+    "created"       timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT "pk_idempotency_key" PRIMARY KEY ( "key" )
+);
+
+CREATE INDEX "idx_post_idempotency_created" ON "post_idempotency" USING btree (created);
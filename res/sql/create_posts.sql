@@ -2,10 +2,6 @@ CREATE TYPE imgext AS ENUM (
     'Bmp', 'Gif', 'Jpg', 'Png', 'Tiff', 'Webp'
 );
 
-CREATE TYPE rating AS ENUM (
-    'Safe', 'Sketchy', 'Explicit'
-);
-
 CREATE TABLE "posts"
 (
     "id"            bigserial NOT NULL,
@@ -25,10 +21,17 @@ CREATE TABLE "posts"
     "width"         integer NOT NULL,
     "height"        integer NOT NULL,
     "is_deleted"    boolean NOT NULL DEFAULT false,
+    "deleted_at"    timestamp with time zone NULL,
+    "group_id"      bigint NULL,
+    "created_ip"    inet NULL,
+    "is_locked"     boolean NOT NULL DEFAULT false,
     CONSTRAINT "pk_postid" PRIMARY KEY ( "id" ),
-    CONSTRAINT "fk_poster" FOREIGN KEY ( "poster" ) REFERENCES "users" ( "id" )
+    CONSTRAINT "fk_poster" FOREIGN KEY ( "poster" ) REFERENCES "users" ( "id" ),
+    CONSTRAINT "fk_group" FOREIGN KEY ( "group_id" ) REFERENCES "post_groups" ( "id" )
 );
 
 CREATE INDEX "idx_posts_create_date" ON "posts" USING btree (create_date);
+CREATE INDEX "idx_posts_modified_date" ON "posts" USING btree (modified_date);
 CREATE INDEX "idx_posts_tag_vector" ON "posts" USING gin (tag_vector);
 CREATE INDEX "idx_posts_is_deleted" ON "posts" USING btree (is_deleted);
+CREATE INDEX "idx_posts_group_id" ON "posts" USING btree (group_id);
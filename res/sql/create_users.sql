@@ -2,6 +2,10 @@ CREATE TYPE perms AS ENUM (
     'Guest', 'User', 'Moderator', 'Admin'
 );
 
+CREATE TYPE rating AS ENUM (
+    'Safe', 'Sketchy', 'Explicit'
+);
+
 CREATE TABLE "users"
 (
     "id"          serial NOT NULL,
@@ -10,8 +14,12 @@ CREATE TABLE "users"
     "pass"        text NOT NULL,
     "picture"     text NOT NULL DEFAULT '/s/pfp/default.png',
     "permissions" perms NOT NULL DEFAULT 'User',
+    "default_rating_filter" rating[] NOT NULL DEFAULT '{}',
+    "created_ip"  inet NULL,
     CONSTRAINT "PK_userid" PRIMARY KEY ( "id" ),
     UNIQUE (name)
 );
 
 INSERT INTO users (id, name, pass, permissions) VALUES (0, 'wadmin', 'password!', 'Admin');
+-- Content of deleted accounts is handed over to this user, it can never log in
+INSERT INTO users (id, name, pass, permissions) VALUES (-1, '[deleted]', '!', 'Guest');
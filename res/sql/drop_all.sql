@@ -1,10 +1,19 @@
+DROP TABLE IF EXISTS post_idempotency;
+
+DROP TABLE IF EXISTS post_audit;
+	DROP TYPE IF EXISTS auditaction;
+
 DROP TABLE IF EXISTS posts;
 	DROP TYPE IF EXISTS imgext;
-	DROP TYPE IF EXISTS rating;
+
+DROP TABLE IF EXISTS post_groups;
+
+DROP TABLE IF EXISTS invites;
 
 DROP TABLE IF EXISTS tags;
 
 DROP TABLE IF EXISTS users;
 	DROP TYPE IF EXISTS perms;
+	DROP TYPE IF EXISTS rating;
 
 DROP EXTENSION IF EXISTS tag_parser CASCADE;
@@ -1,15 +1,18 @@
 use crate::database::{enums::Perms, user};
-use crate::{error::APIError, try500};
+use crate::error::APIError;
+use crate::limits;
 
 use actix_web::{
+	cookie::{time::Duration, Cookie, SameSite},
 	dev::{self, Service, ServiceRequest, ServiceResponse},
-	http::header,
+	http::header::{self, HeaderMap},
 	web::Data,
 	Error, HttpMessage, HttpRequest,
 };
 use futures::future::{ready, FutureExt, LocalBoxFuture, Ready};
 use serde::{Deserialize, Serialize};
 
+use std::cell::Cell;
 use std::rc::Rc;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -18,6 +21,13 @@ pub struct AuthInfo {
 	pub perms: Perms,
 }
 
+impl AuthInfo {
+	/// Guests can look but not touch, this is how accounts get soft-banned
+	pub fn can_write(&self) -> bool {
+		self.perms != Perms::Guest
+	}
+}
+
 impl core::convert::From<user::User> for AuthInfo {
 	fn from(user: user::User) -> Self {
 		Self {
@@ -27,30 +37,175 @@ impl core::convert::From<user::User> for AuthInfo {
 	}
 }
 
+/// Like `try500!` but for redis, a lost connection is reported as the auth store
+/// being unavailable so clients know to retry while we reconnect
+macro_rules! try_redis {
+	($e:expr,$($args:tt)+) => {
+		$e.map_err(|err: redis::RedisError| {
+			if err.is_connection_dropped()
+				|| err.is_connection_refusal()
+				|| err.is_timeout()
+				|| err.is_io_error()
+			{
+				log::warn!(
+					"redis unavailable: {}\n[ERROR]: {:?}",
+					format_args!($($args)+),
+					err
+				);
+				APIError::Unavailable
+			} else {
+				log::error!(
+					"internal error has occurred!\n[MESSAGE]: {}\n[ERROR]: {:?}",
+					format_args!($($args)+),
+					err
+				);
+				APIError::InternalError
+			}
+		})?
+	};
+}
+
+/// Session tokens can't be empty and are measured in bytes
+fn token_within_limits(token: &str) -> bool {
+	!token.is_empty() && token.len() <= limits::TOKEN_MAX
+}
+
+/// Name of the cookie sessions are kept in when cookie auth is turned on
+pub const SESSION_COOKIE: &str = "watame_session";
+
+/// Cookie handing a browser its session token, out of reach of scripts and never
+/// sent cross-site. A `ttl` of 0 makes it last until the browser is closed
+pub fn session_cookie(token: &str, ttl: u64) -> Cookie<'static> {
+	let mut cookie = Cookie::build(SESSION_COOKIE, token.to_owned())
+		.path("/")
+		.secure(true)
+		.http_only(true)
+		.same_site(SameSite::Strict)
+		.finish();
+	if ttl != 0 {
+		cookie.set_max_age(Duration::seconds(ttl as i64));
+	}
+	cookie
+}
+
+/// Cookie telling the browser to throw away its session cookie
+pub fn removal_cookie() -> Cookie<'static> {
+	let mut cookie = session_cookie("", 0);
+	cookie.make_removal();
+	cookie
+}
+
+/// Pull the session token out of a request, the `Authorization` header wins over
+/// the session `cookie` if a request somehow has both
+fn session_token(
+	headers: &HeaderMap,
+	cookie: Option<Cookie<'_>>,
+) -> Result<Option<String>, APIError> {
+	match headers.get(header::AUTHORIZATION) {
+		Some(x) => Ok(Some(
+			x.to_str().map_err(|_| APIError::BadRequestData)?.to_owned(),
+		)),
+		None => Ok(cookie.map(|c| c.value().to_owned())),
+	}
+}
+
 // This isn't really a factory, but it's done this way so we don't have to use
 // an Atomic reference counter
 #[derive(Clone)]
 pub struct AuthDbCreator {
-	conn: redis::aio::MultiplexedConnection,
+	conns: Vec<redis::aio::ConnectionManager>,
+	next: Cell<usize>,
+	/// Put in front of every key we use so redis can be shared with others
+	prefix: String,
 }
 
 impl AuthDbCreator {
-	pub async fn new(uri: &str) -> Self {
+	/// Open `pool_size` connections to redis which requests are spread across, all
+	/// keys are kept under `prefix`
+	pub async fn new(uri: &str, pool_size: usize, prefix: &str) -> Self {
 		let client = redis::Client::open(uri).expect("failed to create redis client");
-		let conn = client
-			.get_multiplexed_tokio_connection()
-			.await
-			.expect("failed to connect to redis");
-		Self { conn }
+		let mut conns = Vec::with_capacity(pool_size.max(1));
+		for _ in 0..pool_size.max(1) {
+			// The manager reconnects by itself should redis go away and come back
+			let conn = redis::aio::ConnectionManager::new(client.clone())
+				.await
+				.expect("failed to connect to redis");
+			conns.push(conn);
+		}
+		Self {
+			conns,
+			next: Cell::new(0),
+			prefix: prefix.to_owned(),
+		}
 	}
 
-	pub async fn clear_sessions(uri: &str) {
-		let mut auth_db = Self::new(uri).await;
-		let _: () = redis::cmd("FLUSHALL")
-			.query_async(&mut auth_db.conn)
-			.await
-			.expect("failed to flush redis keys");
+	/// Hand out the connections in turn, cloning one is cheap as it is shared
+	fn conn(&self) -> redis::aio::ConnectionManager {
+		let i = self.next.get();
+		self.next.set((i + 1) % self.conns.len());
+		self.conns[i].clone()
+	}
+
+	/// Place `key` under our prefix
+	fn key(&self, key: &str) -> String {
+		format!("{}{}", self.prefix, key)
+	}
+
+	fn keys(&self, keys: &[String]) -> Vec<String> {
+		keys.iter().map(|k| self.key(k)).collect()
+	}
+
+	/// End every session, only session keys are removed so login lockouts, caches
+	/// and anything else sharing redis are left alone
+	pub async fn clear_sessions(uri: &str, prefix: &str) {
+		let auth_db = Self::new(uri, 1, prefix).await;
+		let mut conn = auth_db.conn();
+		for pattern in session_patterns(prefix) {
+			// Keys are deleted a page at a time as they're found, SCAN copes with the
+			// keyspace changing underneath it
+			let mut cursor = 0u64;
+			loop {
+				let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+					.cursor_arg(cursor)
+					.arg("MATCH")
+					.arg(&pattern)
+					.arg("COUNT")
+					.arg(500)
+					.query_async(&mut conn)
+					.await
+					.expect("failed to scan redis keys");
+				if !keys.is_empty() {
+					let _: () = redis::cmd("DEL")
+						.arg(&keys)
+						.query_async(&mut conn)
+						.await
+						.expect("failed to delete redis keys");
+				}
+				if next == 0 {
+					break;
+				}
+				cursor = next;
+			}
+		}
+	}
+}
+
+/// `MATCH` patterns for the session keys under `prefix`, the sessions themselves and
+/// the sets tracking each user's sessions
+fn session_patterns(prefix: &str) -> [String; 2] {
+	["user:", "sessions:"].map(|kind| format!("{}{}*", glob_escape(prefix), kind))
+}
+
+/// Escape the characters redis treats specially in `MATCH` patterns
+fn glob_escape(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+			out.push('\\');
+		}
+		out.push(c);
 	}
+	out
 }
 
 #[derive(Clone)]
@@ -61,22 +216,215 @@ impl AuthDb {
 		Self(Rc::new(auth_db))
 	}
 
-	pub async fn remember(&self, key: &str, user: &AuthInfo) -> Result<bool, APIError> {
-		let mut conn = self.0.conn.clone();
-		let res: bool = try500!(
-			redis::cmd("SETNX")
-				.arg(key)
-				.arg(serde_json::to_string(&user).unwrap())
-				.query_async(&mut conn)
-				.await,
-			"authdb:remember SETNX {:?} {:?}",
+	/// Store a session for `user`, a `ttl` of 0 means the session never expires
+	pub async fn remember(&self, key: &str, user: &AuthInfo, ttl: u64) -> Result<bool, APIError> {
+		let key = self.0.key(key);
+		let mut conn = self.0.conn();
+		let mut cmd = redis::cmd("SET");
+		cmd.arg(&key)
+			.arg(serde_json::to_string(&user).unwrap())
+			.arg("NX");
+		if ttl != 0 {
+			cmd.arg("EX").arg(ttl);
+		}
+		let res: Option<String> = try_redis!(
+			cmd.query_async(&mut conn).await,
+			"authdb:remember SET {:?} {:?}",
 			key,
 			user
 		);
+		// Keep track of the sessions a user has so they can all be ended at once
+		if res.is_some() {
+			let _: () = try_redis!(
+				redis::cmd("SADD")
+					.arg(self.0.key(&format!("sessions:{}", user.uid)))
+					.arg(&key)
+					.query_async(&mut conn)
+					.await,
+				"authdb:remember SADD {:?}",
+				key
+			);
+		}
+
+		Ok(res.is_some())
+	}
+
+	/// Count a login attempt against each of `keys`, erroring once any of them has
+	/// had more than `max` attempts without a successful login. The lockout starts at
+	/// `lockout` seconds and doubles with every attempt made while locked out
+	pub async fn count_login_attempt(
+		&self,
+		keys: &[String],
+		max: u32,
+		lockout: u64,
+	) -> Result<(), APIError> {
+		if max == 0 {
+			return Ok(());
+		}
+		// Counting and checking in one step means a burst of parallel attempts can't
+		// all slip in before any of them are counted
+		let script = redis::Script::new(
+			r"
+			local locked = 0
+			for _, key in ipairs(KEYS) do
+				local count = redis.call('INCR', key)
+				local expiry = tonumber(ARGV[2])
+				if count > tonumber(ARGV[1]) then
+					locked = 1
+					local over = math.min(count - tonumber(ARGV[1]) - 1, 10)
+					expiry = math.min(expiry * 2 ^ over, 86400)
+				end
+				redis.call('EXPIRE', key, expiry)
+			end
+			return locked
+			",
+		);
+		let keys = self.0.keys(keys);
+		let mut conn = self.0.conn();
+		let locked: bool = try_redis!(
+			script
+				.key(&keys)
+				.arg(max)
+				.arg(lockout)
+				.invoke_async(&mut conn)
+				.await,
+			"authdb:count_login_attempt {:?}",
+			keys
+		);
+		if locked {
+			return Err(APIError::RateLimited);
+		}
+		Ok(())
+	}
+
+	pub async fn clear_login_failures(&self, keys: &[String]) -> Result<(), APIError> {
+		let keys = self.0.keys(keys);
+		let mut conn = self.0.conn();
+		let _: () = try_redis!(
+			redis::cmd("DEL").arg(&keys).query_async(&mut conn).await,
+			"authdb:clear_login_failures DEL {:?}",
+			keys
+		);
+		Ok(())
+	}
+
+	/// End every session belonging to `uid`
+	pub async fn forget_user(&self, uid: i32) -> Result<(), APIError> {
+		let set = self.0.key(&format!("sessions:{}", uid));
+		let mut conn = self.0.conn();
+		let keys: Vec<String> = try_redis!(
+			redis::cmd("SMEMBERS")
+				.arg(&set)
+				.query_async(&mut conn)
+				.await,
+			"authdb:forget_user SMEMBERS {:?}",
+			set
+		);
+		let _: () = try_redis!(
+			redis::cmd("DEL")
+				.arg(&keys)
+				.arg(&set)
+				.query_async(&mut conn)
+				.await,
+			"authdb:forget_user DEL {:?}",
+			set
+		);
+		Ok(())
+	}
+
+	/// Mark the start of an upload for `uid`, erroring if they already have `max`
+	/// uploads in progress. The slot is given back once the guard is dropped
+	pub async fn start_upload(&self, uid: i32, max: u32) -> Result<UploadGuard, APIError> {
+		let key = self.0.key(&format!("uploads:{}", uid));
+		let mut conn = self.0.conn();
+		// The expiry is only a safety net in case we never get to decrement the counter
+		let (count, _): (u32, bool) = try_redis!(
+			redis::pipe()
+				.atomic()
+				.cmd("INCR")
+				.arg(&key)
+				.cmd("EXPIRE")
+				.arg(&key)
+				.arg(3600)
+				.query_async(&mut conn)
+				.await,
+			"authdb:start_upload INCR {:?}",
+			key
+		);
+
+		let guard = UploadGuard { key, conn };
+		if max != 0 && count > max {
+			return Err(APIError::RateLimited);
+		}
+		Ok(guard)
+	}
 
+	/// Fetch a cached value, used for responses that are expensive to compute
+	pub async fn cache_get(&self, key: &str) -> Result<Option<String>, APIError> {
+		let key = self.0.key(key);
+		let mut conn = self.0.conn();
+		let res: Option<String> = try_redis!(
+			redis::cmd("GET").arg(&key).query_async(&mut conn).await,
+			"authdb:cache_get GET {:?}",
+			key
+		);
 		Ok(res)
 	}
 
+	/// Cache `value` under `key` for `ttl` seconds
+	pub async fn cache_set(&self, key: &str, value: &str, ttl: u64) -> Result<(), APIError> {
+		let key = self.0.key(key);
+		let mut conn = self.0.conn();
+		let _: () = try_redis!(
+			redis::cmd("SET")
+				.arg(&key)
+				.arg(value)
+				.arg("EX")
+				.arg(ttl)
+				.query_async(&mut conn)
+				.await,
+			"authdb:cache_set SET {:?}",
+			key
+		);
+		Ok(())
+	}
+
+	/// Claim `key` for a request that must only be carried out once, it is held as
+	/// pending for up to `ttl` seconds until the guard is completed or dropped
+	pub async fn reserve(&self, key: &str, ttl: u64) -> Result<Reservation, APIError> {
+		let key = self.0.key(key);
+		let mut conn = self.0.conn();
+		let reserved: Option<String> = try_redis!(
+			redis::cmd("SET")
+				.arg(&key)
+				.arg(RESERVATION_PENDING)
+				.arg("NX")
+				.arg("EX")
+				.arg(ttl)
+				.query_async(&mut conn)
+				.await,
+			"authdb:reserve SET {:?}",
+			key
+		);
+		if reserved.is_some() {
+			return Ok(Reservation::Reserved(ReservationGuard {
+				key,
+				conn,
+				armed: true,
+			}));
+		}
+		let value: Option<String> = try_redis!(
+			redis::cmd("GET").arg(&key).query_async(&mut conn).await,
+			"authdb:reserve GET {:?}",
+			key
+		);
+		// Having expired in between is treated as pending too, a retry will claim it
+		Ok(match value {
+			Some(value) if value != RESERVATION_PENDING => Reservation::Done(value),
+			_ => Reservation::Pending,
+		})
+	}
+
 	pub async fn verify(
 		&self,
 		header: Option<&str>,
@@ -87,13 +435,13 @@ impl AuthDb {
 		}
 		// Check the token
 		let token = header.unwrap();
-		if token.len() == 0 || token.len() > 512 {
+		if !token_within_limits(token) {
 			return Err(APIError::BadRequestData);
 		}
-		let key = format!("user:{}", token);
+		let key = self.0.key(&format!("user:{}", token));
 
-		let mut conn = self.0.conn.clone();
-		let exists: Option<String> = try500!(
+		let mut conn = self.0.conn();
+		let exists: Option<String> = try_redis!(
 			redis::cmd("GET").arg(&key).query_async(&mut conn).await,
 			"authdb:verify GET {:?}",
 			key
@@ -106,8 +454,85 @@ impl AuthDb {
 	}
 }
 
+/// Holds one of a users concurrent upload slots, freeing it when dropped
+pub struct UploadGuard {
+	key: String,
+	conn: redis::aio::ConnectionManager,
+}
+
+impl Drop for UploadGuard {
+	fn drop(&mut self) {
+		let key = std::mem::take(&mut self.key);
+		let mut conn = self.conn.clone();
+		actix_web::rt::spawn(async move {
+			let res: Result<i64, _> = redis::cmd("DECR").arg(&key).query_async(&mut conn).await;
+			if let Err(e) = res {
+				log::error!("({}): Failed to release upload slot {}", e, key);
+			}
+		});
+	}
+}
+
+/// Placeholder kept under a reserved key until the request holding it is done
+const RESERVATION_PENDING: &str = "pending";
+
+/// What `AuthDb::reserve` found under a key
+pub enum Reservation {
+	/// The key is ours, whoever asked first gets to carry out the request
+	Reserved(ReservationGuard),
+	/// Another request holding the key hasn't finished yet
+	Pending,
+	/// Another request already finished and left this behind
+	Done(String),
+}
+
+/// Holds a reserved key, releasing it when dropped so a failed request can be
+/// tried again
+pub struct ReservationGuard {
+	key: String,
+	conn: redis::aio::ConnectionManager,
+	armed: bool,
+}
+
+impl ReservationGuard {
+	/// Replace the placeholder with `value` for `ttl` seconds. The key is kept either
+	/// way, should this fail it stays pending until it expires
+	pub async fn complete(mut self, value: &str, ttl: u64) -> Result<(), APIError> {
+		self.armed = false;
+		let _: () = try_redis!(
+			redis::cmd("SET")
+				.arg(&self.key)
+				.arg(value)
+				.arg("EX")
+				.arg(ttl)
+				.query_async(&mut self.conn)
+				.await,
+			"authdb:complete SET {:?}",
+			self.key
+		);
+		Ok(())
+	}
+}
+
+impl Drop for ReservationGuard {
+	fn drop(&mut self) {
+		if !self.armed {
+			return;
+		}
+		let key = std::mem::take(&mut self.key);
+		let mut conn = self.conn.clone();
+		actix_web::rt::spawn(async move {
+			let res: Result<i64, _> = redis::cmd("DEL").arg(&key).query_async(&mut conn).await;
+			if let Err(e) = res {
+				log::error!("({}): Failed to release reservation {}", e, key);
+			}
+		});
+	}
+}
+
 pub struct AuthMiddleware<S> {
 	auth_db: AuthDb,
+	cookies: bool,
 	service: Rc<S>,
 }
 
@@ -124,15 +549,19 @@ where
 	fn call(&self, req: ServiceRequest) -> Self::Future {
 		let srv = self.service.clone();
 		let auth_db = self.auth_db.clone();
+		let cookies = self.cookies;
 
 		async move {
-			// Grab our authorization header, even if there isn't one
-			let id = match req.headers().get(header::AUTHORIZATION) {
-				Some(x) => Some(x.to_str().map_err(|_| APIError::BadRequestData)?),
-				None => None,
+			// Grab our session token, even if there isn't one. The cookie is only
+			// looked at if cookie auth is turned on
+			let cookie = if cookies {
+				req.cookie(SESSION_COOKIE)
+			} else {
+				None
 			};
+			let id = session_token(req.headers(), cookie)?;
 			// Get the database to check that it is valid
-			let info = auth_db.verify(id, &req).await?;
+			let info = auth_db.verify(id.as_deref(), &req).await?;
 			// If so insert an extension into the service request to get later
 			if let Some(info) = info {
 				req.extensions_mut().insert::<AuthInfo>(info);
@@ -147,11 +576,14 @@ where
 
 pub struct AuthMiddlewareFactory {
 	auth_db: AuthDb,
+	cookies: bool,
 }
 
 impl AuthMiddlewareFactory {
-	pub fn new(auth_db: AuthDb) -> Self {
-		Self { auth_db }
+	/// `cookies` lets sessions be read from the session cookie as well as the
+	/// `Authorization` header
+	pub fn new(auth_db: AuthDb, cookies: bool) -> Self {
+		Self { auth_db, cookies }
 	}
 }
 
@@ -168,6 +600,7 @@ where
 	fn new_transform(&self, service: S) -> Self::Future {
 		ready(Ok(AuthMiddleware {
 			auth_db: self.auth_db.clone(),
+			cookies: self.cookies,
 			service: Rc::new(service),
 		}))
 	}
@@ -176,23 +609,29 @@ where
 pub struct Authenticated(AuthInfo, AuthDb);
 
 impl Authenticated {
-	#[allow(dead_code)]
 	pub fn get_db(&self) -> &AuthDb {
 		&self.1
 	}
 
 	pub async fn forget(&self, req: &HttpRequest) -> Result<(), APIError> {
-		// The idea is this is already checked, we are just getting it again
-		let key = req
-			.headers()
-			.get(header::AUTHORIZATION)
-			.unwrap()
-			.to_str()
-			.unwrap();
-
-		let mut conn = self.1 .0.conn.clone();
-		let _: () = try500!(
-			redis::cmd("DEL").arg(&key).query_async(&mut conn).await,
+		// The idea is this is already checked, we are just getting it again. Only
+		// cookie auth could have let a request without the header get this far
+		let token = session_token(req.headers(), req.cookie(SESSION_COOKIE))?
+			.ok_or(APIError::BadCredentials)?;
+		let key = self.1 .0.key(&format!("user:{}", token));
+
+		let mut conn = self.1 .0.conn();
+		let _: () = try_redis!(
+			redis::pipe()
+				.cmd("DEL")
+				.arg(&key)
+				.ignore()
+				.cmd("SREM")
+				.arg(self.1 .0.key(&format!("sessions:{}", self.0.uid)))
+				.arg(&key)
+				.ignore()
+				.query_async(&mut conn)
+				.await,
 			"auth:forget DEL {:?}",
 			key
 		);
@@ -273,3 +712,293 @@ impl MaybeAuthenticated {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::settings::SESSION_TTL_MAX;
+
+	#[test]
+	fn session_cookie_longest_ttl() {
+		let cookie = session_cookie("token", SESSION_TTL_MAX);
+		assert_eq!(
+			cookie.max_age(),
+			Some(Duration::seconds(SESSION_TTL_MAX as i64))
+		);
+		let expires = chrono::Utc::now() + chrono::Duration::seconds(SESSION_TTL_MAX as i64);
+		assert!(expires > chrono::Utc::now());
+	}
+
+	#[test]
+	fn only_guests_cannot_write() {
+		for (perms, write) in [
+			(Perms::Guest, false),
+			(Perms::User, true),
+			(Perms::Moderator, true),
+			(Perms::Admin, true),
+		] {
+			assert_eq!(AuthInfo { uid: 1, perms }.can_write(), write, "{:?}", perms);
+		}
+	}
+
+	#[test]
+	fn token_limit() {
+		assert!(token_within_limits(&"t".repeat(limits::TOKEN_MAX)));
+		assert!(!token_within_limits(&"t".repeat(limits::TOKEN_MAX + 1)));
+		assert!(!token_within_limits(""));
+		// Bytes rather than characters
+		assert!(!token_within_limits(&"ね".repeat(limits::TOKEN_MAX / 2)));
+	}
+
+	#[test]
+	fn keys_prefixed() {
+		let auth_db = AuthDbCreator {
+			conns: Vec::new(),
+			next: Cell::new(0),
+			prefix: "tenant:".to_owned(),
+		};
+		assert_eq!(auth_db.key("user:abc"), "tenant:user:abc");
+		assert_eq!(
+			auth_db.keys(&["a".to_owned(), "b".to_owned()]),
+			["tenant:a", "tenant:b"]
+		);
+	}
+
+	#[test]
+	fn glob_escaped() {
+		assert_eq!(glob_escape("watame:"), "watame:");
+		assert_eq!(glob_escape("a*b?c[d]e\\f"), "a\\*b\\?c\\[d\\]e\\\\f");
+	}
+
+	/// Needs redis running with the default settings
+	#[actix_web::test]
+	#[ignore]
+	async fn clear_sessions_keeps_other_prefixes() {
+		let settings = crate::settings::Settings::default();
+		// Unescaped the `*` in our prefix would match their keys as well
+		let ours = format!("watame-test-{}*:", std::process::id());
+		let theirs = format!("watame-test-{}x:", std::process::id());
+		let mut conn = redis::Client::open(settings.redis_uri.as_str())
+			.unwrap()
+			.get_async_connection()
+			.await
+			.unwrap();
+		let mine = format!("{}user:token", ours);
+		let other = [
+			format!("{}user:token", theirs),
+			format!("user:token-{}", std::process::id()),
+		];
+		for key in other.iter().chain([&mine]) {
+			let _: () = redis::cmd("SET")
+				.arg(key)
+				.arg("{}")
+				.query_async(&mut conn)
+				.await
+				.unwrap();
+		}
+
+		AuthDbCreator::clear_sessions(&settings.redis_uri, &ours).await;
+		let exists: i64 = redis::cmd("EXISTS")
+			.arg(&mine)
+			.query_async(&mut conn)
+			.await
+			.unwrap();
+		assert_eq!(exists, 0);
+		let exists: i64 = redis::cmd("EXISTS")
+			.arg(&other)
+			.query_async(&mut conn)
+			.await
+			.unwrap();
+		assert_eq!(exists, 2);
+		let _: () = redis::cmd("DEL")
+			.arg(&other)
+			.query_async(&mut conn)
+			.await
+			.unwrap();
+	}
+
+	#[test]
+	fn only_session_patterns() {
+		assert_eq!(
+			session_patterns("watame:"),
+			["watame:user:*", "watame:sessions:*"]
+		);
+		assert_eq!(session_patterns("a*:"), ["a\\*:user:*", "a\\*:sessions:*"]);
+	}
+
+	/// Needs redis running with the default settings
+	#[actix_web::test]
+	#[ignore]
+	async fn clear_sessions_keeps_other_keys() {
+		let settings = crate::settings::Settings::default();
+		let prefix = format!("watame-test-{}:", std::process::id());
+		let mut conn = redis::Client::open(settings.redis_uri.as_str())
+			.unwrap()
+			.get_async_connection()
+			.await
+			.unwrap();
+		let key = |k: &str| format!("{}{}", prefix, k);
+		let sessions = [key("user:token"), key("sessions:1")];
+		let kept = [
+			key("login_fail:ip:127.0.0.1"),
+			key("cache:stats"),
+			key("uploads:1"),
+			key("idempotency:1:abc"),
+		];
+		for k in sessions.iter().chain(&kept) {
+			let _: () = redis::cmd("SET")
+				.arg(k)
+				.arg("1")
+				.query_async(&mut conn)
+				.await
+				.unwrap();
+		}
+
+		AuthDbCreator::clear_sessions(&settings.redis_uri, &prefix).await;
+		for (keys, left) in [(&sessions[..], 0), (&kept[..], kept.len() as i64)] {
+			let exists: i64 = redis::cmd("EXISTS")
+				.arg(keys)
+				.query_async(&mut conn)
+				.await
+				.unwrap();
+			assert_eq!(exists, left, "{:?}", keys);
+		}
+		let _: () = redis::cmd("DEL")
+			.arg(&kept)
+			.query_async(&mut conn)
+			.await
+			.unwrap();
+	}
+
+	#[test]
+	fn session_cookie_no_ttl() {
+		let cookie = session_cookie("token", 0);
+		assert_eq!(cookie.max_age(), None);
+		assert_eq!(cookie.http_only(), Some(true));
+		assert_eq!(cookie.secure(), Some(true));
+	}
+
+	#[test]
+	fn session_token_sources() {
+		let mut headers = HeaderMap::new();
+		let cookie = || Some(Cookie::new(SESSION_COOKIE, "from-cookie"));
+		assert_eq!(session_token(&headers, None).unwrap(), None);
+		assert_eq!(
+			session_token(&headers, cookie()).unwrap().as_deref(),
+			Some("from-cookie")
+		);
+
+		headers.insert(
+			header::AUTHORIZATION,
+			header::HeaderValue::from_static("from-header"),
+		);
+		assert_eq!(
+			session_token(&headers, None).unwrap().as_deref(),
+			Some("from-header")
+		);
+		// The header wins when both are sent
+		assert_eq!(
+			session_token(&headers, cookie()).unwrap().as_deref(),
+			Some("from-header")
+		);
+
+		headers.insert(
+			header::AUTHORIZATION,
+			header::HeaderValue::from_bytes(b"\xff").unwrap(),
+		);
+		assert!(matches!(
+			session_token(&headers, cookie()),
+			Err(APIError::BadRequestData)
+		));
+	}
+
+	#[test]
+	fn removal_cookie_clears_session() {
+		let cookie = removal_cookie();
+		let session = session_cookie("token", 60);
+		assert_eq!(cookie.name(), session.name());
+		assert_eq!(cookie.path(), session.path());
+		assert_eq!(cookie.value(), "");
+		assert_eq!(cookie.max_age(), Some(Duration::ZERO));
+	}
+
+	/// Needs redis running with the default settings
+	#[actix_web::test]
+	#[ignore]
+	async fn both_auth_transports() {
+		use actix_web::{test, web, App, HttpResponse};
+
+		let settings = crate::settings::Settings::default();
+		let auth_db = AuthDb::new(AuthDbCreator::new(&settings.redis_uri, 1, "watame-test:").await);
+		let token = format!("transport-test-{}", std::process::id());
+		let user = AuthInfo {
+			uid: 7,
+			perms: Perms::User,
+		};
+		auth_db
+			.remember(&format!("user:{}", token), &user, 60)
+			.await
+			.unwrap();
+
+		let whoami = |auth: MaybeAuthenticated| async move {
+			HttpResponse::Ok().body(auth.as_ref().map_or(-1, |a| a.uid).to_string())
+		};
+		for cookies in [true, false] {
+			let app = test::init_service(
+				App::new()
+					.wrap(AuthMiddlewareFactory::new(auth_db.clone(), cookies))
+					.app_data(web::Data::new(auth_db.clone()))
+					.route("/", web::get().to(whoami)),
+			)
+			.await;
+			let uid = |req: test::TestRequest| {
+				let app = &app;
+				async move {
+					let res = test::call_service(app, req.to_request()).await;
+					String::from_utf8(test::read_body(res).await.to_vec()).unwrap()
+				}
+			};
+			let header =
+				test::TestRequest::get().insert_header((header::AUTHORIZATION, token.as_str()));
+			assert_eq!(uid(header).await, "7");
+			let cookie =
+				test::TestRequest::get().cookie(Cookie::new(SESSION_COOKIE, token.clone()));
+			// The cookie is only honoured when cookie auth is turned on
+			assert_eq!(uid(cookie).await, if cookies { "7" } else { "-1" });
+			assert_eq!(uid(test::TestRequest::get()).await, "-1");
+		}
+	}
+
+	/// Needs redis running with the default settings
+	#[actix_web::test]
+	#[ignore]
+	async fn login_attempts_lock_out() {
+		let settings = crate::settings::Settings::default();
+		let auth_db = AuthDb::new(AuthDbCreator::new(&settings.redis_uri, 1, "watame-test:").await);
+		let keys = [format!("login_fail:test:{}", std::process::id())];
+		let ttl = || async {
+			let mut conn = auth_db.0.conn();
+			let ttl: i64 = redis::cmd("TTL")
+				.arg(auth_db.0.key(&keys[0]))
+				.query_async(&mut conn)
+				.await
+				.unwrap();
+			ttl
+		};
+
+		auth_db.count_login_attempt(&keys, 2, 60).await.unwrap();
+		auth_db.count_login_attempt(&keys, 2, 60).await.unwrap();
+		assert!(matches!(
+			auth_db.count_login_attempt(&keys, 2, 60).await,
+			Err(APIError::RateLimited)
+		));
+		assert!(ttl().await > 30);
+		// Trying again while locked out doubles the wait
+		assert!(auth_db.count_login_attempt(&keys, 2, 60).await.is_err());
+		assert!(ttl().await > 90);
+
+		auth_db.clear_login_failures(&keys).await.unwrap();
+		auth_db.count_login_attempt(&keys, 2, 60).await.unwrap();
+		auth_db.clear_login_failures(&keys).await.unwrap();
+	}
+}
@@ -0,0 +1,59 @@
+use crate::database::{enums::AuditAction, pg, post::Timestamp, DatabaseError};
+
+#[derive(serde::Serialize)]
+pub struct AuditEntry {
+	pub id: i64,
+	pub post_id: i64,
+	pub uid: i32,
+	pub action: AuditAction,
+	pub detail: serde_json::Value,
+	pub created: Timestamp,
+}
+
+impl AuditEntry {
+	fn deserialise(row: &pg::row::Row) -> Self {
+		AuditEntry {
+			id: row.get(0),
+			post_id: row.get(1),
+			uid: row.get(2),
+			action: row.get(3),
+			detail: row.get(4),
+			created: row.get(5),
+		}
+	}
+
+	pub async fn select_post_history<C: pg::GenericClient>(
+		client: &C,
+		post_id: i64,
+	) -> Result<Vec<AuditEntry>, DatabaseError> {
+		let query = "SELECT * FROM post_audit WHERE post_id=$1 ORDER BY created ASC, id ASC";
+		let rows = client
+			.query(query, &[&post_id])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(rows.iter().map(Self::deserialise).collect())
+	}
+}
+
+#[derive(Debug)]
+pub struct NewAuditEntry {
+	pub post_id: i64,
+	pub uid: i32,
+	pub action: AuditAction,
+	pub detail: serde_json::Value,
+}
+
+impl NewAuditEntry {
+	/// Should be called with the same transaction as the change being recorded
+	pub async fn insert_into<C: pg::GenericClient>(&self, client: &C) -> Result<(), DatabaseError> {
+		let query = "INSERT INTO post_audit (post_id, uid, action, detail) VALUES ($1, $2, $3, $4)";
+		client
+			.execute(
+				query,
+				&[&self.post_id, &self.uid, &self.action, &self.detail],
+			)
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(())
+	}
+}
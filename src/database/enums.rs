@@ -1,5 +1,6 @@
 use crate::database::pg;
-use crate::pages::search::PostSorting;
+use crate::pages::search::{DeletedMode, PostSorting};
+use crate::pages::tag::TagSorting;
 
 use pg::types::{FromSql as FromSqlDerive, ToSql as ToSqlDerive};
 
@@ -14,21 +15,27 @@ pub enum Perms {
 	Admin,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSqlDerive, FromSqlDerive)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSqlDerive, FromSqlDerive)]
 #[postgres(name = "rating")]
 pub enum Rating {
 	Safe,
+	#[default]
 	Sketchy,
 	Explicit,
 }
 
-impl core::default::Default for Rating {
-	fn default() -> Self {
-		Rating::Sketchy
-	}
+#[derive(Debug, Clone, Copy, Serialize, ToSqlDerive, FromSqlDerive)]
+#[postgres(name = "auditaction")]
+pub enum AuditAction {
+	Delete,
+	Restore,
+	Edit,
+	Lock,
+	Unlock,
+	Purge,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, ToSqlDerive, FromSqlDerive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSqlDerive, FromSqlDerive)]
 #[postgres(name = "imgext")]
 pub enum ImageExtension {
 	Bmp,
@@ -39,28 +46,134 @@ pub enum ImageExtension {
 	Webp,
 }
 
-impl std::convert::From<image::ImageFormat> for ImageExtension {
-	fn from(im: image::ImageFormat) -> Self {
+impl ImageExtension {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			ImageExtension::Bmp => "bmp",
+			ImageExtension::Gif => "gif",
+			ImageExtension::Jpg => "jpg",
+			ImageExtension::Png => "png",
+			ImageExtension::Tiff => "tiff",
+			ImageExtension::Webp => "webp",
+		}
+	}
+
+	/// Every format we know how to store
+	pub const ALL: [ImageExtension; 6] = [
+		ImageExtension::Bmp,
+		ImageExtension::Gif,
+		ImageExtension::Jpg,
+		ImageExtension::Png,
+		ImageExtension::Tiff,
+		ImageExtension::Webp,
+	];
+
+	/// Look up a format by its extension, ignoring case
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.to_lowercase().as_str() {
+			"jpeg" => Some(ImageExtension::Jpg),
+			name => Self::ALL.into_iter().find(|e| e.as_str() == name),
+		}
+	}
+}
+
+impl std::convert::TryFrom<image::ImageFormat> for ImageExtension {
+	type Error = image::ImageFormat;
+
+	fn try_from(im: image::ImageFormat) -> Result<Self, Self::Error> {
 		use image::ImageFormat;
 		match im {
-			ImageFormat::Bmp => ImageExtension::Bmp,
-			ImageFormat::Gif => ImageExtension::Gif,
-			ImageFormat::Jpeg => ImageExtension::Jpg,
-			ImageFormat::Png => ImageExtension::Png,
-			ImageFormat::Tiff => ImageExtension::Tiff,
-			ImageFormat::WebP => ImageExtension::Webp,
-			_ => panic!("unknown image format: {:?}", im),
+			ImageFormat::Bmp => Ok(ImageExtension::Bmp),
+			ImageFormat::Gif => Ok(ImageExtension::Gif),
+			ImageFormat::Jpeg => Ok(ImageExtension::Jpg),
+			ImageFormat::Png => Ok(ImageExtension::Png),
+			ImageFormat::Tiff => Ok(ImageExtension::Tiff),
+			ImageFormat::WebP => Ok(ImageExtension::Webp),
+			_ => Err(im),
+		}
+	}
+}
+
+/// What posts can be ordered by, only ever turned into fixed SQL so nothing a
+/// client sends can end up in an `ORDER BY`
+#[derive(Debug, Copy, Clone)]
+pub enum SortColumn {
+	CreateDate,
+	ModifiedDate,
+	Score,
+	/// Score decayed by age
+	Hot,
+}
+
+impl SortColumn {
+	pub fn as_sql(self) -> &'static str {
+		match self {
+			SortColumn::CreateDate => "create_date",
+			SortColumn::ModifiedDate => "modified_date",
+			SortColumn::Score => "score",
+			SortColumn::Hot => {
+				"(score / POWER(EXTRACT(EPOCH FROM (now() - create_date)) / 3600 + 2, 1.5))"
+			}
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SortDirection {
+	Ascending,
+	Descending,
+}
+
+impl SortDirection {
+	pub fn as_sql(self) -> &'static str {
+		match self {
+			SortDirection::Ascending => "ASC",
+			SortDirection::Descending => "DESC",
+		}
+	}
+
+	pub fn reverse(self) -> Self {
+		match self {
+			SortDirection::Ascending => SortDirection::Descending,
+			SortDirection::Descending => SortDirection::Ascending,
 		}
 	}
 }
 
 impl PostSorting {
-	pub fn to_sql(&self) -> &str {
+	/// The column posts are ordered by and in which direction, before the id
+	/// tiebreaker
+	pub fn order(self) -> (SortColumn, SortDirection) {
+		match self {
+			PostSorting::DateAscending => (SortColumn::CreateDate, SortDirection::Ascending),
+			PostSorting::DateDescending => (SortColumn::CreateDate, SortDirection::Descending),
+			PostSorting::ModifiedAscending => (SortColumn::ModifiedDate, SortDirection::Ascending),
+			PostSorting::ModifiedDescending => {
+				(SortColumn::ModifiedDate, SortDirection::Descending)
+			}
+			PostSorting::VoteAscending => (SortColumn::Score, SortDirection::Ascending),
+			PostSorting::VoteDescending => (SortColumn::Score, SortDirection::Descending),
+			PostSorting::Hot => (SortColumn::Hot, SortDirection::Descending),
+		}
+	}
+}
+
+impl TagSorting {
+	pub fn to_sql(self) -> &'static str {
+		match self {
+			TagSorting::Count => "ORDER BY count DESC, name ASC",
+			TagSorting::Name => "ORDER BY name ASC",
+		}
+	}
+}
+
+impl DeletedMode {
+	/// Condition on `is_deleted`, if any
+	pub fn condition(self) -> Option<&'static str> {
 		match self {
-			PostSorting::DateAscending => "ORDER BY create_date ASC, id ASC",
-			PostSorting::DateDescending => "ORDER BY create_date DESC, id DESC",
-			PostSorting::VoteAscending => "ORDER BY score ASC, id ASC",
-			PostSorting::VoteDescending => "ORDER BY score DESC, id DESC",
+			DeletedMode::Exclude => Some("is_deleted='false'"),
+			DeletedMode::Include => None,
+			DeletedMode::Only => Some("is_deleted='true'"),
 		}
 	}
 }
@@ -0,0 +1,64 @@
+//! Rows for tests to build on. Tests using these need postgres running with the
+//! default settings and the schema installed, so they are all `#[ignore]`d. They work
+//! inside a transaction that is never committed so nothing is left behind, any that
+//! can't clean up after themselves instead
+
+use crate::database::{
+	enums::{ImageExtension, Rating},
+	pg,
+	post::{NewPost, PostFull},
+	user::{NewUser, User},
+	Pool,
+};
+
+/// A pool for the database the default settings point at
+pub fn pool() -> Pool {
+	super::establish_pool(&mut crate::settings::Settings::default())
+}
+
+/// A user named `name` with the address `name@example.com`, both have to be unique
+/// so names should include something like the process id
+pub async fn insert_user<C: pg::GenericClient>(client: &C, name: &str) -> User {
+	let email = format!("{}@example.com", name);
+	NewUser {
+		name,
+		email: Some(&email),
+		pass: "!",
+		picture: "",
+		created_ip: None,
+	}
+	.insert_into(client)
+	.await
+	.unwrap()
+}
+
+/// A post by `poster` tagged with `tags`. No two of the numbers are the same so a
+/// column read from the wrong place shows up
+pub async fn insert_post<C: pg::GenericClient>(client: &C, poster: i32, tags: &[&str]) -> PostFull {
+	NewPost {
+		filename: "a.png",
+		ext: ImageExtension::Png,
+		path: "00",
+		size: 12,
+		dimensions: (30, 40),
+		rating: Rating::Safe,
+		description: "fixture",
+		tags,
+		poster,
+		group_id: None,
+		created_ip: None,
+	}
+	.insert_into(client, "simple")
+	.await
+	.unwrap()
+}
+
+/// A user named `name` and a post of theirs tagged with `tags`
+pub async fn insert_post_fixture<C: pg::GenericClient>(
+	client: &C,
+	name: &str,
+	tags: &[&str],
+) -> PostFull {
+	let user = insert_user(client, name).await;
+	insert_post(client, user.id, tags).await
+}
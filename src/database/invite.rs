@@ -0,0 +1,35 @@
+use crate::database::{pg, DatabaseError};
+
+pub struct Invite;
+
+impl Invite {
+	/// Store a new single use invite code made by `creator`
+	pub async fn insert<C: pg::GenericClient>(
+		client: &C,
+		code: &str,
+		creator: i32,
+	) -> Result<(), DatabaseError> {
+		let query = "INSERT INTO invites (code, creator) VALUES ($1, $2)";
+		client
+			.execute(query, &[&code, &creator])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(())
+	}
+
+	/// Mark an invite as used by `uid`, returns false if the code doesn't exist or
+	/// has already been used
+	pub async fn claim<C: pg::GenericClient>(
+		client: &C,
+		code: &str,
+		uid: i32,
+	) -> Result<bool, DatabaseError> {
+		let query = "UPDATE invites SET used_by=$2, used_date=now() \
+		             WHERE code=$1 AND used_by IS NULL";
+		let n = client
+			.execute(query, &[&code, &uid])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(n == 1)
+	}
+}
@@ -1,6 +1,11 @@
+pub mod audit;
 pub mod enums;
 pub mod error;
+#[cfg(test)]
+pub mod fixtures;
+pub mod invite;
 pub mod post;
+pub mod stats;
 pub mod tag;
 pub mod user;
 
@@ -17,10 +22,8 @@ pub fn establish_pool(settings: &mut crate::settings::Settings) -> Pool {
 	cfg.port = Some(settings.database_port);
 	cfg.user = Some(std::mem::take(&mut settings.database_credentials.0));
 
-	let pool = cfg
-		.create_pool(Some(Runtime::Tokio1), pg::NoTls)
-		.expect("failed to create database pool");
-	pool
+	cfg.create_pool(Some(Runtime::Tokio1), pg::NoTls)
+		.expect("failed to create database pool")
 }
 
 pub async fn install_schema(mut settings: crate::settings::Settings) {
@@ -30,11 +33,31 @@ pub async fn install_schema(mut settings: crate::settings::Settings) {
 		.await
 		.expect("failed to get connection from pool");
 
+	// The custom tag_parser extension is only needed when it has been configured,
+	// otherwise a built-in configuration such as `simple` is used. Those split tags
+	// with `-`, `_` or `.` in them into several lexemes, see `Settings::parse`
+	if settings.ts_config == "tag_parser" {
+		if let Err(e) = db
+			.batch_execute("CREATE EXTENSION IF NOT EXISTS tag_parser;")
+			.await
+		{
+			log::error!(
+				"({}): Failed to create tag_parser extension, set WATAME_TS_CONFIG=simple to \
+				 run without it",
+				e
+			);
+			std::process::exit(1);
+		}
+	}
+
 	let scripts = [
-		"CREATE EXTENSION IF NOT EXISTS tag_parser;",
 		include_str!("../../res/sql/create_users.sql"),
+		include_str!("../../res/sql/create_invites.sql"),
 		include_str!("../../res/sql/create_tags.sql"),
+		include_str!("../../res/sql/create_post_groups.sql"),
 		include_str!("../../res/sql/create_posts.sql"),
+		include_str!("../../res/sql/create_post_audit.sql"),
+		include_str!("../../res/sql/create_post_idempotency.sql"),
 	];
 
 	for script in scripts {
@@ -55,3 +78,97 @@ pub async fn drop_tables(mut settings: crate::settings::Settings) {
 		.await
 		.expect("failed to drop tables");
 }
+
+/// Print what `drop_tables` would remove without touching anything
+pub async fn drop_tables_dry_run(mut settings: crate::settings::Settings) {
+	let pool = establish_pool(&mut settings);
+	let db = pool
+		.get()
+		.await
+		.expect("failed to get connection from pool");
+
+	for table in [
+		"post_idempotency",
+		"post_audit",
+		"posts",
+		"post_groups",
+		"invites",
+		"tags",
+		"users",
+	] {
+		let query = format!("SELECT count(*) FROM {}", table);
+		match db.query_one(query.as_str(), &[]).await {
+			Ok(row) => println!(
+				"table '{}' would be dropped ({} rows)",
+				table,
+				row.get::<_, i64>(0)
+			),
+			Err(_) => println!("table '{}' does not exist", table),
+		}
+	}
+}
+
+/// Create a user with admin permissions, for setting up a new instance. The
+/// details are checked the same way registering does
+pub async fn create_admin(
+	mut settings: crate::settings::Settings,
+	name: &str,
+	email: &str,
+	pass: &str,
+) {
+	use crate::limits;
+	use crate::pages::user::hash_password;
+	use user::{NewUser, User};
+
+	if !limits::within(name, limits::USERNAME_MIN, limits::USERNAME_MAX) {
+		log::error!(
+			"username must be between {} and {} characters",
+			limits::USERNAME_MIN,
+			limits::USERNAME_MAX
+		);
+		std::process::exit(1);
+	}
+	let hash = match hash_password(pass, &settings.password_policy) {
+		Ok(hash) => hash,
+		Err(e) => {
+			log::error!("{}", e);
+			std::process::exit(1);
+		}
+	};
+	let email = Some(email).filter(|e| !e.is_empty());
+
+	let pool = establish_pool(&mut settings);
+	let mut db = pool
+		.get()
+		.await
+		.expect("failed to get connection from pool");
+	let trans = db.transaction().await.expect("failed to start transaction");
+	match User::check_existence::<pg::Transaction<'_>>(&trans, name, email).await {
+		Ok(Some(field)) => {
+			log::error!("{} has already been used", field);
+			std::process::exit(1);
+		}
+		Ok(None) => {}
+		Err(e) => {
+			log::error!("({}): Failed to check for existing users", e);
+			std::process::exit(1);
+		}
+	}
+
+	let new_user = NewUser {
+		name,
+		email,
+		pass: &hash,
+		picture: &settings.default_pfp,
+		created_ip: None,
+	};
+	let user = new_user
+		.insert_into::<pg::Transaction<'_>>(&trans)
+		.await
+		.expect("failed to insert user");
+	User::update_perms::<pg::Transaction<'_>>(&trans, user.id, enums::Perms::Admin)
+		.await
+		.expect("failed to make user an admin");
+	trans.commit().await.expect("failed to commit new admin");
+	println!("Created admin '{}' with id {}", name, user.id);
+}
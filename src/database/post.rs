@@ -1,10 +1,24 @@
 use pg::types::ToSql;
+use std::net::IpAddr;
 
-use crate::database::{enums::*, pg, tag::TagVector, DatabaseError};
-use crate::pages::search::PostSorting;
+use crate::database::{
+	enums::*,
+	pg,
+	tag::{Tag, TagVector},
+	DatabaseError,
+};
+use crate::pages::search::{DeletedMode, PostSorting};
 
 pub type Timestamp = chrono::DateTime<chrono::offset::Utc>;
 
+/// Columns of a full post in the order `Post::deserialise_full` reads them, always
+/// select these by name rather than `*` so changes to the table can't shift them
+const POST_COLUMNS: &str = "id, poster, tag_vector, create_date, modified_date, description, \
+                            rating, score, views, source, filename, path, ext, size, width, \
+                            height, is_deleted, group_id, created_ip, is_locked";
+/// How many columns `POST_COLUMNS` selects, anything selected after it starts here
+const POST_COLUMNS_LEN: usize = 20;
+
 #[derive(serde::Serialize)]
 pub struct PostFull {
 	pub id: i64,
@@ -12,6 +26,9 @@ pub struct PostFull {
 	pub tag_vector: TagVector,
 	pub create_date: Timestamp,
 	pub modified_date: Timestamp,
+	/// The dates again as unix seconds, for clients that would rather not parse them
+	pub create_ts: i64,
+	pub modified_ts: i64,
 	pub description: Option<String>,
 	pub rating: Rating,
 	pub score: i32,
@@ -24,57 +41,68 @@ pub struct PostFull {
 	pub width: i32,
 	pub height: i32,
 	pub is_deleted: bool,
+	/// Locked posts can only be edited by moderators, owners can still delete them
+	pub is_locked: bool,
+	/// The gallery this post was uploaded as part of, if any
+	pub group_id: Option<i64>,
+	/// Where the post was uploaded from, only ever shown to moderators
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub created_ip: Option<IpAddr>,
 }
 
-pub enum Post {
-	Partial(i64),
-	Full(PostFull),
-}
+impl PostFull {
+	/// Names of every field as they are serialised
+	pub const FIELDS: [&'static str; 22] = [
+		"id",
+		"poster",
+		"tag_vector",
+		"create_date",
+		"modified_date",
+		"create_ts",
+		"modified_ts",
+		"description",
+		"rating",
+		"score",
+		"views",
+		"source",
+		"filename",
+		"path",
+		"ext",
+		"size",
+		"width",
+		"height",
+		"is_deleted",
+		"is_locked",
+		"group_id",
+		"created_ip",
+	];
 
-impl Post {
-	pub fn get_id(&self) -> i64 {
-		match self {
-			Self::Partial(id) => *id,
-			Self::Full(post) => post.id,
-		}
-	}
-
-	pub fn into_full(self) -> PostFull {
-		match self {
-			Self::Full(post) => post,
-			_ => panic!("tried to get full post when wasn't full!"),
-		}
-	}
-
-	pub fn as_full(&self) -> &PostFull {
-		match self {
-			Self::Full(ref post) => post,
-			_ => panic!("tried to get full post when wasn't full!"),
-		}
+	/// Strip out anything only moderators are allowed to see
+	pub fn hide_moderation_info(&mut self) {
+		self.created_ip = None;
 	}
+}
 
-	fn if_full<F: FnOnce(&mut PostFull)>(&mut self, f: F) {
-		match self {
-			Post::Partial(_) => {}
-			Post::Full(ref mut p) => f(p),
-		}
-	}
+/// A post we only know the id of, used for queries that don't need the whole row.
+/// Anything selecting a full row returns a `PostFull` instead
+pub struct Post(pub i64);
 
-	fn deserialise<'a>(row: &'a pg::row::Row) -> Self {
-		// Try and get the 17th column (is_deleted) to see if a full post or partial
-		match row.try_get::<usize, bool>(16) {
-			Ok(_) => Post::Full(Self::deserialise_full(row)),
-			Err(_) => Post::Partial(row.get(0)),
-		}
+impl Post {
+	pub fn get_id(&self) -> i64 {
+		self.0
 	}
 
-	pub fn deserialise_full<'a>(row: &'a pg::row::Row) -> PostFull {
+	pub fn deserialise_full(row: &pg::row::Row) -> PostFull {
+		let create_date: Timestamp = row.get(3);
+		let modified_date: Timestamp = row.get(4);
 		PostFull {
 			id: row.get(0),
 			poster: row.get(1),
 			tag_vector: row.get(2),
-			create_date: row.get(3),
-			modified_date: row.get(4),
+			create_date,
+			modified_date,
+			create_ts: create_date.timestamp(),
+			modified_ts: modified_date.timestamp(),
 			description: row.get(5),
 			rating: row.get(6),
 			score: row.get(7),
@@ -87,68 +115,127 @@ impl Post {
 			width: row.get(14),
 			height: row.get(15),
 			is_deleted: row.get(16),
+			group_id: row.get(17),
+			created_ip: row.get(18),
+			is_locked: row.get(19),
 		}
 	}
 }
 
+/// How many posts there are of each rating
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct RatingCounts {
+	pub safe: i64,
+	pub sketchy: i64,
+	pub explicit: i64,
+}
+
 impl Post {
+	/// Count the posts that are up for each rating
+	pub async fn count_by_rating<C: pg::GenericClient>(
+		client: &C,
+	) -> Result<RatingCounts, DatabaseError> {
+		let query = "SELECT rating, count(*) FROM posts WHERE is_deleted='false' GROUP BY rating";
+		let rows = client
+			.query(query, &[])
+			.await
+			.map_err(DatabaseError::from)?;
+		// Ratings without any posts don't come back at all, so they stay at 0
+		let mut counts = RatingCounts::default();
+		for row in rows {
+			let count = row.get(1);
+			match row.get(0) {
+				Rating::Safe => counts.safe = count,
+				Rating::Sketchy => counts.sketchy = count,
+				Rating::Explicit => counts.explicit = count,
+			}
+		}
+		Ok(counts)
+	}
+
 	pub async fn select_post<C: pg::GenericClient>(
 		client: &C,
 		id: i64,
-	) -> Result<Option<Self>, DatabaseError> {
-		let query = "SELECT * FROM posts WHERE id=$1 AND is_deleted='false'";
+	) -> Result<Option<PostFull>, DatabaseError> {
+		let query = format!(
+			"SELECT {} FROM posts WHERE id=$1 AND is_deleted='false'",
+			POST_COLUMNS
+		);
 		let row = client
-			.query_opt(query, &[&id])
+			.query_opt(query.as_str(), &[&id])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		match row {
-			Some(row) => Ok(Some(Self::deserialise(&row))),
+			Some(row) => Ok(Some(Self::deserialise_full(&row))),
 			None => Ok(None),
 		}
 	}
 
-	pub async fn select_can_delete<C: pg::GenericClient>(
+	/// Like `select_post` but also finds posts that have been marked as deleted
+	pub async fn select_post_any<C: pg::GenericClient>(
+		client: &C,
+		id: i64,
+	) -> Result<Option<PostFull>, DatabaseError> {
+		let query = format!("SELECT {} FROM posts WHERE id=$1", POST_COLUMNS);
+		let row = client
+			.query_opt(query.as_str(), &[&id])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.as_ref().map(Self::deserialise_full))
+	}
+
+	/// Fetch a live post and whether `user` is allowed to change it, the row stays
+	/// locked until the transaction ends so the post can't change underneath us
+	pub async fn select_can_change<C: pg::GenericClient>(
 		client: &C,
 		id: i64,
 		user: i32,
-	) -> Result<Option<(bool, Self)>, DatabaseError> {
-		// Select the post to see if it exists, also get the poster id
-		let params: [&(dyn ToSql + Sync); 1] = [&id];
-		let query = "SELECT * FROM posts WHERE id=$1 AND is_deleted='false'";
-		let row1 = client.query_opt(query, &params);
-
-		// Select the user and see if they have permissions
-		let params: [&(dyn ToSql + Sync); 1] = [&user];
-		let query = "SELECT * FROM users WHERE id=$1";
-		let row2 = client.query_one(query, &params);
-
-		// Await on these
-		let (post_row, user_row) =
-			futures::try_join!(row1, row2).map_err(|e| DatabaseError::from(e))?;
-
-		match post_row {
-			Some(post) => {
-				let perms = user_row.get(5);
-				let post = Self::deserialise(&post);
-				let poster = post.as_full().poster;
-				if poster == user || matches!(perms, Perms::Moderator | Perms::Admin) {
-					Ok(Some((true, post)))
-				} else {
-					Ok(Some((false, post)))
-				}
+	) -> Result<Option<(bool, PostFull)>, DatabaseError> {
+		// Fetch the post along with the permissions of whoever is asking in one go,
+		// everything in the post is needed to clean up after it
+		let query = format!(
+			"SELECT {}, (SELECT permissions FROM users WHERE id=$2) FROM posts WHERE id=$1 \
+			 AND is_deleted='false' FOR UPDATE",
+			POST_COLUMNS
+		);
+		let row = client
+			.query_opt(query.as_str(), &[&id, &user])
+			.await
+			.map_err(DatabaseError::from)?;
+
+		match row {
+			Some(row) => {
+				let perms: Option<Perms> = row.get(POST_COLUMNS_LEN);
+				let post = Self::deserialise_full(&row);
+				let allowed =
+					post.poster == user || matches!(perms, Some(Perms::Moderator | Perms::Admin));
+				Ok(Some((allowed, post)))
 			}
 			None => Ok(None),
 		}
 	}
 
+	/// Up to `limit` posts that have been deleted for at least `grace` seconds with an
+	/// id after `after`, lowest id first so callers can work through them in batches.
+	/// Posts deleted before this was tracked always qualify
 	pub async fn select_is_deleted<C: pg::GenericClient>(
 		client: &C,
+		grace: u64,
+		after: i64,
+		limit: u32,
 	) -> Result<Vec<PostFull>, DatabaseError> {
-		let query = "SELECT * FROM posts WHERE is_deleted='true'";
+		let query = format!(
+			"SELECT {} FROM posts WHERE is_deleted='true' AND COALESCE(deleted_at, \
+			 '-infinity') <= now() - $1::bigint * interval '1 second' AND id > $2 \
+			 ORDER BY id ASC LIMIT $3",
+			POST_COLUMNS
+		);
+		// Anything longer than a century or so is out of range for an interval anyway
+		let grace = i64::from(u32::try_from(grace).unwrap_or(u32::MAX));
 		let rows = client
-			.query(query, &[])
+			.query(query.as_str(), &[&grace, &after, &i64::from(limit)])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		let mut posts = Vec::new();
 		for row in rows {
 			posts.push(Self::deserialise_full(&row));
@@ -156,71 +243,59 @@ impl Post {
 		Ok(posts)
 	}
 
-	pub async fn select_post_random<C: pg::GenericClient>(
+	/// Posts modified at or after `since` oldest first, deleted posts are included so
+	/// mirrors know to remove them
+	pub async fn select_modified_since<C: pg::GenericClient>(
 		client: &C,
-	) -> Result<Option<Self>, DatabaseError> {
-		let query = "SELECT * FROM posts ORDER BY RANDOM() LIMIT 1 WHERE is_deleted='false'";
+		since: Timestamp,
+		limit: u32,
+	) -> Result<Vec<PostFull>, DatabaseError> {
+		let query = format!(
+			"SELECT {} FROM posts WHERE modified_date >= $1 ORDER BY modified_date ASC, id ASC \
+			 LIMIT $2::bigint",
+			POST_COLUMNS
+		);
+		let rows = client
+			.query(query.as_str(), &[&since, &i64::from(limit)])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(rows.iter().map(Self::deserialise_full).collect())
+	}
+
+	pub async fn select_post_random_filtered<C: pg::GenericClient>(
+		client: &C,
+		ts_config: &str,
+		search: &SearchFilter<'_>,
+	) -> Result<Option<PostFull>, DatabaseError> {
+		let filter = PostFilter::new(ts_config, search);
+		let query = format!(
+			"SELECT {} FROM posts {} ORDER BY RANDOM() LIMIT 1",
+			POST_COLUMNS,
+			filter.where_clause()
+		);
 		let row = client
-			.query_opt(query, &[])
+			.query_opt(query.as_str(), &filter.params())
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		match row {
-			Some(row) => Ok(Some(Self::deserialise(&row))),
+			Some(row) => Ok(Some(Self::deserialise_full(&row))),
 			None => Ok(None),
 		}
 	}
 
 	pub async fn select_fulltext_tags<C: pg::GenericClient>(
 		client: &C,
-		tags: &[&str],
+		ts_config: &str,
+		search: &SearchFilter<'_>,
 		page: u32,
 		limit: u32,
 		sorting: PostSorting,
 	) -> Result<Vec<PostFull>, DatabaseError> {
-		// If there are no tags, then run other version
-		if tags.len() == 0 {
-			return Self::select_fulltext_empty(client, page, limit, sorting).await;
-		}
-		let (t_inc, t_exc) = ts_query_builder(tags);
-		let rows = if t_exc.is_empty() {
-			let query = format!(
-				"SELECT * FROM posts WHERE tag_vector @@ plainto_tsquery('tag_parser', $1) AND \
-				 is_deleted='false' {} OFFSET {} LIMIT {}",
-				sorting.to_sql(),
-				page * limit,
-				limit
-			);
-			client
-				.query(query.as_str(), &[&t_inc])
-				.await
-				.map_err(|e| DatabaseError::from(e))?
-		} else if t_inc.is_empty() {
-			let query = format!(
-				"SELECT * FROM posts WHERE NOT tag_vector @@ plainto_tsquery('tag_parser', $1) \
-				 AND is_deleted='false' {} OFFSET {} LIMIT {}",
-				sorting.to_sql(),
-				page * limit,
-				limit
-			);
-			println!("gaming, {}", query);
-			client
-				.query(query.as_str(), &[&t_exc])
-				.await
-				.map_err(|e| DatabaseError::from(e))?
-		} else {
-			let query = format!(
-				"SELECT * FROM posts WHERE tag_vector @@ plainto_tsquery('tag_parser', $1) AND \
-				 NOT tag_vector @@ plainto_tsquery('tag_parser', $2) AND     is_deleted='false' \
-				 {} OFFSET {} LIMIT {}",
-				sorting.to_sql(),
-				page * limit,
-				limit
-			);
-			client
-				.query(query.as_str(), &[&t_inc, &t_exc])
-				.await
-				.map_err(|e| DatabaseError::from(e))?
-		};
+		let (query, filter) = fulltext_query(ts_config, search, page, limit, sorting);
+		let rows = client
+			.query(query.as_str(), &filter.params())
+			.await
+			.map_err(DatabaseError::from)?;
 
 		let mut posts = Vec::new();
 		for row in rows {
@@ -229,31 +304,56 @@ impl Post {
 		Ok(posts)
 	}
 
-	async fn select_fulltext_empty<C: pg::GenericClient>(
+	/// Find the ids of the posts either side of `id` when searching with `search` and
+	/// `sorting`, this compares against the sort key so doesn't need an offset
+	pub async fn select_neighbors<C: pg::GenericClient>(
 		client: &C,
-		page: u32,
-		limit: u32,
+		ts_config: &str,
+		search: &SearchFilter<'_>,
+		id: i64,
 		sorting: PostSorting,
-	) -> Result<Vec<PostFull>, DatabaseError> {
-		let query = format!(
-			"SELECT * FROM posts WHERE is_deleted='false' {} OFFSET {} LIMIT {}",
-			sorting.to_sql(),
-			page * limit,
-			limit
-		);
-		let rows = client
-			.query(query.as_str(), &[])
-			.await
-			.map_err(|e| DatabaseError::from(e))?;
-		let mut posts = Vec::new();
-		for row in rows {
-			posts.push(Self::deserialise_full(&row));
-		}
-		Ok(posts)
+	) -> Result<(Option<i64>, Option<i64>), DatabaseError> {
+		let (column, direction) = sorting.order();
+		let (before, after) = match direction {
+			SortDirection::Ascending => ("<", ">"),
+			SortDirection::Descending => (">", "<"),
+		};
+
+		let neighbor = |cmp: &str, reverse: bool| {
+			let mut filter = PostFilter::new(ts_config, search);
+			let id = filter.param(id);
+			filter.condition(format!(
+				"({col}, id) {cmp} (SELECT {col}, id FROM posts WHERE id={id})",
+				col = column.as_sql(),
+				cmp = cmp,
+				id = id
+			));
+			// Walking backwards means flipping the order so the closest post is first
+			let direction = if reverse {
+				direction.reverse()
+			} else {
+				direction
+			};
+			let query = format!(
+				"SELECT id FROM posts {} {} LIMIT 1",
+				filter.where_clause(),
+				order_by((column, direction))
+			);
+			async move {
+				let row = client
+					.query_opt(query.as_str(), &filter.params())
+					.await
+					.map_err(DatabaseError::from)?;
+				Ok::<_, DatabaseError>(row.map(|r| r.get(0)))
+			}
+		};
+		let prev = neighbor(before, true);
+		let next = neighbor(after, false);
+		futures::try_join!(prev, next)
 	}
 
 	pub async fn update_path<C: pg::GenericClient>(
-		&mut self,
+		&self,
 		client: &C,
 		new_path: &str,
 	) -> Result<(), DatabaseError> {
@@ -261,30 +361,109 @@ impl Post {
 		client
 			.execute(query, &[&new_path, &self.get_id()])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
-		self.if_full(|p| {
-			p.path.clear();
-			p.path.push_str(new_path);
-		});
+			.map_err(DatabaseError::from)?;
 		Ok(())
 	}
 
 	pub async fn update_is_deleted<C: pg::GenericClient>(
-		&mut self,
+		&self,
 		client: &C,
 		is_deleted: bool,
 	) -> Result<(), DatabaseError> {
-		let query = "UPDATE posts SET is_deleted=$1 WHERE id=$2";
+		// Bump the modified date so incremental syncs see the change, and note when it
+		// was deleted so it isn't purged straight away
+		let query = "UPDATE posts SET is_deleted=$1, deleted_at=CASE WHEN $1 THEN now() END, \
+		             modified_date=now() WHERE id=$2";
 		client
 			.execute(query, &[&is_deleted, &self.get_id()])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
-		self.if_full(|p| {
-			p.is_deleted = is_deleted;
-		});
+			.map_err(DatabaseError::from)?;
 		Ok(())
 	}
 
+	/// Undo a delete that hasn't been purged yet, `None` if the post isn't deleted
+	pub async fn update_restore<C: pg::GenericClient>(
+		&self,
+		client: &C,
+	) -> Result<Option<PostFull>, DatabaseError> {
+		let query = format!(
+			"UPDATE posts SET is_deleted='false', deleted_at=NULL, modified_date=now() WHERE \
+			 id=$1 AND is_deleted='true' RETURNING {}",
+			POST_COLUMNS
+		);
+		let row = client
+			.query_opt(query.as_str(), &[&self.get_id()])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.as_ref().map(Self::deserialise_full))
+	}
+
+	/// Lock or unlock a post, flipping it if `locked` isn't given. Returns whether
+	/// the post is now locked, `None` if there is no such post
+	pub async fn update_is_locked<C: pg::GenericClient>(
+		&self,
+		client: &C,
+		locked: Option<bool>,
+	) -> Result<Option<bool>, DatabaseError> {
+		let query = "UPDATE posts SET is_locked=COALESCE($1, NOT is_locked), modified_date=now() \
+		             WHERE id=$2 AND is_deleted='false' RETURNING is_locked";
+		let row = client
+			.query_opt(query, &[&locked, &self.get_id()])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.map(|r| r.get(0)))
+	}
+
+	/// Change the details of a live post, anything given as `None` is left as it is
+	pub async fn update_details<C: pg::GenericClient>(
+		&self,
+		client: &C,
+		ts_config: &str,
+		tags: Option<&[&str]>,
+		description: Option<&str>,
+		rating: Option<Rating>,
+	) -> Result<Option<PostFull>, DatabaseError> {
+		// to_tsvector gives back NULL for NULL tags so the old ones are kept
+		let query = format!(
+			"UPDATE posts SET tag_vector=COALESCE(to_tsvector($1::text::regconfig, $2), \
+			 tag_vector), description=COALESCE($3, description), rating=COALESCE($4, rating), \
+			 modified_date=now() WHERE id=$5 AND is_deleted='false' RETURNING {}",
+			POST_COLUMNS
+		);
+		let tags = tags.map(join_tags);
+		let row = client
+			.query_opt(
+				query.as_str(),
+				&[&ts_config, &tags, &description, &rating, &self.get_id()],
+			)
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.as_ref().map(Self::deserialise_full))
+	}
+
+	/// Delete the post right away whether or not it has been marked as deleted,
+	/// decreasing the counts of its tags if that hadn't already been done
+	pub async fn delete_now<C: pg::GenericClient>(
+		&self,
+		client: &C,
+	) -> Result<bool, DatabaseError> {
+		let query = "DELETE FROM posts WHERE id=$1 RETURNING tag_vector, is_deleted";
+		let row = client
+			.query_opt(query, &[&self.get_id()])
+			.await
+			.map_err(DatabaseError::from)?;
+		match row {
+			Some(row) => {
+				let (tags, is_deleted): (TagVector, bool) = (row.get(0), row.get(1));
+				if !is_deleted {
+					Tag::update_decrease_counts(client, &tags.0).await?;
+				}
+				Ok(true)
+			}
+			None => Ok(false),
+		}
+	}
+
 	pub async fn delete_post_checked<C: pg::GenericClient>(
 		&self,
 		client: &C,
@@ -293,35 +472,298 @@ impl Post {
 		let res = client
 			.execute(query, &[&self.get_id()])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		Ok(res != 0)
 	}
 }
 
-fn ts_query_builder(tags: &[&str]) -> (String, String) {
-	let mut include = String::new();
-	let mut exclude = String::new();
+/// Everything search style queries can filter posts by, anything left empty
+/// doesn't filter at all
+#[derive(Debug, Default)]
+pub struct SearchFilter<'a> {
+	pub tags: &'a [&'a str],
+	pub ratings: &'a [Rating],
+	/// Only match posts without any tags at all
+	pub untagged: bool,
+	/// Only match posts with exactly the included tags and no others
+	pub exact: bool,
+	/// Whether deleted posts are left out, kept in or the only ones matched
+	pub deleted: DeletedMode,
+	pub min_score: Option<i32>,
+	pub min_views: Option<i32>,
+}
+
+/// Accumulates the conditions and parameters of a post search so that the
+/// different search style queries can share their filtering
+struct PostFilter {
+	conditions: Vec<String>,
+	params: Vec<Box<dyn ToSql + Sync + Send>>,
+}
+
+impl PostFilter {
+	fn new(ts_config: &str, search: &SearchFilter<'_>) -> Self {
+		let mut filter = PostFilter {
+			conditions: Vec::new(),
+			params: Vec::new(),
+		};
+		if let Some(condition) = search.deleted.condition() {
+			filter.condition(condition.to_owned());
+		}
+		filter.tags(ts_config, search.tags, search.exact);
+		if !search.ratings.is_empty() {
+			let ratings = filter.param(search.ratings.to_vec());
+			filter.condition(format!("rating = ANY({})", ratings));
+		}
+		if search.untagged {
+			filter.condition("tag_vector = ''::tsvector".to_owned());
+		}
+		if let Some(min_score) = search.min_score {
+			let min_score = filter.param(min_score);
+			filter.condition(format!("score >= {}", min_score));
+		}
+		if let Some(min_views) = search.min_views {
+			let min_views = filter.param(min_views);
+			filter.condition(format!("views >= {}", min_views));
+		}
+		filter
+	}
+
+	/// Bind a new parameter, returning the placeholder to use for it
+	fn param<T: ToSql + Sync + Send + 'static>(&mut self, value: T) -> String {
+		self.params.push(Box::new(value));
+		format!("${}", self.params.len())
+	}
+
+	fn condition(&mut self, condition: String) {
+		self.conditions.push(condition);
+	}
+
+	/// Only match posts with all the included tags and none of the excluded, when
+	/// `exact` the post must not have any other tags either. Tags ending in `*` match
+	/// any tag starting with the rest of it
+	fn tags(&mut self, ts_config: &str, tags: &[&str], exact: bool) {
+		if tags.is_empty() {
+			if exact {
+				self.condition("length(tag_vector) = 0".to_owned());
+			}
+			return;
+		}
+		let query = ts_query_builder(tags);
+		let config = self.param(ts_config.to_owned());
+		if let Some(t_inc) = self.tsquery(&config, query.include, query.include_prefix) {
+			self.condition(format!("tag_vector @@ {}", t_inc));
+			// Having all of them and the same number of lexemes means nothing else,
+			// counting them the same way they were stored copes with any config
+			if exact {
+				let all = self.param(query.include_all);
+				self.condition(format!(
+					"length(tag_vector) = length(to_tsvector({}::text::regconfig, {}))",
+					config, all
+				));
+			}
+		} else if exact {
+			self.condition("length(tag_vector) = 0".to_owned());
+		}
+		if let Some(t_exc) = self.tsquery(&config, query.exclude, query.exclude_prefix) {
+			self.condition(format!("NOT tag_vector @@ {}", t_exc));
+		}
+	}
+
+	/// A tsquery matching every one of the `plain` tags and `prefix` terms, `None` if
+	/// there aren't any. The prefix terms are already quoted by `prefix_term`
+	fn tsquery(&mut self, config: &str, plain: String, prefix: String) -> Option<String> {
+		let plain = (!plain.is_empty()).then(|| {
+			let plain = self.param(plain);
+			format!("plainto_tsquery({}::text::regconfig, {})", config, plain)
+		});
+		let prefix = (!prefix.is_empty()).then(|| {
+			let prefix = self.param(prefix);
+			format!("to_tsquery({}::text::regconfig, {})", config, prefix)
+		});
+		match (plain, prefix) {
+			(Some(plain), Some(prefix)) => Some(format!("({} && {})", plain, prefix)),
+			(plain, prefix) => plain.or(prefix),
+		}
+	}
+
+	fn where_clause(&self) -> String {
+		if self.conditions.is_empty() {
+			return String::new();
+		}
+		format!("WHERE {}", self.conditions.join(" AND "))
+	}
+
+	fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+		self.params
+			.iter()
+			.map(|p| p.as_ref() as &(dyn ToSql + Sync))
+			.collect()
+	}
+}
+
+/// Query for a page of search results along with its parameters, the offset and
+/// limit are bound as parameters so the query text stays the same across pages
+fn fulltext_query(
+	ts_config: &str,
+	search: &SearchFilter<'_>,
+	page: u32,
+	limit: u32,
+	sorting: PostSorting,
+) -> (String, PostFilter) {
+	let mut filter = PostFilter::new(ts_config, search);
+	let (offset, limit) = page_bounds(page, limit);
+	let query = format!(
+		"SELECT {} FROM posts {} {} OFFSET {}::bigint LIMIT {}::bigint",
+		POST_COLUMNS,
+		filter.where_clause(),
+		order_by(sorting.order()),
+		filter.param(offset),
+		filter.param(limit)
+	);
+	(query, filter)
+}
+
+/// `ORDER BY` clause for a sort, with the id as a tiebreaker so pages are stable
+fn order_by((column, direction): (SortColumn, SortDirection)) -> String {
+	format!(
+		"ORDER BY {col} {dir}, id {dir}",
+		col = column.as_sql(),
+		dir = direction.as_sql()
+	)
+}
+
+/// Turn a page number and page size into an `(offset, limit)` pair, the offset
+/// saturates as two 32 bit inputs can still overflow a signed 64 bit product
+pub(crate) fn page_bounds(page: u32, limit: u32) -> (i64, i64) {
+	(
+		i64::from(page).saturating_mul(i64::from(limit)),
+		i64::from(limit),
+	)
+}
+
+/// The tags of a search split up ready to be made into tsqueries
+#[derive(Default)]
+struct TagQuery {
+	include: String,
+	exclude: String,
+	/// `to_tsquery` text for the included and excluded prefix tags
+	include_prefix: String,
+	exclude_prefix: String,
+	/// Every included tag, prefix tags without their `*`
+	include_all: String,
+}
+
+fn ts_query_builder(tags: &[&str]) -> TagQuery {
+	let mut query = TagQuery::default();
 	for tag in tags {
-		if &tag[0..1] == "!" {
-			exclude.push_str(&tag[1..]);
-			exclude.push(',');
-		} else {
-			include.push_str(tag);
-			include.push(',');
+		let (tag, excluded) = match tag.strip_prefix('!') {
+			Some(tag) => (tag, true),
+			None => (*tag, false),
+		};
+		if !excluded {
+			query.include_all.push_str(tag.trim_end_matches('*'));
+			query.include_all.push(',');
 		}
+		let (list, sep, term) = match prefix_term(tag) {
+			Some(term) if excluded => (&mut query.exclude_prefix, " & ", term),
+			Some(term) => (&mut query.include_prefix, " & ", term),
+			None if excluded => (&mut query.exclude, ",", tag.to_owned()),
+			None => (&mut query.include, ",", tag.to_owned()),
+		};
+		if !list.is_empty() {
+			list.push_str(sep);
+		}
+		list.push_str(&term);
 	}
-	include.pop();
-	exclude.pop();
-	println!("{:?} , {:?}", include, exclude);
-	(include, exclude)
+	query.include_all.pop();
+	query
+}
+
+/// Turn a tag ending in `*` into a quoted `to_tsquery` prefix term. Everything is
+/// inside the quotes so nothing in the tag can be read as tsquery syntax
+fn prefix_term(tag: &str) -> Option<String> {
+	let stem = tag.strip_suffix('*')?.trim_end_matches('*');
+	let mut term = String::with_capacity(stem.len() + 4);
+	term.push('\'');
+	for c in stem.chars() {
+		match c {
+			'\'' => term.push_str("''"),
+			'\\' => term.push_str("\\\\"),
+			c => term.push(c),
+		}
+	}
+	term.push_str("':*");
+	Some(term)
+}
+
+/// Create a new group for a gallery upload, returning its id
+pub async fn insert_post_group<C: pg::GenericClient>(
+	client: &C,
+	poster: i32,
+) -> Result<i64, DatabaseError> {
+	let query = "INSERT INTO post_groups (poster) VALUES($1) RETURNING id";
+	let row = client
+		.query_one(query, &[&poster])
+		.await
+		.map_err(DatabaseError::from)?;
+	Ok(row.get(0))
+}
+
+/// What the upload made with `key` answered, if it went through within the last
+/// `ttl` seconds
+pub async fn select_idempotent_upload<C: pg::GenericClient>(
+	client: &C,
+	key: &str,
+	ttl: u64,
+) -> Result<Option<String>, DatabaseError> {
+	let query = "SELECT response FROM post_idempotency WHERE key=$1 AND created > now() - \
+	             $2::bigint * interval '1 second'";
+	// Anything longer than a century or so is out of range for an interval anyway
+	let ttl = i64::from(u32::try_from(ttl).unwrap_or(u32::MAX));
+	let row = client
+		.query_opt(query, &[&key, &ttl])
+		.await
+		.map_err(DatabaseError::from)?;
+	Ok(row.map(|r| r.get(0)))
+}
+
+/// Remember what the upload made with `key` answered, call this in the upload's
+/// transaction. Returns false if another upload already used the key within `ttl`
+/// seconds, expired keys are cleared out as we go
+pub async fn insert_idempotent_upload<C: pg::GenericClient>(
+	client: &C,
+	key: &str,
+	response: &str,
+	ttl: u64,
+) -> Result<bool, DatabaseError> {
+	// Anything longer than a century or so is out of range for an interval anyway
+	let ttl = i64::from(u32::try_from(ttl).unwrap_or(u32::MAX));
+	let query = "DELETE FROM post_idempotency WHERE created <= now() - $1::bigint * interval '1 \
+	             second'";
+	client
+		.execute(query, &[&ttl])
+		.await
+		.map_err(DatabaseError::from)?;
+	let query = "INSERT INTO post_idempotency (key, response) VALUES ($1, $2) ON CONFLICT (key) \
+	             DO NOTHING";
+	let res = client
+		.execute(query, &[&key, &response])
+		.await
+		.map_err(DatabaseError::from)?;
+	Ok(res != 0)
 }
 
 impl std::convert::From<i64> for Post {
 	fn from(id: i64) -> Post {
-		Post::Partial(id)
+		Post(id)
 	}
 }
 
+/// Tags as the single string `to_tsvector` is given
+fn join_tags(tags: &[&str]) -> String {
+	tags.iter().flat_map(|s| s.chars().chain([','])).collect()
+}
+
 #[derive(Debug)]
 pub struct NewPost<'a> {
 	pub filename: &'a str,
@@ -333,25 +775,27 @@ pub struct NewPost<'a> {
 	pub description: &'a str,
 	pub tags: &'a [&'a str],
 	pub poster: i32,
+	pub group_id: Option<i64>,
+	pub created_ip: Option<IpAddr>,
 }
 
 impl NewPost<'_> {
 	pub async fn insert_into<C: pg::GenericClient>(
 		&self,
 		client: &C,
+		ts_config: &str,
 	) -> Result<PostFull, DatabaseError> {
-		let query = "INSERT INTO posts (filename, path, ext, size, width, height, description, \
-		             rating, tag_vector, poster) VALUES($1, $2, $3, $4, $5, $6, $7, $8, \
-		             to_tsvector('tag_parser', $9), $10) RETURNING *";
-		let tags: String = self
-			.tags
-			.iter()
-			.flat_map(|s| s.chars().chain([',']))
-			.collect();
+		let query = format!(
+			"INSERT INTO posts (filename, path, ext, size, width, height, description, rating, \
+			 tag_vector, poster, group_id, created_ip) VALUES($1, $2, $3, $4, $5, $6, $7, \
+			 $8, to_tsvector($9::text::regconfig, $10), $11, $12, $13) RETURNING {}",
+			POST_COLUMNS
+		);
+		let tags = join_tags(self.tags);
 
 		let row = client
 			.query_one(
-				query,
+				query.as_str(),
 				&[
 					&self.filename,
 					&self.path,
@@ -361,12 +805,744 @@ impl NewPost<'_> {
 					&self.dimensions.1,
 					&self.description,
 					&self.rating,
+					&ts_config,
 					&tags,
 					&self.poster,
+					&self.group_id,
+					&self.created_ip,
 				],
 			)
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		Ok(Post::deserialise_full(&row))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	// Anything touching postgres is ignored, see `database::fixtures` for what it needs
+	use super::*;
+	use crate::database::fixtures::{self, insert_post, insert_post_fixture, insert_user};
+
+	#[test]
+	fn prefix_term_escapes() {
+		assert_eq!(prefix_term("plain"), None);
+		assert_eq!(prefix_term("ta*").as_deref(), Some("'ta':*"));
+		assert_eq!(prefix_term("ta***").as_deref(), Some("'ta':*"));
+		// tsquery operators are only ever inside the quotes
+		assert_eq!(prefix_term("a:b&c|d!e*").as_deref(), Some("'a:b&c|d!e':*"));
+		assert_eq!(prefix_term("it's*").as_deref(), Some("'it''s':*"));
+		assert_eq!(prefix_term("'*").as_deref(), Some("'''':*"));
+		assert_eq!(prefix_term("a\\b*").as_deref(), Some("'a\\\\b':*"));
+		assert_eq!(prefix_term("a*b*").as_deref(), Some("'a*b':*"));
+	}
+
+	#[test]
+	fn ts_query_builder_splits_tags() {
+		let query = ts_query_builder(&["a:b", "!c|d*", "e'f*", "!g&h", "i!*"]);
+		assert_eq!(query.include, "a:b");
+		assert_eq!(query.exclude, "g&h");
+		assert_eq!(query.include_prefix, "'e''f':* & 'i!':*");
+		assert_eq!(query.exclude_prefix, "'c|d':*");
+		assert_eq!(query.include_all, "a:b,e'f,i!");
+	}
+
+	#[test]
+	fn ts_query_builder_empty() {
+		let query = ts_query_builder(&[]);
+		assert!(query.include.is_empty());
+		assert!(query.exclude.is_empty());
+		assert!(query.include_prefix.is_empty());
+		assert!(query.exclude_prefix.is_empty());
+		assert!(query.include_all.is_empty());
+	}
+
+	fn search(tags: &'static [&'static str]) -> SearchFilter<'static> {
+		SearchFilter {
+			tags,
+			ratings: &[Rating::Safe],
+			untagged: false,
+			exact: false,
+			deleted: DeletedMode::default(),
+			min_score: Some(3),
+			min_views: None,
+		}
+	}
+
+	#[test]
+	fn fulltext_query_same_across_pages() {
+		let search = search(&["a", "b*"]);
+		let sorting = PostSorting::DateDescending;
+		let (first, first_filter) = fulltext_query("simple", &search, 0, 20, sorting);
+		let (later, later_filter) = fulltext_query("simple", &search, 7, 20, sorting);
+		let (bigger, _) = fulltext_query("simple", &search, 3, 50, sorting);
+		assert_eq!(first, later);
+		assert_eq!(first, bigger);
+		assert!(!first.contains("140"));
+
+		let bounds = |filter: &PostFilter| {
+			let params = filter.params();
+			format!("{:?}", &params[params.len() - 2..])
+		};
+		assert_eq!(bounds(&first_filter), "[0, 20]");
+		assert_eq!(bounds(&later_filter), "[140, 20]");
+	}
+
+	#[test]
+	fn page_bounds_saturate() {
+		assert_eq!(page_bounds(0, 20), (0, 20));
+		assert_eq!(page_bounds(3, 20), (60, 20));
+		assert_eq!(
+			page_bounds(u32::MAX, u32::MAX),
+			(i64::MAX, i64::from(u32::MAX))
+		);
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn prefix_terms_are_valid_tsquery() {
+		let pool = fixtures::pool();
+		let conn = pool.get().await.unwrap();
+		for tag in [
+			"a:b*", "c&d*", "e|f*", "!g*", "h'i*", "j\\k*", "l)m(*", "n<->o*",
+		] {
+			let query = ts_query_builder(&[tag]);
+			let term = if tag.starts_with('!') {
+				query.exclude_prefix
+			} else {
+				query.include_prefix
+			};
+			let matched: bool = conn
+				.query_one(
+					"SELECT to_tsvector('simple', $1) @@ to_tsquery('simple', $2)",
+					&[&tag.trim_start_matches('!').trim_end_matches('*'), &term],
+				)
+				.await
+				.unwrap_or_else(|e| panic!("{:?} gave {:?}: {}", tag, term, e))
+				.get(0);
+			assert!(matched, "{:?} gave {:?}", tag, term);
+		}
+	}
+
+	#[test]
+	fn post_columns_len() {
+		assert_eq!(POST_COLUMNS.split(',').count(), POST_COLUMNS_LEN);
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn reads_survive_column_changes() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		// Never committed so the table goes back to how it was
+		let trans = conn.transaction().await.unwrap();
+		// Move a column from the middle to the end, and add another after it
+		trans
+			.batch_execute(
+				"ALTER TABLE posts DROP COLUMN score; ALTER TABLE posts ADD COLUMN extra text \
+				 NOT NULL DEFAULT 'extra'; ALTER TABLE posts ADD COLUMN score integer NOT \
+				 NULL DEFAULT 7",
+			)
+			.await
+			.unwrap();
+
+		let name = format!("columns-test-{}", std::process::id());
+		let user = insert_user::<pg::Transaction<'_>>(&trans, &name).await;
+		let inserted = insert_post::<pg::Transaction<'_>>(&trans, user.id, &["a", "b"]).await;
+		assert_eq!(inserted.score, 7);
+
+		let post = Post::select_post::<pg::Transaction<'_>>(&trans, inserted.id)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(post.id, inserted.id);
+		assert_eq!(post.poster, user.id);
+		assert_eq!(post.score, 7);
+		assert_eq!(post.views, 0);
+		assert_eq!(post.filename, "a.png");
+		assert_eq!(post.path, "00");
+		assert_eq!(post.size, 12);
+		assert_eq!((post.width, post.height), (30, 40));
+		assert_eq!(post.description.as_deref(), Some("fixture"));
+		assert!(matches!(post.rating, Rating::Safe));
+		assert!(!post.is_deleted && !post.is_locked);
+
+		let (allowed, post) =
+			Post::select_can_change::<pg::Transaction<'_>>(&trans, post.id, user.id)
+				.await
+				.unwrap()
+				.unwrap();
+		assert!(allowed);
+		assert_eq!(post.score, 7);
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn deleted_posts_still_found() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("gone-test-{}", std::process::id());
+		let post = insert_post_fixture::<pg::Transaction<'_>>(&trans, &name, &[]).await;
+		trans
+			.execute(
+				"UPDATE posts SET is_deleted='true' WHERE id=$1",
+				&[&post.id],
+			)
+			.await
+			.unwrap();
+
+		let live = Post::select_post::<pg::Transaction<'_>>(&trans, post.id)
+			.await
+			.unwrap();
+		assert!(live.is_none());
+		let any = Post::select_post_any::<pg::Transaction<'_>>(&trans, post.id)
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(any.is_deleted);
+		let missing = Post::select_post_any::<pg::Transaction<'_>>(&trans, post.id + 1)
+			.await
+			.unwrap();
+		assert!(missing.is_none());
+	}
+
+	const SORTINGS: [PostSorting; 7] = [
+		PostSorting::DateAscending,
+		PostSorting::DateDescending,
+		PostSorting::ModifiedAscending,
+		PostSorting::ModifiedDescending,
+		PostSorting::VoteAscending,
+		PostSorting::VoteDescending,
+		PostSorting::Hot,
+	];
+
+	#[test]
+	fn every_sorting_has_tiebreaker() {
+		for sorting in SORTINGS {
+			let (_, direction) = sorting.order();
+			let order = order_by(sorting.order());
+			assert!(
+				order.ends_with(&format!(", id {}", direction.as_sql())),
+				"{:?} gave {}",
+				sorting,
+				order
+			);
+		}
+	}
+
+	#[test]
+	fn sort_clauses_are_fixed_sql() {
+		for sorting in SORTINGS {
+			let (column, direction) = sorting.order();
+			// Nothing that could take a parameter, close a statement or quote a value
+			let sql = column.as_sql();
+			assert!(
+				!sql.contains(['$', ';', '\'', '"']) && !sql.contains("--"),
+				"{:?} gave {}",
+				sorting,
+				sql
+			);
+			assert!(matches!(direction.as_sql(), "ASC" | "DESC"));
+			assert_eq!(direction.reverse().reverse(), direction);
+			assert_ne!(direction.reverse(), direction);
+		}
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn sort_clauses_prepare() {
+		let pool = fixtures::pool();
+		let conn = pool.get().await.unwrap();
+		for sorting in SORTINGS {
+			for direction in [SortDirection::Ascending, SortDirection::Descending] {
+				let (column, _) = sorting.order();
+				let query = format!(
+					"SELECT id FROM posts {} OFFSET $1::bigint LIMIT $2::bigint",
+					order_by((column, direction))
+				);
+				let statement = conn
+					.prepare(&query)
+					.await
+					.unwrap_or_else(|e| panic!("{:?} gave {}: {}", sorting, query, e));
+				// Only the offset and limit are parameters
+				assert_eq!(statement.params().len(), 2, "{:?}", sorting);
+			}
+		}
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn pages_neither_overlap_nor_skip() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("order-test-{}", std::process::id());
+		let user = insert_user::<pg::Transaction<'_>>(&trans, &name).await;
+		let tag = format!("ordertest{}", std::process::id());
+		let tags = [tag.as_str()];
+		let mut ids = Vec::new();
+		for _ in 0..10 {
+			ids.push(
+				insert_post::<pg::Transaction<'_>>(&trans, user.id, &tags)
+					.await
+					.id,
+			);
+		}
+		// Every post ties on every sort key, leaving only the id to order them by
+		trans
+			.execute(
+				"UPDATE posts SET create_date='2020-01-01', modified_date='2020-01-01', \
+				 score=3 WHERE poster=$1",
+				&[&user.id],
+			)
+			.await
+			.unwrap();
+		ids.sort_unstable();
+
+		let search_tags = [tag.as_str()];
+		let search = SearchFilter {
+			tags: &search_tags,
+			ratings: &[],
+			untagged: false,
+			exact: false,
+			deleted: DeletedMode::default(),
+			min_score: None,
+			min_views: None,
+		};
+		for sorting in SORTINGS {
+			let mut seen = Vec::new();
+			for page in 0..4 {
+				let posts = Post::select_fulltext_tags::<pg::Transaction<'_>>(
+					&trans, "simple", &search, page, 3, sorting,
+				)
+				.await
+				.unwrap();
+				seen.extend(posts.iter().map(|p| p.id));
+			}
+			let mut sorted = seen.clone();
+			sorted.sort_unstable();
+			assert_eq!(sorted, ids, "{:?} gave {:?}", sorting, seen);
+			// Ties are broken by id in the same direction as the sort
+			if sorting.order().1 == SortDirection::Descending {
+				sorted.reverse();
+			}
+			assert_eq!(seen, sorted, "{:?}", sorting);
+		}
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn purge_waits_for_grace() {
+		const WEEK: u64 = 60 * 60 * 24 * 7;
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("grace-test-{}", std::process::id());
+		let post = insert_post_fixture::<pg::Transaction<'_>>(&trans, &name, &[]).await;
+		let id = post.id;
+		let trans = &trans;
+		let purgeable = |grace| async move {
+			Post::select_is_deleted::<pg::Transaction<'_>>(trans, grace, id - 1, 1)
+				.await
+				.unwrap()
+				.iter()
+				.any(|p| p.id == id)
+		};
+
+		Post(post.id)
+			.update_is_deleted::<pg::Transaction<'_>>(trans, true)
+			.await
+			.unwrap();
+		// Freshly deleted posts wait out the grace period
+		assert!(!purgeable(WEEK).await);
+		assert!(purgeable(0).await);
+
+		trans
+			.execute(
+				"UPDATE posts SET deleted_at=now() - interval '8 days' WHERE id=$1",
+				&[&post.id],
+			)
+			.await
+			.unwrap();
+		assert!(purgeable(WEEK).await);
+
+		// Deleted before the column existed
+		trans
+			.execute("UPDATE posts SET deleted_at=NULL WHERE id=$1", &[&post.id])
+			.await
+			.unwrap();
+		assert!(purgeable(WEEK).await);
+
+		// Restoring takes it out of the recycle bin altogether
+		Post(post.id)
+			.update_is_deleted::<pg::Transaction<'_>>(trans, false)
+			.await
+			.unwrap();
+		assert!(!purgeable(0).await);
+	}
+
+	#[test]
+	fn thresholds_bound_as_parameters() {
+		let mut search = search(&[]);
+		search.ratings = &[];
+		search.min_views = Some(10);
+		let filter = PostFilter::new("simple", &search);
+		let clause = filter.where_clause();
+		assert!(
+			clause.ends_with("score >= $1 AND views >= $2"),
+			"{}",
+			clause
+		);
+		assert_eq!(format!("{:?}", filter.params()), "[3, 10]");
+
+		search.min_score = None;
+		search.min_views = None;
+		let clause = PostFilter::new("simple", &search).where_clause();
+		assert!(
+			!clause.contains("score") && !clause.contains("views"),
+			"{}",
+			clause
+		);
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn thresholds_exclude_posts() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("threshold-test-{}", std::process::id());
+		let tag = format!("thresholdtest{}", std::process::id());
+		let tags = [tag.as_str()];
+		let first = insert_post_fixture::<pg::Transaction<'_>>(&trans, &name, &tags).await;
+		let mut ids = vec![first.id];
+		for _ in 0..3 {
+			ids.push(
+				insert_post::<pg::Transaction<'_>>(&trans, first.poster, &tags)
+					.await
+					.id,
+			);
+		}
+		for (id, (score, views)) in ids.iter().zip([(0, 0), (5, 0), (5, 20), (1, 50)]) {
+			trans
+				.execute(
+					"UPDATE posts SET score=$1, views=$2 WHERE id=$3",
+					&[&score, &views, id],
+				)
+				.await
+				.unwrap();
+		}
+
+		let trans = &trans;
+		let found = |min_score: Option<i32>, min_views: Option<i32>| async move {
+			let search = SearchFilter {
+				tags: &tags,
+				ratings: &[],
+				untagged: false,
+				exact: false,
+				deleted: DeletedMode::default(),
+				min_score,
+				min_views,
+			};
+			let posts = Post::select_fulltext_tags::<pg::Transaction<'_>>(
+				trans,
+				"simple",
+				&search,
+				0,
+				10,
+				PostSorting::DateAscending,
+			)
+			.await
+			.unwrap();
+			posts.iter().map(|p| p.id).collect::<Vec<_>>()
+		};
+		assert_eq!(found(None, None).await, ids);
+		assert_eq!(found(Some(3), None).await, [ids[1], ids[2]]);
+		assert_eq!(found(None, Some(10)).await, [ids[2], ids[3]]);
+		assert_eq!(found(Some(3), Some(10)).await, [ids[2]]);
+		// Thresholds are inclusive
+		assert_eq!(found(Some(5), Some(20)).await, [ids[2]]);
+		assert!(found(Some(6), None).await.is_empty());
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn epoch_seconds_match_dates() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("epoch-test-{}", std::process::id());
+		let post = insert_post_fixture::<pg::Transaction<'_>>(&trans, &name, &[]).await;
+		trans
+			.execute(
+				"UPDATE posts SET create_date='2021-02-03T04:05:06Z', \
+				 modified_date='2021-02-03T04:05:07.9Z' WHERE id=$1",
+				&[&post.id],
+			)
+			.await
+			.unwrap();
+		let post = Post::select_post::<pg::Transaction<'_>>(&trans, post.id)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(post.create_ts, 1612325106);
+		// Fractions of a second are dropped
+		assert_eq!(post.modified_ts, 1612325107);
+		assert_eq!(post.create_ts, post.create_date.timestamp());
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn update_details_keeps_what_isnt_given() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("details-test-{}", std::process::id());
+		let post = insert_post_fixture::<pg::Transaction<'_>>(&trans, &name, &["a"]).await;
+		let handle = Post(post.id);
+
+		let edited = handle
+			.update_details::<pg::Transaction<'_>>(&trans, "simple", None, Some("new"), None)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(edited.tag_vector.0, ["a"]);
+		assert_eq!(edited.description.as_deref(), Some("new"));
+		assert!(matches!(edited.rating, Rating::Safe));
+
+		let edited = handle
+			.update_details::<pg::Transaction<'_>>(
+				&trans,
+				"simple",
+				Some(&["c", "b"]),
+				None,
+				Some(Rating::Explicit),
+			)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(edited.tag_vector.0, ["b", "c"]);
+		assert_eq!(edited.description.as_deref(), Some("new"));
+		assert!(matches!(edited.rating, Rating::Explicit));
+
+		// Deleted posts are left alone
+		handle
+			.update_is_deleted::<pg::Transaction<'_>>(&trans, true)
+			.await
+			.unwrap();
+		let edited = handle
+			.update_details::<pg::Transaction<'_>>(&trans, "simple", None, Some("gone"), None)
+			.await
+			.unwrap();
+		assert!(edited.is_none());
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn simple_config_splits_multi_word_tags() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("split-test-{}", std::process::id());
+		let tag = format!("splittest{}", std::process::id());
+		let post = insert_post_fixture::<pg::Transaction<'_>>(
+			&trans,
+			&name,
+			&[&tag, "long_hair", "blue-eyes"],
+		)
+		.await;
+		// Hyphenated words keep the whole as well as the parts, underscores only the parts
+		let mut lexemes = post.tag_vector.0.clone();
+		lexemes.retain(|t| *t != tag);
+		assert_eq!(lexemes, ["blue", "blue-eyes", "eyes", "hair", "long"]);
+
+		let trans = &trans;
+		let found = |tags: &'static [&'static str], exact: bool| {
+			let tag = tag.clone();
+			async move {
+				let mut tags = tags.to_vec();
+				tags.push(&tag);
+				let search = SearchFilter {
+					tags: &tags,
+					exact,
+					..Default::default()
+				};
+				Post::select_fulltext_tags::<pg::Transaction<'_>>(
+					trans,
+					"simple",
+					&search,
+					0,
+					10,
+					PostSorting::DateAscending,
+				)
+				.await
+				.unwrap()
+				.iter()
+				.any(|p| p.id == post.id)
+			}
+		};
+		// Every part matches on its own, so excluding one takes out the whole tag
+		assert!(found(&["long_hair"], false).await);
+		assert!(found(&["hair"], false).await);
+		assert!(found(&["blue"], false).await);
+		assert!(!found(&["!long"], false).await);
+		assert!(!found(&["!eyes"], false).await);
+		// Exact matching counts lexemes the same way on both sides, so the full tags
+		// match but so do their parts when every one of them is given
+		assert!(found(&["long_hair", "blue-eyes"], true).await);
+		assert!(found(&["long", "hair", "blue-eyes"], true).await);
+		assert!(!found(&["hair", "blue-eyes"], true).await);
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn idempotency_keys_used_once() {
+		const DAY: u64 = 60 * 60 * 24;
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let key = format!("idempotency-test-{}", std::process::id());
+		let trans = &trans;
+		let insert = |response: &'static str| {
+			let key = key.clone();
+			async move {
+				insert_idempotent_upload::<pg::Transaction<'_>>(trans, &key, response, DAY)
+					.await
+					.unwrap()
+			}
+		};
+
+		assert!(insert("first").await);
+		assert!(!insert("second").await);
+		let stored = select_idempotent_upload::<pg::Transaction<'_>>(trans, &key, DAY)
+			.await
+			.unwrap();
+		assert_eq!(stored.as_deref(), Some("first"));
+
+		// Once it has expired the key is free to be used again
+		trans
+			.execute(
+				"UPDATE post_idempotency SET created=now() - interval '2 days' WHERE key=$1",
+				&[&key],
+			)
+			.await
+			.unwrap();
+		let stored = select_idempotent_upload::<pg::Transaction<'_>>(trans, &key, DAY)
+			.await
+			.unwrap();
+		assert!(stored.is_none());
+		assert!(insert("third").await);
+		let stored = select_idempotent_upload::<pg::Transaction<'_>>(trans, &key, DAY)
+			.await
+			.unwrap();
+		assert_eq!(stored.as_deref(), Some("third"));
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn edited_post_tops_modified_descending() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let name = format!("modified-test-{}", std::process::id());
+		let tag = format!("modifiedtest{}", std::process::id());
+		let tags = [tag.as_str()];
+		let older = insert_post_fixture::<pg::Transaction<'_>>(&trans, &name, &tags).await;
+		let newer = insert_post::<pg::Transaction<'_>>(&trans, older.poster, &tags).await;
+		let trans = &trans;
+		// now() is the same for the whole transaction, so anything changed here has to
+		// be moved back before the next change can be told apart from it
+		let backdate = |id: i64, date: &'static str| async move {
+			trans
+				.execute(
+					"UPDATE posts SET modified_date=$2::text::timestamptz WHERE id=$1",
+					&[&id, &date],
+				)
+				.await
+				.unwrap();
+		};
+		trans
+			.execute(
+				"UPDATE posts SET create_date='2020-01-01' WHERE id=$1",
+				&[&older.id],
+			)
+			.await
+			.unwrap();
+		trans
+			.execute(
+				"UPDATE posts SET create_date='2020-01-02' WHERE id=$1",
+				&[&newer.id],
+			)
+			.await
+			.unwrap();
+		backdate(older.id, "2020-01-01").await;
+		backdate(newer.id, "2020-01-02").await;
+
+		let order = |sorting| async move {
+			let search = SearchFilter {
+				tags: &tags,
+				ratings: &[],
+				untagged: false,
+				exact: false,
+				deleted: DeletedMode::default(),
+				min_score: None,
+				min_views: None,
+			};
+			Post::select_fulltext_tags::<pg::Transaction<'_>>(
+				trans, "simple", &search, 0, 10, sorting,
+			)
+			.await
+			.unwrap()
+			.iter()
+			.map(|p| p.id)
+			.collect::<Vec<_>>()
+		};
+		assert_eq!(
+			order(PostSorting::ModifiedDescending).await,
+			[newer.id, older.id]
+		);
+
+		// Retagging the older post brings it to the top, the creation order stays
+		Post(older.id)
+			.update_details::<pg::Transaction<'_>>(
+				trans,
+				"simple",
+				Some(&[tag.as_str(), "retagged"]),
+				None,
+				None,
+			)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(
+			order(PostSorting::ModifiedDescending).await,
+			[older.id, newer.id]
+		);
+		assert_eq!(
+			order(PostSorting::ModifiedAscending).await,
+			[newer.id, older.id]
+		);
+		assert_eq!(
+			order(PostSorting::DateDescending).await,
+			[newer.id, older.id]
+		);
+
+		// A post restored after being deleted a while ago is back on top too
+		backdate(older.id, "2020-01-05").await;
+		Post(newer.id)
+			.update_is_deleted::<pg::Transaction<'_>>(trans, true)
+			.await
+			.unwrap();
+		backdate(newer.id, "2020-01-03").await;
+		Post(newer.id)
+			.update_restore::<pg::Transaction<'_>>(trans)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(
+			order(PostSorting::ModifiedDescending).await,
+			[newer.id, older.id]
+		);
+	}
+}
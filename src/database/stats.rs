@@ -0,0 +1,41 @@
+use crate::database::{
+	pg,
+	post::{Post, RatingCounts},
+	DatabaseError,
+};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Stats {
+	posts: i64,
+	active_posts: i64,
+	tags: i64,
+	users: i64,
+	views: i64,
+	storage_bytes: i64,
+	by_rating: RatingCounts,
+}
+
+impl Stats {
+	pub async fn select_stats<C: pg::GenericClient>(client: &C) -> Result<Stats, DatabaseError> {
+		let query = "SELECT \
+		             (SELECT COUNT(*) FROM posts), \
+		             (SELECT COUNT(*) FROM posts WHERE is_deleted='false'), \
+		             (SELECT COUNT(*) FROM tags), \
+		             (SELECT COUNT(*) FROM users), \
+		             (SELECT COALESCE(SUM(views), 0)::bigint FROM posts), \
+		             (SELECT COALESCE(SUM(size), 0)::bigint FROM posts)";
+		let row = client
+			.query_one(query, &[])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(Stats {
+			posts: row.get(0),
+			active_posts: row.get(1),
+			tags: row.get(2),
+			users: row.get(3),
+			views: row.get(4),
+			storage_bytes: row.get(5),
+			by_rating: Post::count_by_rating(client).await?,
+		})
+	}
+}
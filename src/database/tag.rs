@@ -3,7 +3,8 @@ use bytes::{buf::BufMut, BytesMut};
 use pg::types::{FromSql, IsNull, ToSql, Type};
 use std::borrow::ToOwned;
 
-use crate::database::{pg, DatabaseError};
+use crate::database::{pg, post::page_bounds, DatabaseError};
+use crate::pages::tag::TagSorting;
 
 #[derive(serde::Serialize)]
 pub struct Tag {
@@ -14,7 +15,7 @@ pub struct Tag {
 }
 
 impl Tag {
-	fn deserialise<'a>(row: &'a pg::row::Row) -> Self {
+	fn deserialise(row: &pg::row::Row) -> Self {
 		Tag {
 			id: row.get(0),
 			name: row.get(1),
@@ -33,13 +34,61 @@ impl Tag {
 		let row = client
 			.query_opt(query, &[&name])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		match row {
 			Some(row) => Ok(Some(Tag::deserialise(&row))),
 			None => Ok(None),
 		}
 	}
 
+	/// Look up several tags at once, the results are in the same order as `names`
+	/// with unknown tags left out
+	pub async fn select_tag_names<C: pg::GenericClient>(
+		client: &C,
+		names: &[&str],
+	) -> Result<Vec<Tag>, DatabaseError> {
+		let query =
+			"SELECT t.* FROM unnest($1::text[]) WITH ORDINALITY AS n(name, ord) JOIN tags t \
+		             ON t.name = n.name ORDER BY n.ord";
+		let rows = client
+			.query(query, &[&names])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(rows.iter().map(Tag::deserialise).collect())
+	}
+
+	/// Which of `names` are known tags, used to point out typos in searches
+	pub async fn existing_names<C: pg::GenericClient>(
+		client: &C,
+		names: &[&str],
+	) -> Result<Vec<String>, DatabaseError> {
+		let query = "SELECT name FROM tags WHERE name = ANY($1)";
+		let rows = client
+			.query(query, &[&names])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(rows.iter().map(|r| r.get(0)).collect())
+	}
+
+	/// Page through every tag, used to browse rather than look tags up
+	pub async fn select_page<C: pg::GenericClient>(
+		client: &C,
+		page: u32,
+		limit: u32,
+		sorting: TagSorting,
+	) -> Result<Vec<Tag>, DatabaseError> {
+		let (offset, limit) = page_bounds(page, limit);
+		let query = format!(
+			"SELECT * FROM tags {} OFFSET $1::bigint LIMIT $2::bigint",
+			sorting.to_sql()
+		);
+		let rows = client
+			.query(query.as_str(), &[&offset, &limit])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(rows.iter().map(Tag::deserialise).collect())
+	}
+
 	#[allow(dead_code)]
 	pub async fn insert_empty<C: pg::GenericClient>(
 		client: &C,
@@ -50,33 +99,40 @@ impl Tag {
 		client
 			.execute(query, &[&tag, &ty])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		Ok(())
 	}
 
+	/// Bump the count of every tag in `tags`, creating any that don't exist yet
 	pub async fn update_tag_count<C: pg::GenericClient>(
 		client: &C,
 		tags: &[&str],
 	) -> Result<u64, DatabaseError> {
-		let statement = client
-			.prepare_typed(
-				"INSERT INTO tags (name, count) VALUES ($1, 1) ON CONFLICT (name) DO UPDATE SET \
-				 count = tags.count+1",
-				&[Type::TEXT],
-			)
+		// DISTINCT as a row can't be updated twice by the same statement
+		let query = "INSERT INTO tags (name, count) SELECT DISTINCT unnest($1::text[]), 1 ON \
+		             CONFLICT (name) DO UPDATE SET count = tags.count+1";
+		client
+			.execute(query, &[&tags])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
-		let mut futures = Vec::with_capacity(tags.len());
-		for tag in tags {
-			let stmt = &statement;
-			let fut = async move { client.execute(stmt, &[&tag]).await };
-			futures.push(fut);
-		}
-		let mut modified = 0;
-		for rows in futures::future::join_all(futures).await {
-			modified += rows.map_err(|e| DatabaseError::from(e))?;
-		}
-		Ok(modified)
+			.map_err(DatabaseError::from)
+	}
+
+	/// Recount how many live posts have the tag `name`, returning the old and new
+	/// counts or `None` if there is no such tag
+	pub async fn recount_one<C: pg::GenericClient>(
+		client: &C,
+		ts_config: &str,
+		name: &str,
+	) -> Result<Option<(i64, i64)>, DatabaseError> {
+		let query = "UPDATE tags t SET count = (SELECT count(*) FROM posts WHERE tag_vector @@ \
+		             plainto_tsquery($2::text::regconfig, $1) AND NOT is_deleted) FROM (SELECT \
+		             id, count AS old FROM tags WHERE name = $1) o WHERE t.id = o.id RETURNING \
+		             COALESCE(o.old, 0), t.count";
+		let row = client
+			.query_opt(query, &[&name, &ts_config])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.map(|r| (r.get(0), r.get(1))))
 	}
 
 	pub async fn update_decrease_counts<C: pg::GenericClient>(
@@ -87,7 +143,7 @@ impl Tag {
 		client
 			.execute(query, &[&tags])
 			.await
-			.map_err(|e| DatabaseError::from(e))
+			.map_err(DatabaseError::from)
 	}
 }
 
@@ -100,7 +156,7 @@ impl ToSql for TagVector {
 		&self,
 		_ty: &Type,
 		w: &mut BytesMut,
-	) -> Result<IsNull, Box<(dyn std::error::Error + Sync + Send + 'static)>> {
+	) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send + 'static>> {
 		for tag in &self.0 {
 			w.put_slice(tag.as_bytes())
 		}
@@ -116,7 +172,7 @@ impl<'a> FromSql<'a> for TagVector {
 	fn from_sql(
 		_ty: &Type,
 		raw: &'a [u8],
-	) -> Result<Self, Box<(dyn std::error::Error + Sync + Send + 'static)>> {
+	) -> Result<Self, Box<dyn std::error::Error + Sync + Send + 'static>> {
 		let tags = tsvector_from_sql(raw)
 			.into_iter()
 			.map(|s| s.to_owned())
@@ -128,7 +184,7 @@ impl<'a> FromSql<'a> for TagVector {
 	}
 }
 
-fn tsvector_from_sql<'a>(raw: &'a [u8]) -> Vec<&'a str> {
+fn tsvector_from_sql(raw: &[u8]) -> Vec<&str> {
 	let expected_tags = BigEndian::read_u32(raw);
 	let mut tags = Vec::with_capacity(expected_tags as usize);
 	let mut i = 4;
@@ -154,3 +210,63 @@ fn tsvector_from_sql<'a>(raw: &'a [u8]) -> Vec<&'a str> {
 
 	tags
 }
+
+#[cfg(test)]
+mod tests {
+	// Anything touching postgres is ignored, see `database::fixtures` for what it needs
+	use super::*;
+	use crate::database::fixtures;
+
+	#[actix_web::test]
+	#[ignore]
+	async fn tag_counts_upsert_in_one_go() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+		let existing = format!("countexisting{}", std::process::id());
+		let empty = format!("countempty{}", std::process::id());
+		let new = format!("countnew{}", std::process::id());
+		trans
+			.execute(
+				"INSERT INTO tags (name, count) VALUES ($1, 2)",
+				&[&existing],
+			)
+			.await
+			.unwrap();
+		Tag::insert_empty::<pg::Transaction<'_>>(&trans, &empty, 0)
+			.await
+			.unwrap();
+
+		// Repeats only count once, as they would in a post's tag vector
+		let tags = [
+			existing.as_str(),
+			empty.as_str(),
+			new.as_str(),
+			new.as_str(),
+		];
+		let affected = Tag::update_tag_count::<pg::Transaction<'_>>(&trans, &tags)
+			.await
+			.unwrap();
+		assert_eq!(affected, 3);
+
+		let count = |name: &str| {
+			let (name, trans) = (name.to_owned(), &trans);
+			async move {
+				let count: i64 = trans
+					.query_one("SELECT count FROM tags WHERE name=$1", &[&name])
+					.await
+					.unwrap()
+					.get(0);
+				count
+			}
+		};
+		assert_eq!(count(&existing).await, 3);
+		assert_eq!(count(&empty).await, 1);
+		assert_eq!(count(&new).await, 1);
+
+		let affected = Tag::update_tag_count::<pg::Transaction<'_>>(&trans, &[])
+			.await
+			.unwrap();
+		assert_eq!(affected, 0);
+	}
+}
@@ -1,8 +1,15 @@
 pub use deadpool_postgres::tokio_postgres as pg;
 
-use crate::database::{enums::Perms, DatabaseError};
+use crate::database::{
+	enums::{Perms, Rating},
+	DatabaseError,
+};
 
 use serde::Serialize;
+use std::net::IpAddr;
+
+/// Placeholder user that the content of deleted accounts is handed over to
+pub const DELETED_USER: i32 = -1;
 
 #[derive(Serialize)]
 pub struct User {
@@ -12,6 +19,9 @@ pub struct User {
 	pub pass: String,
 	pub picture: String,
 	pub perms: Perms,
+	/// Ratings shown when a search doesn't ask for any, empty shows everything
+	pub default_rating_filter: Vec<Rating>,
+	pub created_ip: Option<IpAddr>,
 }
 
 impl User {
@@ -23,8 +33,8 @@ impl User {
 		let row = client
 			.query_opt(query, &[&uid])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
-		Ok(row.as_ref().map(|row| Self::deserialise(row)))
+			.map_err(DatabaseError::from)?;
+		Ok(row.as_ref().map(Self::deserialise))
 	}
 
 	pub async fn select_name<C: pg::GenericClient>(
@@ -35,27 +45,130 @@ impl User {
 		let row = client
 			.query_opt(query, &[&name])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
-		Ok(row.as_ref().map(|row| Self::deserialise(row)))
+			.map_err(DatabaseError::from)?;
+		Ok(row.as_ref().map(Self::deserialise))
+	}
+
+	pub async fn select_rating_filter<C: pg::GenericClient>(
+		client: &C,
+		uid: i32,
+	) -> Result<Option<Vec<Rating>>, DatabaseError> {
+		let query = "SELECT default_rating_filter FROM users WHERE id=$1";
+		let row = client
+			.query_opt(query, &[&uid])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.map(|r| r.get(0)))
+	}
+
+	pub async fn update_rating_filter<C: pg::GenericClient>(
+		client: &C,
+		uid: i32,
+		ratings: &[Rating],
+	) -> Result<(), DatabaseError> {
+		let query = "UPDATE users SET default_rating_filter=$1 WHERE id=$2";
+		client
+			.execute(query, &[&ratings, &uid])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(())
+	}
+
+	pub async fn update_perms<C: pg::GenericClient>(
+		client: &C,
+		uid: i32,
+		perms: Perms,
+	) -> Result<bool, DatabaseError> {
+		let query = "UPDATE users SET permissions=$1 WHERE id=$2";
+		let res = client
+			.execute(query, &[&perms, &uid])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(res != 0)
+	}
+
+	/// Count the admins, locking them until the transaction ends so that two admins
+	/// can't both be taken away at once and leave none behind
+	pub async fn count_admins<C: pg::GenericClient>(client: &C) -> Result<i64, DatabaseError> {
+		let query =
+			"SELECT count(*) FROM (SELECT id FROM users WHERE permissions='Admin' FOR UPDATE) a";
+		let row = client
+			.query_one(query, &[])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(row.get(0))
+	}
+
+	/// Hand every post of `uid` over to the deleted user, taking them down as well
+	/// if `soft_delete`. Returns how many posts there were
+	pub async fn anonymize_posts<C: pg::GenericClient>(
+		client: &C,
+		uid: i32,
+		soft_delete: bool,
+	) -> Result<u64, DatabaseError> {
+		// Posts coming down no longer count towards their tags, same as a deletion
+		if soft_delete {
+			let query = "UPDATE tags t SET count = t.count - c.n FROM (SELECT \
+			             unnest(tsvector_to_array(tag_vector)) AS name, count(*) AS n FROM \
+			             posts WHERE poster=$1 AND NOT is_deleted GROUP BY 1) c WHERE t.name = \
+			             c.name";
+			client
+				.execute(query, &[&uid])
+				.await
+				.map_err(DatabaseError::from)?;
+		}
+		let query = "UPDATE posts SET poster=$2, is_deleted=(is_deleted OR $3), \
+		             deleted_at=CASE WHEN $3 AND NOT is_deleted THEN now() ELSE deleted_at END, \
+		             modified_date=now() WHERE poster=$1";
+		client
+			.execute(query, &[&uid, &DELETED_USER, &soft_delete])
+			.await
+			.map_err(DatabaseError::from)
+	}
+
+	/// Remove a user, anything else still pointing at them is handed over to the
+	/// deleted user so the foreign keys hold. The audit log keeps their id. Posts
+	/// should be dealt with first using `anonymize_posts`
+	pub async fn delete<C: pg::GenericClient>(client: &C, uid: i32) -> Result<bool, DatabaseError> {
+		let reassign = [
+			"UPDATE posts SET poster=$2 WHERE poster=$1",
+			"UPDATE post_groups SET poster=$2 WHERE poster=$1",
+			"UPDATE invites SET creator=$2 WHERE creator=$1",
+			"UPDATE invites SET used_by=$2 WHERE used_by=$1",
+		];
+		for query in reassign {
+			client
+				.execute(query, &[&uid, &DELETED_USER])
+				.await
+				.map_err(DatabaseError::from)?;
+		}
+		let res = client
+			.execute("DELETE FROM users WHERE id=$1", &[&uid])
+			.await
+			.map_err(DatabaseError::from)?;
+		Ok(res != 0)
 	}
 
+	/// Check if the name or email are already used, returning which one was
 	pub async fn check_existence<C: pg::GenericClient>(
 		client: &C,
 		name: &str,
 		email: Option<&str>,
-	) -> Result<bool, DatabaseError> {
-		let query = "SELECT id FROM users WHERE name=$1 OR email=$2";
+	) -> Result<Option<&'static str>, DatabaseError> {
+		let query = "SELECT name=$1 FROM users WHERE name=$1 OR email=$2 ORDER BY name=$1 DESC \
+		             LIMIT 1";
 		let row = client
 			.query_opt(query, &[&name, &email])
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		match row {
-			Some(_) => Ok(true),
-			None => Ok(false),
+			Some(row) if row.get(0) => Ok(Some("username")),
+			Some(_) => Ok(Some("email")),
+			None => Ok(None),
 		}
 	}
 
-	fn deserialise<'a>(row: &'a pg::row::Row) -> Self {
+	fn deserialise(row: &pg::row::Row) -> Self {
 		User {
 			id: row.get(0),
 			name: row.get(1),
@@ -63,6 +176,8 @@ impl User {
 			pass: row.get(3),
 			picture: row.get(4),
 			perms: row.get(5),
+			default_rating_filter: row.get(6),
+			created_ip: row.get(7),
 		}
 	}
 }
@@ -72,7 +187,8 @@ pub struct NewUser<'a> {
 	pub name: &'a str,
 	pub email: Option<&'a str>,
 	pub pass: &'a str,
-	pub picture: Option<&'a str>,
+	pub picture: &'a str,
+	pub created_ip: Option<IpAddr>,
 }
 
 impl<'a> NewUser<'a> {
@@ -81,7 +197,8 @@ impl<'a> NewUser<'a> {
 		client: &C,
 	) -> Result<User, DatabaseError> {
 		let query =
-			"INSERT INTO users (name, email, pass, picture) VALUES($1, $2, $3, $4) RETURNING *";
+			"INSERT INTO users (name, email, pass, picture, created_ip) VALUES($1, $2, $3, \
+		             $4, $5) RETURNING *";
 		let row = client
 			.query_one(
 				query,
@@ -89,11 +206,116 @@ impl<'a> NewUser<'a> {
 					&self.name,
 					&self.email,
 					&self.pass,
-					&self.picture.unwrap_or("/s/pfp/default.png"),
+					&self.picture,
+					&self.created_ip,
 				],
 			)
 			.await
-			.map_err(|e| DatabaseError::from(e))?;
+			.map_err(DatabaseError::from)?;
 		Ok(User::deserialise(&row))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	// Anything touching postgres is ignored, see `database::fixtures` for what it needs
+	use super::*;
+	use crate::database::fixtures::{self, insert_user};
+
+	#[actix_web::test]
+	#[ignore]
+	async fn count_admins_waits_for_demotions() {
+		let pool = fixtures::pool();
+		let mut first = pool.get().await.unwrap();
+		let mut second = pool.get().await.unwrap();
+
+		let name = format!("admin-test-{}", std::process::id());
+		let user = insert_user::<pg::Client>(&first, &name).await;
+		User::update_perms::<pg::Client>(&first, user.id, Perms::Admin)
+			.await
+			.unwrap();
+
+		let demote = first.transaction().await.unwrap();
+		let admins = User::count_admins::<pg::Transaction<'_>>(&demote)
+			.await
+			.unwrap();
+		User::update_perms::<pg::Transaction<'_>>(&demote, user.id, Perms::User)
+			.await
+			.unwrap();
+
+		// The second count has to wait for the demotion rather than counting the
+		// admin that is on its way out
+		let check = second.transaction().await.unwrap();
+		let (counted, _) =
+			futures::join!(User::count_admins::<pg::Transaction<'_>>(&check), async {
+				actix_web::rt::time::sleep(std::time::Duration::from_millis(200)).await;
+				demote.commit().await.unwrap();
+			});
+		assert_eq!(counted.unwrap(), admins - 1);
+		check.commit().await.unwrap();
+
+		User::delete::<pg::Client>(&first, user.id).await.unwrap();
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn deleted_user_stays_in_audit_log() {
+		use crate::database::{audit::NewAuditEntry, enums::AuditAction};
+
+		let pool = fixtures::pool();
+		let conn = pool.get().await.unwrap();
+
+		let name = format!("audit-test-{}", std::process::id());
+		let user = insert_user::<pg::Client>(&conn, &name).await;
+		// Audit entries outlive their posts so any id will do
+		let post_id = -i64::from(user.id);
+		NewAuditEntry {
+			post_id,
+			uid: user.id,
+			action: AuditAction::Lock,
+			detail: serde_json::json!({}),
+		}
+		.insert_into::<pg::Client>(&conn)
+		.await
+		.unwrap();
+
+		assert!(User::delete::<pg::Client>(&conn, user.id).await.unwrap());
+		let uid: i32 = conn
+			.query_one("SELECT uid FROM post_audit WHERE post_id=$1", &[&post_id])
+			.await
+			.unwrap()
+			.get(0);
+		assert_eq!(uid, user.id);
+		conn.execute("DELETE FROM post_audit WHERE post_id=$1", &[&post_id])
+			.await
+			.unwrap();
+	}
+
+	#[actix_web::test]
+	#[ignore]
+	async fn check_existence_names_the_field() {
+		let pool = fixtures::pool();
+		let mut conn = pool.get().await.unwrap();
+		let trans = conn.transaction().await.unwrap();
+
+		let name = format!("exists-test-{}", std::process::id());
+		let email = format!("{}@example.com", name);
+		insert_user::<pg::Transaction<'_>>(&trans, &name).await;
+
+		let check = |name: &str, email: Option<&str>| {
+			let (name, email) = (name.to_owned(), email.map(str::to_owned));
+			let trans = &trans;
+			async move {
+				User::check_existence::<pg::Transaction<'_>>(trans, &name, email.as_deref())
+					.await
+					.unwrap()
+			}
+		};
+		assert_eq!(check(&name, None).await, Some("username"));
+		assert_eq!(check("someone-else", Some(&email)).await, Some("email"));
+		// Both taken reports the username
+		assert_eq!(check(&name, Some(&email)).await, Some("username"));
+		assert_eq!(check("someone-else", Some("else@example.com")).await, None);
+		assert_eq!(check("someone-else", None).await, None);
+	}
+}
@@ -28,54 +28,147 @@ macro_rules! try500 {
 #[allow(unused)]
 #[derive(Debug, Display, Error)]
 pub enum APIError {
-	#[display(fmt = r#"{{"error":"internal server error"}}"#)]
+	#[display(fmt = "internal server error")]
 	InternalError,
-	#[display(fmt = r#"{{"error":"bad request"}}"#)]
+	#[display(fmt = "bad request")]
 	BadRequestData,
-	#[display(fmt = r#"{{"error":"timeout"}}"#)]
+	#[display(fmt = "invalid query parameter: {}", _0)]
+	InvalidQuery(#[error(not(source))] String),
+	#[display(fmt = "invalid request body: {}", _0)]
+	InvalidBody(#[error(not(source))] String),
+	#[display(fmt = "timeout")]
 	Timeout,
-	#[display(fmt = r#"{{"error":"unauthorised"}}"#)]
+	#[display(fmt = "service temporarily unavailable, please try again")]
+	Unavailable,
+	#[display(fmt = "unauthorised")]
 	Auth,
-	#[display(fmt = r#"{{"error":"payload to large"}}"#)]
+	#[display(fmt = "forbidden")]
+	Forbidden,
+	#[display(fmt = "payload to large")]
 	PayloadSize,
-	#[display(fmt = r#"{{"error":"unsupported mime type"}}"#)]
+	#[display(fmt = "unsupported mime type")]
 	MimeType,
-	#[display(fmt = r#"{{"error":"too many tags, please reduce amount"}}"#)]
+	#[display(fmt = "post has been removed")]
+	Gone,
+	#[display(fmt = "too many tags, please reduce amount")]
 	TagLimit,
-	#[display(fmt = r#"{{"error":"one or more tags contained invalid characters"}}"#)]
+	#[display(fmt = "too many images in one upload")]
+	ImageLimit,
+	#[display(fmt = "one or more tags contained invalid characters")]
 	BadTags,
-	#[display(fmt = r#"{{"error":"too many items per page, please reduce amount"}}"#)]
+	#[display(fmt = "one or more tags are not allowed")]
+	BannedTag,
+	#[display(fmt = "too many items per page, please reduce amount")]
 	PageSize,
-	#[display(fmt = r#"{{"error":"account details have already been used"}}"#)]
-	UserExists,
-	#[display(fmt = r#"{{"error":"password is too weak"}}"#)]
-	BadPassword,
-	#[display(fmt = r#"{{"error":"password or username where not correct"}}"#)]
+	#[display(fmt = "too many requests, please try again later")]
+	RateLimited,
+	#[display(fmt = "{} has already been used", _0)]
+	UserExists(#[error(not(source))] &'static str),
+	#[display(fmt = "password is too weak, {}", _0)]
+	BadPassword(#[error(not(source))] &'static str),
+	#[display(fmt = "password or username where not correct")]
 	BadCredentials,
+	#[display(fmt = "registration is closed, a valid invite code is required")]
+	RegistrationClosed,
+	#[display(fmt = "method not allowed on this resource")]
+	MethodNotAllowed,
+	#[display(fmt = "post is locked")]
+	Locked,
+	#[display(fmt = "not found")]
+	NotFound,
+	#[display(fmt = "a request with this idempotency key is still in progress")]
+	InProgress,
+}
+
+impl APIError {
+	/// Stable machine readable code, clients should branch on this rather than the message
+	pub fn code(&self) -> &'static str {
+		match *self {
+			Self::InternalError => "INTERNAL_ERROR",
+			Self::BadRequestData => "BAD_REQUEST",
+			Self::InvalidQuery(_) => "INVALID_QUERY",
+			Self::InvalidBody(_) => "INVALID_BODY",
+			Self::Timeout => "TIMEOUT",
+			Self::Unavailable => "UNAVAILABLE",
+			Self::Auth => "UNAUTHORISED",
+			Self::Forbidden => "FORBIDDEN",
+			Self::PayloadSize => "PAYLOAD_SIZE",
+			Self::MimeType => "MIME_TYPE",
+			Self::Gone => "GONE",
+			Self::TagLimit => "TAG_LIMIT",
+			Self::ImageLimit => "IMAGE_LIMIT",
+			Self::BadTags => "BAD_TAGS",
+			Self::BannedTag => "BANNED_TAG",
+			Self::PageSize => "PAGE_SIZE",
+			Self::RateLimited => "RATE_LIMITED",
+			Self::UserExists(_) => "USER_EXISTS",
+			Self::BadPassword(_) => "BAD_PASSWORD",
+			Self::BadCredentials => "BAD_CREDENTIALS",
+			Self::RegistrationClosed => "REGISTRATION_CLOSED",
+			Self::MethodNotAllowed => "METHOD_NOT_ALLOWED",
+			Self::Locked => "LOCKED",
+			Self::NotFound => "NOT_FOUND",
+			Self::InProgress => "IN_PROGRESS",
+		}
+	}
+
+	/// The JSON body sent to clients, `Display` is kept as a plain message for logging
+	pub fn json_body(&self) -> String {
+		serde_json::json!({ "error": self.to_string(), "code": self.code() }).to_string()
+	}
 }
 
 impl error::ResponseError for APIError {
 	fn error_response(&self) -> HttpResponse {
 		HttpResponseBuilder::new(self.status_code())
 			.insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-			.body(self.to_string())
-			.into()
+			.body(self.json_body())
 	}
 
 	fn status_code(&self) -> StatusCode {
 		match *self {
 			Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
 			Self::BadRequestData => StatusCode::BAD_REQUEST,
+			Self::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+			Self::InvalidBody(_) => StatusCode::BAD_REQUEST,
 			Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+			Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
 			Self::Auth => StatusCode::UNAUTHORIZED,
+			Self::Forbidden => StatusCode::FORBIDDEN,
 			Self::PayloadSize => StatusCode::PAYLOAD_TOO_LARGE,
 			Self::MimeType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+			Self::Gone => StatusCode::GONE,
 			Self::TagLimit => StatusCode::BAD_REQUEST,
+			Self::ImageLimit => StatusCode::BAD_REQUEST,
 			Self::BadTags => StatusCode::BAD_REQUEST,
+			Self::BannedTag => StatusCode::BAD_REQUEST,
 			Self::PageSize => StatusCode::BAD_REQUEST,
-			Self::UserExists => StatusCode::BAD_REQUEST,
-			Self::BadPassword => StatusCode::BAD_REQUEST,
+			Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+			Self::UserExists(_) => StatusCode::CONFLICT,
+			Self::BadPassword(_) => StatusCode::BAD_REQUEST,
 			Self::BadCredentials => StatusCode::BAD_REQUEST,
+			Self::RegistrationClosed => StatusCode::FORBIDDEN,
+			Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+			Self::Locked => StatusCode::LOCKED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::InProgress => StatusCode::CONFLICT,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use error::ResponseError;
+
+	#[test]
+	fn user_exists_conflicts() {
+		for field in ["username", "email"] {
+			let err = APIError::UserExists(field);
+			assert_eq!(err.status_code(), StatusCode::CONFLICT);
+			let body: serde_json::Value = serde_json::from_str(&err.json_body()).unwrap();
+			assert_eq!(body["code"], "USER_EXISTS");
+			assert_eq!(body["error"], format!("{} has already been used", field));
 		}
 	}
 }
@@ -0,0 +1,44 @@
+//! How long the text fields clients send us may be, kept in one place so the
+//! handlers enforcing them don't drift apart. Lengths are in characters unless
+//! said otherwise
+
+pub const USERNAME_MIN: usize = 4;
+/// Has to fit the `varchar` of `users.name`
+pub const USERNAME_MAX: usize = 24;
+/// Longest an address can be according to the SMTP RFCs
+pub const EMAIL_MAX: usize = 254;
+pub const INVITE_MAX: usize = 64;
+pub const DESCRIPTION_MAX: usize = 4096;
+pub const TAG_MAX: usize = 64;
+/// Session tokens, in bytes. Ours are much shorter, this is only to keep junk out
+/// of redis
+pub const TOKEN_MAX: usize = 512;
+
+/// Whether `s` is between `min` and `max` characters long, inclusive
+pub fn within(s: &str, min: usize, max: usize) -> bool {
+	(min..=max).contains(&s.chars().count())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn username_fits_schema() {
+		let schema = include_str!("../res/sql/create_users.sql");
+		assert!(schema.contains(&format!(
+			"\"name\"        varchar({}) NOT NULL",
+			USERNAME_MAX
+		)));
+	}
+
+	#[test]
+	fn within_counts_characters() {
+		assert!(within("abcd", 4, 4));
+		assert!(!within("abc", 4, 8));
+		assert!(!within("abcdefghi", 4, 8));
+		// Four characters but eight bytes
+		assert!(within("ねこねこ", 4, 4));
+		assert!(within("", 0, 0));
+	}
+}
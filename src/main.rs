@@ -1,4 +1,4 @@
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Write};
 
 use actix_cors::Cors;
 use actix_web::{middleware, web::Data, App, HttpServer};
@@ -7,20 +7,21 @@ use log::LevelFilter;
 mod auth;
 mod database;
 mod error;
+mod limits;
+mod origin;
 mod pages;
+mod proxy;
 mod settings;
 
 use settings::{Action, RunSettings, Settings};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-	simple_logger::SimpleLogger::new()
-		.with_level(LevelFilter::Info)
-		.init()
-		.unwrap();
 	dotenv::dotenv().ok();
+	init_logger();
 
 	let settings = Settings::parse();
+	settings.validate();
 
 	// Decide what we need to do
 	match settings.action {
@@ -29,6 +30,11 @@ async fn main() -> std::io::Result<()> {
 			println!("Installing database schema...");
 			database::install_schema(settings).await
 		}
+		Action::DropTables if settings.dry_run => {
+			println!("Dry run, nothing will be dropped...");
+			database::drop_tables_dry_run(settings).await;
+			println!("all user sessions would be cleared");
+		}
 		Action::DropTables => {
 			println!(
 				"CAUTION: Are you sure you want to drop all the tables? This will delete any data \
@@ -41,7 +47,8 @@ async fn main() -> std::io::Result<()> {
 			let answer = answer[0] as char;
 			if answer == 'Y' || answer == 'y' {
 				println!("Dropping tables...");
-				auth::AuthDbCreator::clear_sessions(&settings.redis_uri).await;
+				auth::AuthDbCreator::clear_sessions(&settings.redis_uri, &settings.redis_prefix)
+					.await;
 				database::drop_tables(settings).await;
 			} else {
 				println!("Cancelled, tables not dropped");
@@ -49,7 +56,27 @@ async fn main() -> std::io::Result<()> {
 		}
 		Action::ClearSessions => {
 			println!("Clearing User Sessions...");
-			auth::AuthDbCreator::clear_sessions(&settings.redis_uri).await;
+			auth::AuthDbCreator::clear_sessions(&settings.redis_uri, &settings.redis_prefix).await;
+		}
+		Action::CreateAdmin => {
+			// Details can come from the environment so this can run unattended
+			let ask = |var: &str, prompt: &str| match std::env::var(var) {
+				Ok(v) => v,
+				Err(_) => {
+					print!("{}: ", prompt);
+					std::io::stdout().flush().ok();
+					let mut line = String::new();
+					std::io::stdin()
+						.read_line(&mut line)
+						.expect("failed to read from stdin");
+					line.trim_end_matches(&['\r', '\n'][..]).to_owned()
+				}
+			};
+			let name = ask("WATAME_ADMIN_NAME", "Username");
+			let email = ask("WATAME_ADMIN_EMAIL", "Email (optional)");
+			let pass = ask("WATAME_ADMIN_PASS", "Password");
+			println!("Creating admin...");
+			database::create_admin(settings, &name, &email, &pass).await;
 		}
 		Action::CreateFolders => {
 			let image_dirs = |root| {
@@ -69,17 +96,61 @@ async fn main() -> std::io::Result<()> {
 			image_dirs(format!("{}/tmb", settings.storage_root));
 			image_dirs(format!("{}/pfp", settings.storage_root));
 			println!("Copying default images...");
-			let image = include_bytes!("../res/default_pfp.png");
-			std::fs::write(format!("{}/pfp/default.png", settings.storage_root), image).ok();
+			// Don't clobber a default picture that has been customised
+			let default_pfp = format!("{}/pfp/default.png", settings.storage_root);
+			if !std::path::Path::new(&default_pfp).exists() {
+				let image = include_bytes!("../res/default_pfp.png");
+				std::fs::write(default_pfp, image).ok();
+			}
 		}
 	}
 	Ok(())
 }
 
+/// Set up logging from `WATAME_LOG_LEVEL` (or `RUST_LOG`), this takes a comma
+/// separated list of either a default level or `module=level` pairs, for
+/// example `warn,watame=debug`
+fn init_logger() {
+	let mut logger = simple_logger::SimpleLogger::new().with_level(LevelFilter::Info);
+	let mut invalid = Vec::new();
+
+	let spec = std::env::var("WATAME_LOG_LEVEL")
+		.or_else(|_| std::env::var("RUST_LOG"))
+		.unwrap_or_default();
+	for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+		logger = match directive.split_once('=') {
+			Some((module, level)) => match level.parse() {
+				Ok(level) => logger.with_module_level(module, level),
+				Err(_) => {
+					invalid.push(directive);
+					logger
+				}
+			},
+			None => match directive.parse() {
+				Ok(level) => logger.with_level(level),
+				Err(_) => {
+					invalid.push(directive);
+					logger
+				}
+			},
+		};
+	}
+
+	logger.init().unwrap();
+	for directive in invalid {
+		log::warn!("invalid log level directive: '{}'", directive);
+	}
+}
+
 async fn run_server(mut settings: Settings) -> std::io::Result<()> {
 	// Connect to the database and create a connection pool
 	let db_pool = database::establish_pool(&mut settings);
-	let auth_db = auth::AuthDbCreator::new(&settings.redis_uri).await;
+	let auth_db = auth::AuthDbCreator::new(
+		&settings.redis_uri,
+		settings.redis_pool_size,
+		&settings.redis_prefix,
+	)
+	.await;
 	// Settings that handlers can access
 	let run_settings = RunSettings::from(&settings);
 	// Create a listener so we can log what port we are operating on
@@ -90,52 +161,69 @@ async fn run_server(mut settings: Settings) -> std::io::Result<()> {
 	);
 
 	#[cfg(feature = "host-storage")]
-	let storage_root = std::mem::take(&mut settings.storage_root);
+	let storage_root = settings
+		.serve_storage
+		.then(|| std::mem::take(&mut settings.storage_root));
+	#[cfg(not(feature = "host-storage"))]
+	if settings.serve_storage {
+		log::warn!("WATAME_SERVE_STORAGE is set but this build has no host-storage feature");
+	}
+	// State changing requests from browsers must come from one of these origins
+	let enforce_origin = settings.enforce_origin;
+	let allowed_origins = std::mem::take(&mut settings.allowed_origins);
+	let mut trusted_origins = allowed_origins.clone();
+	let trusted_proxies = settings.trusted_proxies.clone();
+	trusted_origins.push(settings.public_url.clone());
 
 	let server = HttpServer::new(move || {
-		use actix_web::web::{delete, get, post, resource, QueryConfig};
+		use actix_web::web::{JsonConfig, PayloadConfig, QueryConfig};
 		use pages::*;
 
-		let cors = Cors::default()
-			.allow_any_origin()
-			.allow_any_method()
-			.max_age(3600);
-		let query_config = QueryConfig::default().error_handler(|a, b| {
-			log::error!("{:?} {:?}", a, b);
-			error::APIError::BadRequestData.into()
-		});
+		let cors = Cors::default().allow_any_method().max_age(3600);
+		let cors = if allowed_origins.is_empty() {
+			cors.allow_any_origin()
+		} else {
+			allowed_origins
+				.iter()
+				.fold(cors, |cors, origin| cors.allowed_origin(origin))
+		};
+		// The access log should show the same client address handlers see
+		let trusted_proxies = trusted_proxies.clone();
+		let query_config = QueryConfig::default().error_handler(query_error);
+		let json_config = JsonConfig::default().error_handler(json_error);
 
 		// Wrap up any data or middleware that the actix web server will use
 		let app = App::new()
 			.wrap(cors)
-			.wrap(middleware::Logger::new("\t%a\t\"%r\"\t%s\t%b\t%Dms"))
-			.wrap(auth::AuthMiddlewareFactory::new(auth::AuthDb::new(
-				auth_db.clone(),
-			)))
+			.wrap(
+				middleware::Logger::new("\t%{client}xi\t\"%r\"\t%s\t%b\t%Dms")
+					.custom_request_replace("client", move |req| {
+						proxy::resolve_client_ip(req.peer_addr(), req.headers(), &trusted_proxies)
+							.map_or_else(|| "-".to_owned(), |ip| ip.to_string())
+					}),
+			)
+			.wrap(auth::AuthMiddlewareFactory::new(
+				auth::AuthDb::new(auth_db.clone()),
+				run_settings.cookie_auth,
+			))
+			.wrap(middleware::Condition::new(
+				enforce_origin,
+				origin::OriginMiddlewareFactory::new(trusted_origins.clone()),
+			))
 			.app_data(Data::new(db_pool.clone()))
 			.app_data(Data::new(auth::AuthDb::new(auth_db.clone())))
 			.app_data(Data::new(run_settings.clone()))
-			.app_data(query_config);
+			.app_data(query_config)
+			.app_data(json_config)
+			.app_data(PayloadConfig::new(run_settings.max_payload * 1024));
 
 		// Set our servers routes
-		let app = app
-			.service(
-				resource("/post")
-					.route(delete().to(post::delete_post))
-					.route(get().to(post::get_post))
-					.route(post().to(post::post_upload)),
-			)
-			.service(resource("/user").route(get().to(user::get_self)))
-			.service(resource("/register").route(post().to(user::post_register)))
-			.service(resource("/login").route(post().to(user::post_login)))
-			.service(resource("/logout").route(delete().to(user::delete_logout)))
-			.service(resource("/loggedin").route(get().to(user::get_logged_in)))
-			.service(resource("/purge").route(delete().to(post::delete_purge_posts)))
-			.service(resource("/tag").route(get().to(tag::get_info)))
-			.service(resource("/search").route(get().to(search::get_search)))
-			.service(resource("/random").route(get().to(search::get_random_post)));
+		let app = app.configure(routes);
 		#[cfg(feature = "host-storage")]
-		let app = app.service(actix_files::Files::new("/s", &storage_root));
+		let app = match &storage_root {
+			Some(root) => app.service(storage(root)),
+			None => app,
+		};
 
 		app
 	});
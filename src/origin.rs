@@ -0,0 +1,101 @@
+use crate::error::APIError;
+
+use actix_web::{
+	dev::{self, Service, ServiceRequest, ServiceResponse},
+	http::{header, Method},
+	Error,
+};
+use futures::future::{ready, FutureExt, LocalBoxFuture, Ready};
+
+use std::rc::Rc;
+
+/// Get the `scheme://host[:port]` part of a URL such as a Referer
+fn url_origin(url: &str) -> &str {
+	match url.find("://") {
+		Some(i) => match url[i + 3..].find('/') {
+			Some(j) => &url[..i + 3 + j],
+			None => url,
+		},
+		None => url,
+	}
+}
+
+pub struct OriginMiddleware<S> {
+	allowed: Rc<Vec<String>>,
+	service: Rc<S>,
+}
+
+impl<S> OriginMiddleware<S> {
+	/// Requests that don't change any state, or come from something that isn't a
+	/// browser and so doesn't send an Origin or Referer, are always allowed
+	fn is_allowed(&self, req: &ServiceRequest) -> bool {
+		if matches!(
+			*req.method(),
+			Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+		) {
+			return true;
+		}
+		let headers = req.headers();
+		let origin = match headers
+			.get(header::ORIGIN)
+			.or_else(|| headers.get(header::REFERER))
+		{
+			Some(v) => match v.to_str() {
+				Ok(v) => url_origin(v),
+				Err(_) => return false,
+			},
+			None => return true,
+		};
+		self.allowed.iter().any(|a| a == origin)
+	}
+}
+
+impl<S, B> Service<ServiceRequest> for OriginMiddleware<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	actix_service::forward_ready!(service);
+
+	fn call(&self, req: ServiceRequest) -> Self::Future {
+		if !self.is_allowed(&req) {
+			return ready(Err(APIError::Forbidden.into())).boxed_local();
+		}
+		self.service.call(req).boxed_local()
+	}
+}
+
+pub struct OriginMiddlewareFactory {
+	allowed: Rc<Vec<String>>,
+}
+
+impl OriginMiddlewareFactory {
+	pub fn new(allowed: Vec<String>) -> Self {
+		Self {
+			allowed: Rc::new(allowed),
+		}
+	}
+}
+
+impl<S, B> dev::Transform<S, ServiceRequest> for OriginMiddlewareFactory
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Transform = OriginMiddleware<S>;
+	type InitError = ();
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ready(Ok(OriginMiddleware {
+			allowed: self.allowed.clone(),
+			service: Rc::new(service),
+		}))
+	}
+}
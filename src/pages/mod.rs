@@ -1,4 +1,430 @@
 pub mod post;
 pub mod search;
+pub mod stats;
 pub mod tag;
 pub mod user;
+pub mod version;
+
+use crate::auth::MaybeAuthenticated;
+use crate::database::enums::Perms;
+use crate::error::APIError;
+
+use actix_web::{
+	error::{JsonPayloadError, QueryPayloadError},
+	http::header::{self, HeaderValue},
+	web, HttpRequest, HttpResponseBuilder, ResponseError, Route,
+};
+use serde::de::{
+	value::{self, StrDeserializer},
+	Deserialize, DeserializeOwned, Deserializer, Error as _, IntoDeserializer,
+};
+use std::fmt::Display;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Fallback for a resource hit with a method it has no route for, `allow` lists
+/// the methods that it does have
+pub fn method_not_allowed(allow: &'static str) -> Route {
+	web::to(move || async move {
+		let mut res = APIError::MethodNotAllowed.error_response();
+		res.headers_mut()
+			.insert(header::ALLOW, HeaderValue::from_static(allow));
+		res
+	})
+}
+
+/// Every route the API serves, resources answer methods they have no route for with
+/// a 405 listing the ones they do. HEAD routes share the GET handler as actix strips
+/// the body while keeping the status and headers intact
+pub fn routes(cfg: &mut web::ServiceConfig) {
+	use web::{delete, get, head, patch, post, resource};
+
+	cfg.service(
+		resource("/post")
+			.route(delete().to(post::delete_post))
+			.route(get().to(post::get_post))
+			.route(head().to(post::get_post))
+			.route(patch().to(post::patch_post))
+			.route(post().to(post::post_upload))
+			.default_service(method_not_allowed("GET, HEAD, POST, PATCH, DELETE")),
+	)
+	.service(
+		resource("/image")
+			.route(get().to(post::get_image))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/post/neighbors")
+			.route(get().to(post::get_neighbors))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/post/thumb")
+			.route(get().to(post::get_thumb))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/post/tags")
+			.route(get().to(post::get_post_tags))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/post/lock")
+			.route(post().to(post::post_lock))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/post/restore")
+			.route(post().to(post::post_restore))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/post/history")
+			.route(get().to(post::get_history))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/user")
+			.route(get().to(user::get_self))
+			.route(patch().to(user::patch_self))
+			.route(delete().to(user::delete_self))
+			.default_service(method_not_allowed("GET, PATCH, DELETE")),
+	)
+	.service(
+		resource("/user/perms")
+			.route(post().to(user::post_perms))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/invite")
+			.route(post().to(user::post_invite))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/register")
+			.route(post().to(user::post_register))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/login")
+			.route(post().to(user::post_login))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/logout")
+			.route(delete().to(user::delete_logout))
+			.default_service(method_not_allowed("DELETE")),
+	)
+	.service(
+		resource("/loggedin")
+			.route(get().to(user::get_logged_in))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/posts/changes")
+			.route(get().to(search::get_changes))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/purge")
+			.route(delete().to(post::delete_purge_posts))
+			.default_service(method_not_allowed("DELETE")),
+	)
+	.service(
+		resource("/stats")
+			.route(get().to(stats::get_stats))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/tag")
+			.route(get().to(tag::get_info))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/tag/recount")
+			.route(post().to(tag::post_recount))
+			.default_service(method_not_allowed("POST")),
+	)
+	.service(
+		resource("/version")
+			.route(get().to(version::get_version))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/tags")
+			.route(get().to(tag::get_infos))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/tags/list")
+			.route(get().to(tag::get_list))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/search")
+			.route(get().to(search::get_search))
+			.default_service(method_not_allowed("GET")),
+	)
+	.service(
+		resource("/random")
+			.route(get().to(search::get_random_post))
+			.route(head().to(search::get_random_post))
+			.default_service(method_not_allowed("GET, HEAD")),
+	);
+}
+
+/// Serve the stored media under `/s` from `root`, missing media gets the same JSON
+/// error as the rest of the API
+#[cfg(feature = "host-storage")]
+pub fn storage(root: &str) -> actix_files::Files {
+	actix_files::Files::new("/s", root)
+		.default_handler(web::to(|| async { APIError::NotFound.error_response() }))
+}
+
+/// Turn a query string that didn't deserialise into an error naming what was wrong
+pub fn query_error(err: QueryPayloadError, req: &HttpRequest) -> actix_web::Error {
+	log::error!("{:?} {:?}", err, req);
+	// Pass along what serde had to say so clients know which parameter was wrong
+	let detail = match err {
+		QueryPayloadError::Deserialize(e) => e.to_string(),
+		_ => "malformed query string".to_owned(),
+	};
+	APIError::InvalidQuery(detail).into()
+}
+
+/// Same as `query_error` for JSON bodies, serde names the missing or mistyped field
+pub fn json_error(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+	log::error!("{:?} {:?}", err, req);
+	let detail = match err {
+		JsonPayloadError::Deserialize(e) => e.to_string(),
+		JsonPayloadError::ContentType => "expected application/json".to_owned(),
+		_ => "malformed json body".to_owned(),
+	};
+	APIError::InvalidBody(detail).into()
+}
+
+/// Deserialise an enum query parameter, the error names `field` as well as the bad
+/// value so clients can tell which parameter to fix
+pub fn enum_param<'de, D, T>(d: D, field: &str) -> Result<T, D::Error>
+where
+	D: Deserializer<'de>,
+	T: DeserializeOwned,
+{
+	let raw = String::deserialize(d)?;
+	let de: StrDeserializer<'_, value::Error> = raw.trim().into_deserializer();
+	T::deserialize(de).map_err(|e| D::Error::custom(format_args!("{}: {}", field, e)))
+}
+
+/// Deserialise a numeric query parameter, like [`enum_param`] the error names `field`
+/// since the parse error on its own doesn't say which parameter was bad
+pub fn number_param<'de, D, T>(d: D, field: &str) -> Result<T, D::Error>
+where
+	D: Deserializer<'de>,
+	T: FromStr,
+	T::Err: Display,
+{
+	let raw = String::deserialize(d)?;
+	raw.trim()
+		.parse()
+		.map_err(|e| D::Error::custom(format_args!("{}: {}", field, e)))
+}
+
+/// Whether the requester may see moderation details such as upload addresses
+pub fn is_moderator(auth: &MaybeAuthenticated) -> bool {
+	matches!(
+		auth.as_ref().map(|a| a.perms),
+		Some(Perms::Moderator | Perms::Admin)
+	)
+}
+
+/// Stop a response from being kept by any caches, for anything carrying tokens or
+/// personal details
+pub fn no_store(mut builder: HttpResponseBuilder) -> HttpResponseBuilder {
+	builder
+		.insert_header((header::CACHE_CONTROL, "no-store"))
+		.insert_header((header::PRAGMA, "no-cache"));
+	builder
+}
+
+/// Give up on `fut` with a timeout error if it takes longer than `secs` seconds, so
+/// a stuck database can't hold onto workers forever. A `secs` of 0 waits forever
+pub async fn with_timeout<F: Future>(secs: u64, fut: F) -> Result<F::Output, APIError> {
+	if secs == 0 {
+		return Ok(fut.await);
+	}
+	actix_web::rt::time::timeout(Duration::from_secs(secs), fut)
+		.await
+		.map_err(|_| APIError::Timeout)
+}
+
+/// Whether the client explicitly asked for HTML, a bare `*/*` still gets JSON so
+/// API clients keep working
+pub fn wants_html(req: &HttpRequest) -> bool {
+	req.headers()
+		.get(header::ACCEPT)
+		.and_then(|v| v.to_str().ok())
+		.map(|v| {
+			v.split(',')
+				.any(|t| t.split(';').next().unwrap_or("").trim() == "text/html")
+		})
+		.unwrap_or(false)
+}
+
+/// Escape text so it can be placed inside HTML
+pub fn escape_html(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&#39;"),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actix_web::rt::time::sleep;
+	use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+	/// Stands in for a database that has stopped answering
+	async fn slow_query() -> Result<i64, APIError> {
+		sleep(Duration::from_secs(30)).await;
+		Ok(1)
+	}
+
+	#[actix_web::test]
+	async fn slow_query_times_out() {
+		let app = test::init_service(App::new().route(
+			"/post",
+			web::get().to(|| async {
+				let id = with_timeout(1, slow_query()).await??;
+				Ok::<_, APIError>(HttpResponse::Ok().body(id.to_string()))
+			}),
+		))
+		.await;
+		let req = test::TestRequest::get().uri("/post").to_request();
+		let started = std::time::Instant::now();
+		let res = test::call_service(&app, req).await;
+		assert!(started.elapsed() < Duration::from_secs(5));
+		assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+		let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+		assert_eq!(body["code"], "TIMEOUT");
+	}
+
+	#[actix_web::test]
+	async fn fast_query_finishes() {
+		let res = with_timeout(1, async { 7 }).await;
+		assert!(matches!(res, Ok(7)));
+		// Zero turns the timeout off rather than failing straight away
+		let res = with_timeout(0, async {
+			sleep(Duration::from_millis(10)).await;
+			7
+		})
+		.await;
+		assert!(matches!(res, Ok(7)));
+	}
+
+	#[actix_web::test]
+	async fn unsupported_methods_list_allowed() {
+		use actix_web::http::Method;
+
+		let app = test::init_service(App::new().configure(routes)).await;
+		for (path, allow) in [
+			("/post", "GET, HEAD, POST, PATCH, DELETE"),
+			("/image", "GET"),
+			("/post/neighbors", "GET"),
+			("/post/thumb", "GET"),
+			("/post/tags", "GET"),
+			("/post/lock", "POST"),
+			("/post/restore", "POST"),
+			("/post/history", "GET"),
+			("/user", "GET, PATCH, DELETE"),
+			("/user/perms", "POST"),
+			("/invite", "POST"),
+			("/register", "POST"),
+			("/login", "POST"),
+			("/logout", "DELETE"),
+			("/loggedin", "GET"),
+			("/posts/changes", "GET"),
+			("/purge", "DELETE"),
+			("/stats", "GET"),
+			("/tag", "GET"),
+			("/tag/recount", "POST"),
+			("/version", "GET"),
+			("/tags", "GET"),
+			("/tags/list", "GET"),
+			("/search", "GET"),
+			("/random", "GET, HEAD"),
+		] {
+			let allowed: Vec<&str> = allow.split(", ").collect();
+			for method in [
+				Method::GET,
+				Method::POST,
+				Method::PUT,
+				Method::PATCH,
+				Method::DELETE,
+			] {
+				if allowed.contains(&method.as_str()) {
+					continue;
+				}
+				let req = test::TestRequest::default()
+					.method(method.clone())
+					.uri(path)
+					.to_request();
+				let res = test::call_service(&app, req).await;
+				assert_eq!(
+					res.status(),
+					StatusCode::METHOD_NOT_ALLOWED,
+					"{} {}",
+					method,
+					path
+				);
+				assert_eq!(res.headers().get(header::ALLOW).unwrap(), allow);
+				let body: serde_json::Value =
+					serde_json::from_slice(&test::read_body(res).await).unwrap();
+				assert_eq!(body["code"], "METHOD_NOT_ALLOWED");
+			}
+		}
+	}
+
+	#[actix_web::test]
+	async fn unknown_path_not_found() {
+		let app = test::init_service(App::new().configure(routes)).await;
+		let req = test::TestRequest::get().uri("/nothing").to_request();
+		let res = test::call_service(&app, req).await;
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[cfg(feature = "host-storage")]
+	#[actix_web::test]
+	async fn missing_media_not_found() {
+		let root = std::env::temp_dir().join(format!("watame-storage-{}", std::process::id()));
+		std::fs::create_dir_all(root.join("img/00")).unwrap();
+		std::fs::write(root.join("img/00/1-a.png"), b"png").unwrap();
+		let app = test::init_service(App::new().service(storage(root.to_str().unwrap()))).await;
+
+		let req = test::TestRequest::get()
+			.uri("/s/img/00/1-a.png")
+			.to_request();
+		let res = test::call_service(&app, req).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(test::read_body(res).await, "png");
+
+		for path in ["/s/img/00/2-missing.png", "/s/tmb/ff/3.jpg"] {
+			let req = test::TestRequest::get().uri(path).to_request();
+			let res = test::call_service(&app, req).await;
+			assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+			let body: serde_json::Value =
+				serde_json::from_slice(&test::read_body(res).await).unwrap();
+			assert_eq!(body["code"], "NOT_FOUND", "{}", path);
+		}
+		std::fs::remove_dir_all(root).unwrap();
+	}
+}
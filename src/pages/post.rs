@@ -2,76 +2,368 @@ use async_std::fs;
 use std::io::Cursor;
 use std::path::PathBuf;
 
-use crate::auth::Authenticated;
+use crate::auth::{Authenticated, MaybeAuthenticated, Reservation};
 use crate::database::{
-	enums::{Perms, Rating},
+	audit::{AuditEntry, NewAuditEntry},
+	enums::{AuditAction, ImageExtension, Perms, Rating},
 	pg,
-	post::{NewPost, Post},
+	post::{
+		insert_idempotent_upload, insert_post_group, select_idempotent_upload, NewPost, Post,
+		PostFull, SearchFilter,
+	},
 	tag::Tag,
 	Pool as DbPool,
 };
+use crate::pages::search::{
+	default_sort, default_tags, deserialize_ratings, deserialize_sort, parse_tags, resolve_ratings,
+	PostSorting,
+};
+use crate::pages::{escape_html, is_moderator, wants_html, with_timeout};
+use crate::proxy::client_ip;
 use crate::settings::RunSettings;
-use crate::{error::APIError, try500};
+use crate::{error::APIError, limits, try500};
 
-use actix_multipart::Multipart;
-use actix_web::{http::header, web, HttpResponse};
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::{error::PayloadError, http::header, web, HttpRequest, HttpResponse};
 use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+// How long an upload can be retried with the same Idempotency-Key, in seconds
+const IDEMPOTENCY_TTL: u64 = 60 * 60 * 24;
+// How long a key stays claimed by an upload still in progress, should we go away
+// mid upload it frees up by itself
+const IDEMPOTENCY_PENDING_TTL: u64 = 60 * 10;
+// Deleted posts a purge handles at once
+const PURGE_BATCH: u32 = 500;
+// Widest or tallest image WebP can hold, in pixels
+const WEBP_MAX_DIMENSION: u32 = 16383;
 
+/// The subfolder a post's files live in, this is always one of 256 folders. Files
+/// are named by id so wrapping around is fine
 fn image_path(id: i64) -> String {
-	format!("{:02x}", id >> 16)
+	format!("{:02x}", (id >> 16) & 0xff)
 }
 
 fn format_paths(root: &str, subfolder: &str, id: i64, filename: &str) -> (PathBuf, PathBuf) {
 	// File path for the primary image
-	let img_path = [root, "img", &subfolder, &format!("{}-{}", id, filename)]
+	let img_path = [root, "img", subfolder, &format!("{}-{}", id, filename)]
 		.iter()
 		.collect();
 	// File path for the smaller thumbnail
-	let tmb_path = [root, "tmb", &subfolder, &format!("{}.jpg", id)]
+	let tmb_path = [root, "tmb", subfolder, &format!("{}.jpg", id)]
 		.iter()
 		.collect();
 
 	(img_path, tmb_path)
 }
 
+/// Remove a file, one that is already gone counts as removed so that cleaning up
+/// after a post can safely be tried again
+async fn remove_if_exists(path: &std::path::Path) -> std::io::Result<()> {
+	match fs::remove_file(path).await {
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+			log::debug!("{} was already removed", path.display());
+			Ok(())
+		}
+		res => res,
+	}
+}
+
+/// Remove every one of `paths` with at most `concurrency` removals going at once,
+/// returning the ones that couldn't be removed
+async fn remove_files(paths: impl Iterator<Item = PathBuf>, concurrency: usize) -> Vec<PathBuf> {
+	let mut removals = futures::stream::iter(paths.map(|path| async move {
+		let res = remove_if_exists(&path).await;
+		(path, res)
+	}))
+	.buffer_unordered(concurrency);
+	let mut failed = Vec::new();
+	while let Some((path, res)) = removals.next().await {
+		if let Err(e) = res {
+			log::error!("({}): Failed to remove {}", e, path.display());
+			failed.push(path);
+		}
+	}
+	failed
+}
+
+/// Remove both the image and thumbnail of a post from disk
+async fn remove_post_files(root: &str, post: &PostFull) -> Result<(), APIError> {
+	let (img_path, tmb_path) = format_paths(root, &post.path, post.id, &post.filename);
+	let (img, tmb) = futures::join!(remove_if_exists(&img_path), remove_if_exists(&tmb_path));
+	try500!(img, "image delete {}", img_path.display());
+	try500!(tmb, "thumb delete {}", tmb_path.display());
+	Ok(())
+}
+
+/// Everything that can't appear as is inside a single path segment of a URL
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+	.add(b' ')
+	.add(b'"')
+	.add(b'#')
+	.add(b'%')
+	.add(b'/')
+	.add(b'<')
+	.add(b'>')
+	.add(b'?')
+	.add(b'\\')
+	.add(b'^')
+	.add(b'`')
+	.add(b'{')
+	.add(b'|')
+	.add(b'}');
+
+/// Public URL of the full image, this follows the storage layout so don't hand
+/// it out as a permanent link
+fn image_url(public_url: &str, post: &PostFull) -> String {
+	let name = format!("{}-{}", post.id, post.filename);
+	format!(
+		"{}/s/img/{}/{}",
+		public_url,
+		post.path,
+		utf8_percent_encode(&name, PATH_SEGMENT)
+	)
+}
+
+/// Render a post as a minimal HTML page showing the image and its details
+fn post_html(post: &PostFull, public_url: &str) -> String {
+	let image = image_url(public_url, post);
+	let tags: String = post
+		.tag_vector
+		.0
+		.iter()
+		.map(|t| format!("<li>{}</li>", escape_html(t)))
+		.collect();
+	format!(
+		"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Post \
+		 #{id}</title>\n</head>\n<body>\n<h1>Post #{id}</h1>\n<img src=\"{image}\" \
+		 width=\"{width}\" height=\"{height}\" alt=\"{filename}\">\n<p>{description}</p>\n\
+		 <p>Rating: {rating:?}, score: {score}, views: {views}</p>\n<ul>{tags}</ul>\n</body>\n\
+		 </html>\n",
+		id = post.id,
+		image = escape_html(&image),
+		width = post.width,
+		height = post.height,
+		filename = escape_html(&post.filename),
+		description = escape_html(post.description.as_deref().unwrap_or("")),
+		rating = post.rating,
+		score = post.score,
+		views = post.views,
+		tags = tags
+	)
+}
+
+/// Best effort removal of the files of posts that never made it into the database
+async fn discard_post_files(root: &str, posts: &[PostFull]) {
+	for post in posts {
+		let (img_path, tmb_path) = format_paths(root, &post.path, post.id, &post.filename);
+		let (img, tmb) = futures::join!(remove_if_exists(&img_path), remove_if_exists(&tmb_path));
+		if let Err(e) = img.and(tmb) {
+			log::warn!("({}): Failed to clean up files of post {}", e, post.id);
+		}
+	}
+}
+
 #[derive(serde::Deserialize)]
 pub struct IdPostQuery {
 	id: i64,
 }
 
-pub async fn get_post(
+/// Send clients on to wherever a post's image currently lives, so links to it
+/// keep working if storage gets reorganised
+pub async fn get_image(
 	query: web::Query<IdPostQuery>,
 	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
 ) -> Result<HttpResponse, APIError> {
-	// Verify we haven't been given a negative ID
 	if query.id < 0 {
 		return Err(APIError::BadRequestData);
 	}
 
-	// Query database for post
-	let conn = try500!(pool.get().await, "get_post:db pool");
+	let conn = try500!(pool.get().await, "get_image:db pool");
 	let post = try500!(
 		Post::select_post::<pg::Client>(&conn, query.id).await,
-		"get_post:select_id {}",
+		"get_image:select_post {}",
 		query.id
 	);
 
-	// Check to see if we actually found a post
 	match post {
-		Some(x) => Ok(HttpResponse::Ok()
+		// Temporary as the location may move again
+		Some(x) => Ok(HttpResponse::Found()
+			.append_header((header::LOCATION, image_url(&settings.public_url, &x)))
+			.finish()),
+		None => Ok(HttpResponse::NotFound()
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-			.body(serde_json::to_string(x.as_full()).unwrap())),
+			.body(r#"{"error":"post not found"}"#)),
+	}
+}
+
+/// Split up a comma separated list of post fields a client wants, `None` if they
+/// didn't ask for any in particular
+pub fn parse_fields(raw: &Option<String>) -> Result<Option<Vec<&str>>, APIError> {
+	let raw = match raw {
+		Some(raw) => raw,
+		None => return Ok(None),
+	};
+	let fields: Vec<&str> = raw
+		.split(',')
+		.map(str::trim)
+		.filter(|f| !f.is_empty())
+		.collect();
+	if let Some(f) = fields.iter().find(|f| !PostFull::FIELDS.contains(f)) {
+		return Err(APIError::InvalidQuery(format!("unknown field '{}'", f)));
+	}
+	Ok(Some(fields))
+}
+
+/// Serialise a post keeping only `fields` if given
+pub fn post_json(post: &PostFull, fields: Option<&[&str]>) -> serde_json::Value {
+	let mut value = serde_json::to_value(post).unwrap();
+	if let (Some(fields), serde_json::Value::Object(map)) = (fields, &mut value) {
+		map.retain(|k, _| fields.contains(&k.as_str()));
+	}
+	value
+}
+
+#[derive(serde::Deserialize)]
+pub struct GetPostQuery {
+	id: i64,
+	/// Comma separated list of the fields to send back
+	fields: Option<String>,
+}
+
+pub async fn get_post(
+	req: HttpRequest,
+	query: web::Query<GetPostQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: MaybeAuthenticated,
+) -> Result<HttpResponse, APIError> {
+	// Verify we haven't been given a negative ID
+	if query.id < 0 {
+		return Err(APIError::BadRequestData);
+	}
+	let fields = parse_fields(&query.fields)?;
+
+	// Query database for post
+	let timeout = settings.query_timeout;
+	let conn = try500!(with_timeout(timeout, pool.get()).await?, "get_post:db pool");
+	let post = try500!(
+		with_timeout(
+			timeout,
+			Post::select_post_any::<pg::Client>(&conn, query.id)
+		)
+		.await?,
+		"get_post:select_id {}",
+		query.id
+	);
+
+	// Check to see if we actually found a post, letting clients know if it was removed
+	match live_post(post)? {
+		Some(mut x) => {
+			if !is_moderator(&auth) {
+				x.hide_moderation_info();
+			}
+			// Browsers get a simple page to look at instead of the raw JSON
+			if wants_html(&req) {
+				return Ok(HttpResponse::Ok()
+					.append_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+					.append_header((header::VARY, "Accept"))
+					.body(post_html(&x, &settings.public_url)));
+			}
+			Ok(HttpResponse::Ok()
+				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+				.append_header((header::VARY, "Accept"))
+				.body(post_json(&x, fields.as_deref()).to_string()))
+		}
 		None => Ok(HttpResponse::NotFound()
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 			.body(r#"{"error":"post not found"}"#)),
 	}
 }
 
+#[derive(serde::Deserialize)]
+pub struct NeighborsQuery {
+	id: i64,
+	#[serde(alias = "t", default = "default_tags")]
+	tags: String,
+	#[serde(default, deserialize_with = "deserialize_ratings")]
+	ratings: Vec<Rating>,
+	#[serde(
+		alias = "s",
+		default = "default_sort",
+		deserialize_with = "deserialize_sort"
+	)]
+	sort: PostSorting,
+}
+
+pub async fn get_neighbors(
+	query: web::Query<NeighborsQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: MaybeAuthenticated,
+) -> Result<HttpResponse, APIError> {
+	// Verify we haven't been given a negative ID
+	if query.id < 0 {
+		return Err(APIError::BadRequestData);
+	}
+	let tags = parse_tags(&query.tags, settings.max_search_tags)?;
+
+	// Make sure the post we are finding neighbours for exists
+	let conn = try500!(pool.get().await, "get_neighbors:db pool");
+	let post = try500!(
+		Post::select_post::<pg::Client>(&conn, query.id).await,
+		"get_neighbors:select_post {}",
+		query.id
+	);
+	if post.is_none() {
+		return Ok(HttpResponse::NotFound()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(r#"{"error":"post not found"}"#));
+	}
+
+	// Use the same ratings a search would so we walk through the same results
+	let ratings = resolve_ratings(&**conn, &query.ratings, &auth, &settings).await?;
+	let (prev, next) = try500!(
+		Post::select_neighbors::<pg::Client>(
+			&conn,
+			&settings.ts_config,
+			&SearchFilter {
+				tags: &tags,
+				ratings: &ratings,
+				..Default::default()
+			},
+			query.id,
+			query.sort
+		)
+		.await,
+		"get_neighbors:select_neighbors {} {:?}",
+		query.id,
+		tags
+	);
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::json!({ "prev": prev, "next": next }).to_string()))
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeletePostQuery {
+	id: i64,
+	/// Skip the purge queue and remove the post straight away, moderators only
+	#[serde(default)]
+	hard: bool,
+}
+
 pub async fn delete_post(
-	query: web::Query<IdPostQuery>,
+	query: web::Query<DeletePostQuery>,
 	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
 	auth: Authenticated,
 ) -> Result<HttpResponse, APIError> {
+	if !auth.can_write() {
+		return Err(APIError::Auth);
+	}
 	// Verify we haven't been given a negative ID
 	if query.id < 0 {
 		return Err(APIError::BadRequestData);
@@ -81,206 +373,973 @@ pub async fn delete_post(
 	let mut conn = try500!(pool.get().await, "delete_post:db pool");
 	let trans = try500!(conn.transaction().await);
 	let post = try500!(
-		Post::select_can_delete::<pg::Transaction<'_>>(&trans, query.id, auth.uid).await,
+		Post::select_can_change::<pg::Transaction<'_>>(&trans, query.id, auth.uid).await,
 		"delete_post:select_id_poster {}",
 		query.id
 	);
 
-	// if it exists and we are the owner we can delete it
-	let res = match post {
-		Some((true, mut post)) => {
-			try500!(
-				post.update_is_deleted::<pg::Transaction<'_>>(&trans, true)
-					.await,
-				"delete_post:update_is_deleted"
-			);
-			let post = post.into_full();
-			// Also decrease our tag count
+	// if it exists and we are the owner we can delete it, anything else returns early
+	// and the transaction is rolled back when dropped
+	let moderator = matches!(auth.perms, Perms::Moderator | Perms::Admin);
+	match post {
+		Some((true, post)) => {
+			let handle = Post(post.id);
+			// Regular users don't get to bypass the purge queue
+			let hard = query.hard && moderator;
+			if hard {
+				try500!(
+					handle.delete_now::<pg::Transaction<'_>>(&trans).await,
+					"delete_post:delete_now"
+				);
+			} else {
+				try500!(
+					handle
+						.update_is_deleted::<pg::Transaction<'_>>(&trans, true)
+						.await,
+					"delete_post:update_is_deleted"
+				);
+			}
+			// Also decrease our tag count, a hard delete has already done this
+			if !hard {
+				try500!(
+					Tag::update_decrease_counts::<pg::Transaction<'_>>(&trans, &post.tag_vector.0)
+						.await,
+					"delete_post:update_decrease_counts {:?}",
+					post.tag_vector
+				);
+			}
+			// Record who deleted it
+			let entry = NewAuditEntry {
+				post_id: post.id,
+				uid: auth.uid,
+				action: AuditAction::Delete,
+				detail: serde_json::json!({ "tags": post.tag_vector.0, "hard": hard }),
+			};
 			try500!(
-				Tag::update_decrease_counts::<pg::Transaction<'_>>(&trans, &post.tag_vector.0)
-					.await,
-				"delete_post:update_decrease_counts {:?}",
-				post.tag_vector
+				entry.insert_into::<pg::Transaction<'_>>(&trans).await,
+				"delete_post:audit insert_into {:?}",
+				entry
 			);
+			// Commit our changes and only then remove the files, so a failed commit
+			// doesn't leave a post without its image
+			try500!(trans.commit().await);
+			if hard {
+				remove_post_files(&settings.storage_root, &post).await?;
+			}
 			Ok(HttpResponse::Ok()
 				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-				.body(r#"{"success":"post deleted"}"#))
+				.body(
+					serde_json::json!({ "success": "post deleted", "id": post.id, "hard": hard })
+						.to_string(),
+				))
 		}
 		Some((false, _)) => Err(APIError::Auth),
 		None => Ok(HttpResponse::NotFound()
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 			.body(r#"{"error":"post not found"}"#)),
-	};
-	// Commit our changes, if any
-	try500!(trans.commit().await);
+	}
+}
 
-	res
+#[derive(serde::Deserialize)]
+pub struct LockPostQuery {
+	id: i64,
+	/// Lock or unlock rather than flipping whatever it is now
+	locked: Option<bool>,
 }
 
-pub async fn delete_purge_posts(
+pub async fn post_lock(
+	query: web::Query<LockPostQuery>,
 	pool: web::Data<DbPool>,
-	settings: web::Data<RunSettings>,
 	auth: Authenticated,
 ) -> Result<HttpResponse, APIError> {
-	if auth.perms != Perms::Admin {
+	if !matches!(auth.perms, Perms::Moderator | Perms::Admin) {
 		return Err(APIError::Auth);
 	}
 
-	let conn = try500!(pool.get().await, "delete_post:db pool");
-	let posts = try500!(
-		Post::select_is_deleted::<pg::Client>(&conn).await,
-		"delete_purge_posts:select"
+	let mut conn = try500!(pool.get().await, "post_lock:db pool");
+	let trans = try500!(conn.transaction().await);
+	let locked = try500!(
+		Post(query.id)
+			.update_is_locked::<pg::Transaction<'_>>(&trans, query.locked)
+			.await,
+		"post_lock:update_is_locked {}",
+		query.id
 	);
-	for post in posts {
-		// Check to make sure we only delete if the image is still marked to be deleted
-		if try500!(
-			Post::Partial(post.id)
-				.delete_post_checked::<pg::Client>(&conn)
-				.await,
-			"delete_post"
-		) == false
-		{
-			continue;
+	let locked = match locked {
+		Some(locked) => locked,
+		None => {
+			return Ok(HttpResponse::NotFound()
+				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+				.body(r#"{"error":"post not found"}"#))
 		}
-		// Delete the image files on disk
-		let (img_path, tmb_path) =
-			format_paths(&settings.storage_root, &post.path, post.id, &post.filename);
-		let (img, tmb) = futures::join!(fs::remove_file(&img_path), fs::remove_file(&tmb_path),);
-		try500!(img, "image delete {}", img_path.display());
-		try500!(tmb, "thumb delete {}", tmb_path.display());
-	}
+	};
+	// Record who locked it
+	let entry = NewAuditEntry {
+		post_id: query.id,
+		uid: auth.uid,
+		action: if locked {
+			AuditAction::Lock
+		} else {
+			AuditAction::Unlock
+		},
+		detail: serde_json::json!({}),
+	};
+	try500!(
+		entry.insert_into::<pg::Transaction<'_>>(&trans).await,
+		"post_lock:audit insert_into {:?}",
+		entry
+	);
+	try500!(trans.commit().await);
+
 	Ok(HttpResponse::Ok()
 		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-		.body(r#"{"success":"posts purged"}"#))
+		.body(
+			serde_json::json!({ "success": "post lock updated", "id": query.id, "locked": locked })
+				.to_string(),
+		))
 }
 
 #[derive(serde::Deserialize)]
-struct NewPostDetails {
-	tags: Vec<String>,
-	#[serde(default = "String::new")]
-	description: String,
-	#[serde(default = "Rating::default")]
-	rating: Rating,
+pub struct EditPostQuery {
+	id: i64,
 }
 
-pub async fn post_upload(
-	payload: Multipart,
+/// Changes to a post, anything left out stays as it is
+#[derive(serde::Deserialize)]
+pub struct EditPostDetails {
+	tags: Option<Vec<String>>,
+	description: Option<String>,
+	rating: Option<Rating>,
+}
+
+pub async fn patch_post(
+	query: web::Query<EditPostQuery>,
+	details: web::Json<EditPostDetails>,
 	pool: web::Data<DbPool>,
 	settings: web::Data<RunSettings>,
 	auth: Authenticated,
 ) -> Result<HttpResponse, APIError> {
-	let (image_data, filename, json) =
-		process_multipart_image(payload, settings.max_payload).await?;
+	if !auth.can_write() {
+		return Err(APIError::Auth);
+	}
+	// Verify we haven't been given a negative ID
+	if query.id < 0 {
+		return Err(APIError::BadRequestData);
+	}
+	if matches!(&details.description, Some(d) if !limits::within(d, 0, limits::DESCRIPTION_MAX)) {
+		return Err(APIError::BadRequestData);
+	}
+	let tags = details.tags.as_deref().map(clean_tags).transpose()?;
+	if let Some(tags) = &tags {
+		check_banned(tags, &settings)?;
+	}
 
-	// Load image into memory for thumbnail/info/hashing
-	let image_type = image::guess_format(&image_data).map_err(|_| APIError::MimeType)?;
-	let mut image = image::load_from_memory_with_format(&image_data, image_type)
-		.map_err(|_| APIError::BadRequestData)?;
+	let mut conn = try500!(pool.get().await, "patch_post:db pool");
+	let trans = try500!(conn.transaction().await);
+	let post = try500!(
+		Post::select_can_change::<pg::Transaction<'_>>(&trans, query.id, auth.uid).await,
+		"patch_post:select_can_change {}",
+		query.id
+	);
+	let moderator = matches!(auth.perms, Perms::Moderator | Perms::Admin);
+	let post = match editable_post(post, moderator)? {
+		Some(post) => post,
+		None => {
+			return Ok(HttpResponse::NotFound()
+				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+				.body(r#"{"error":"post not found"}"#))
+		}
+	};
 
-	// Image metadata
-	let dimensions = image::GenericImageView::dimensions(&image);
-	let file_size = image_data.len() as u32;
+	// The row is locked by select_can_change so it is still there
+	let mut edited = try500!(
+		Post(post.id)
+			.update_details::<pg::Transaction<'_>>(
+				&trans,
+				&settings.ts_config,
+				tags.as_deref(),
+				details.description.as_deref(),
+				details.rating,
+			)
+			.await,
+		"patch_post:update_details {}",
+		post.id
+	)
+	.ok_or(APIError::InternalError)?;
+	// Move the counts over from the old tags to the new ones
+	if let Some(tags) = &tags {
+		try500!(
+			Tag::update_decrease_counts::<pg::Transaction<'_>>(&trans, &post.tag_vector.0).await,
+			"patch_post:update_decrease_counts {:?}",
+			post.tag_vector
+		);
+		try500!(
+			Tag::update_tag_count::<pg::Transaction<'_>>(&trans, tags).await,
+			"patch_post:update_tag_count {:?}",
+			tags
+		);
+	}
+	// Record who changed what
+	let details = |post: &PostFull| {
+		serde_json::json!({
+			"tags": post.tag_vector.0,
+			"description": post.description,
+			"rating": post.rating,
+		})
+	};
+	let entry = NewAuditEntry {
+		post_id: post.id,
+		uid: auth.uid,
+		action: AuditAction::Edit,
+		detail: serde_json::json!({ "old": details(&post), "new": details(&edited) }),
+	};
+	try500!(
+		entry.insert_into::<pg::Transaction<'_>>(&trans).await,
+		"patch_post:audit insert_into {:?}",
+		entry
+	);
+	try500!(trans.commit().await);
 
-	// Generate thumb
-	let thumbnail = create_thumbnail(&mut image);
+	if !moderator {
+		edited.hide_moderation_info();
+	}
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::to_string(&edited).unwrap()))
+}
 
-	// Items from JSON description
-	let details: NewPostDetails = serde_json::from_value(json)
-		.ok()
-		.ok_or(APIError::BadRequestData)?;
-	let ta = details.tags;
+#[derive(serde::Deserialize)]
+pub struct RestorePostQuery {
+	id: i64,
+}
 
-	// Check that tags are valid and add them to an array
-	let mut tags = Vec::with_capacity(ta.len());
-	for i in 0..ta.len() {
-		if ta[i].chars().any(|c| matches!(c, '+' | '!')) {
-			return Err(APIError::BadTags);
-		}
-		tags.push(ta[i].trim());
+/// Bring back a post that was deleted but hasn't been purged yet
+pub async fn post_restore(
+	query: web::Query<RestorePostQuery>,
+	pool: web::Data<DbPool>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if !matches!(auth.perms, Perms::Moderator | Perms::Admin) {
+		return Err(APIError::Auth);
 	}
 
-	// Fill in the details for our now post
-	let new_post = NewPost {
-		filename: &filename,
-		ext: image_type.into(),
-		path: "00",
-		size: file_size as i32,
-		dimensions: (dimensions.0 as i32, dimensions.1 as i32),
-		description: &details.description,
-		rating: details.rating,
-		tags: &tags,
-		poster: auth.uid,
-	};
-
-	let mut conn = try500!(pool.get().await, "post_upload:db pool");
+	let mut conn = try500!(pool.get().await, "post_restore:db pool");
 	let trans = try500!(conn.transaction().await);
 	let post = try500!(
-		new_post.insert_into::<pg::Transaction<'_>>(&trans).await,
-		"post_upload:insert_into {:?}",
-		new_post
+		Post(query.id)
+			.update_restore::<pg::Transaction<'_>>(&trans)
+			.await,
+		"post_restore:update_restore {}",
+		query.id
 	);
-
-	// Also insert/update our tags
-	let _ = try500!(
+	let post = match post {
+		Some(post) => post,
+		None => {
+			return Ok(HttpResponse::NotFound()
+				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+				.body(r#"{"error":"post not found"}"#))
+		}
+	};
+	// Deleting took the post off its tags' counts, so put it back on
+	let tags: Vec<&str> = post.tag_vector.0.iter().map(String::as_str).collect();
+	try500!(
 		Tag::update_tag_count::<pg::Transaction<'_>>(&trans, &tags).await,
-		"post_upload:update_tag_count {:?}",
+		"post_restore:update_tag_count {:?}",
 		tags
 	);
-
-	let subfolder = image_path(post.id);
+	// Record who restored it
+	let entry = NewAuditEntry {
+		post_id: post.id,
+		uid: auth.uid,
+		action: AuditAction::Restore,
+		detail: serde_json::json!({ "tags": post.tag_vector.0 }),
+	};
 	try500!(
-		Post::Partial(post.id)
-			.update_path::<pg::Transaction<'_>>(&trans, &subfolder)
-			.await,
-		"post_upload:update_path"
+		entry.insert_into::<pg::Transaction<'_>>(&trans).await,
+		"post_restore:audit insert_into {:?}",
+		entry
 	);
+	try500!(trans.commit().await);
 
-	let (img_path, tmb_path) =
-		format_paths(&settings.storage_root, &subfolder, post.id, &post.filename);
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::json!({ "success": "post restored", "id": post.id }).to_string()))
+}
 
-	// Async fs write the main image as it's already encoded
-	let img = fs::write(&img_path, &image_data);
-	// We have to first encoder the thumbnail as a Jpeg before we can write it
-	let mut tmb_data = Cursor::new(Vec::new());
-	try500!(
-		thumbnail.write_to(&mut tmb_data, image::ImageOutputFormat::Jpeg(90)),
-		"jpeg encode"
+/// Serve a post's thumbnail through the API, for when the static files aren't
+/// reachable. Thumbnails never change once made so they can be cached for a while
+pub async fn get_thumb(
+	req: HttpRequest,
+	query: web::Query<IdPostQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+) -> Result<HttpResponse, APIError> {
+	if query.id < 0 {
+		return Err(APIError::BadRequestData);
+	}
+
+	let not_found = || {
+		HttpResponse::NotFound()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(r#"{"error":"thumbnail not found"}"#)
+	};
+
+	let conn = try500!(pool.get().await, "get_thumb:db pool");
+	let post = try500!(
+		Post::select_post::<pg::Client>(&conn, query.id).await,
+		"get_thumb:select_post {}",
+		query.id
 	);
-	let tmb_data = tmb_data.into_inner();
-	let tmb = fs::write(&tmb_path, &tmb_data);
+	let post = match post {
+		Some(x) => x,
+		None => return Ok(not_found()),
+	};
 
-	// Take these two futures and wait on them
-	let (img, tmb) = futures::join!(img, tmb);
-	try500!(img, "image write {}", img_path.display());
-	try500!(tmb, "thumb write {}", tmb_path.display());
+	let etag = format!("\"tmb-{}\"", post.id);
+	let matches = req
+		.headers()
+		.get(header::IF_NONE_MATCH)
+		.and_then(|v| v.to_str().ok())
+		.is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+	if matches {
+		return Ok(HttpResponse::NotModified()
+			.append_header((header::ETAG, etag))
+			.finish());
+	}
 
-	// Commit our transaction
-	try500!(trans.commit().await);
+	let (_, tmb_path) = format_paths(&settings.storage_root, &post.path, post.id, &post.filename);
+	let data = match fs::read(&tmb_path).await {
+		Ok(data) => data,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(not_found()),
+		Err(e) => {
+			log::error!("({}): Failed to read thumbnail {}", e, tmb_path.display());
+			return Err(APIError::InternalError);
+		}
+	};
 
 	Ok(HttpResponse::Ok()
-		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-		.body(serde_json::to_string(&post).unwrap()))
+		.append_header((header::CONTENT_TYPE, "image/jpeg"))
+		.append_header((header::CACHE_CONTROL, "public, max-age=86400"))
+		.append_header((header::ETAG, etag))
+		.body(data))
 }
 
-async fn process_multipart_image(
-	mut payload: Multipart,
-	maximum_size: usize,
-) -> Result<(Vec<u8>, String, serde_json::Value), APIError> {
-	// Get the multipart data
-	let mut image_data = Vec::new();
-	let mut filename = String::new();
-	let mut json = serde_json::Value::Null;
-	let mut bytes_read = 0;
-	// Iterate over incoming data
-	while let Ok(Some(mut field)) = payload.try_next().await {
-		// Get content disposition and then name, return error if invalid/missing
-		let cont_type = field.content_disposition().clone();
-		let name = cont_type.get_name().ok_or(APIError::BadRequestData)?;
-
-		// Counter how many bytes and return err if over sized
-		let mut count_bytes = |new_bytes: usize| -> Result<(), APIError> {
-			bytes_read += new_bytes;
-			if bytes_read >= maximum_size * 1024 {
+/// Just the tags of a post, for clients that don't need the rest of it
+pub async fn get_post_tags(
+	query: web::Query<IdPostQuery>,
+	pool: web::Data<DbPool>,
+) -> Result<HttpResponse, APIError> {
+	if query.id < 0 {
+		return Err(APIError::BadRequestData);
+	}
+
+	let conn = try500!(pool.get().await, "get_post_tags:db pool");
+	let post = try500!(
+		Post::select_post::<pg::Client>(&conn, query.id).await,
+		"get_post_tags:select_post {}",
+		query.id
+	);
+
+	match post {
+		Some(x) => Ok(HttpResponse::Ok()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(serde_json::json!({ "tags": x.tag_vector.0 }).to_string())),
+		None => Ok(HttpResponse::NotFound()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(r#"{"error":"post not found"}"#)),
+	}
+}
+
+pub async fn get_history(
+	query: web::Query<IdPostQuery>,
+	pool: web::Data<DbPool>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if auth.perms != Perms::Admin {
+		return Err(APIError::Auth);
+	}
+
+	let conn = try500!(pool.get().await, "get_history:db pool");
+	let history = try500!(
+		AuditEntry::select_post_history::<pg::Client>(&conn, query.id).await,
+		"get_history:select_post_history {}",
+		query.id
+	);
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::to_string(&history).unwrap()))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PurgeQuery {
+	#[serde(default)]
+	dry_run: bool,
+}
+
+pub async fn delete_purge_posts(
+	query: web::Query<PurgeQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if auth.perms != Perms::Admin {
+		return Err(APIError::Auth);
+	}
+
+	let mut conn = try500!(pool.get().await, "delete_post:db pool");
+	// Work through the deleted posts a batch at a time so a large backlog doesn't all
+	// end up in memory at once
+	let mut after = 0;
+	let mut purged = Vec::new();
+	let mut files = Vec::new();
+	let mut failed = Vec::new();
+	loop {
+		let posts = try500!(
+			Post::select_is_deleted::<pg::Client>(&conn, settings.purge_grace, after, PURGE_BATCH)
+				.await,
+			"delete_purge_posts:select after {}",
+			after
+		);
+		let last = match posts.last() {
+			Some(post) => post.id,
+			None => break,
+		};
+		after = last;
+
+		// Just report back what would be removed
+		if query.dry_run {
+			for post in posts {
+				let (img_path, tmb_path) =
+					format_paths(&settings.storage_root, &post.path, post.id, &post.filename);
+				purged.push(post.id);
+				files.push(img_path);
+				files.push(tmb_path);
+			}
+			continue;
+		}
+
+		let trans = try500!(conn.transaction().await);
+		let mut doomed = Vec::with_capacity(posts.len());
+		for post in posts {
+			// Check to make sure we only delete if the image is still marked to be deleted
+			if !try500!(
+				Post(post.id)
+					.delete_post_checked::<pg::Transaction<'_>>(&trans)
+					.await,
+				"delete_post"
+			) {
+				continue;
+			}
+			// Record who purged it, the history outlives the post itself
+			let entry = NewAuditEntry {
+				post_id: post.id,
+				uid: auth.uid,
+				action: AuditAction::Purge,
+				detail: serde_json::json!({ "filename": post.filename }),
+			};
+			try500!(
+				entry.insert_into::<pg::Transaction<'_>>(&trans).await,
+				"delete_purge_posts:audit insert_into {:?}",
+				entry
+			);
+			doomed.push(post);
+		}
+		try500!(trans.commit().await);
+		// Delete the image files on disk, these are independent so don't wait on
+		// each one in turn. The posts are already gone so a file that can't be
+		// removed is only logged and reported back rather than failing the purge
+		let paths = doomed.iter().flat_map(|post| {
+			let (img_path, tmb_path) =
+				format_paths(&settings.storage_root, &post.path, post.id, &post.filename);
+			[img_path, tmb_path]
+		});
+		failed.extend(remove_files(paths, settings.purge_concurrency).await);
+		purged.extend(doomed.iter().map(|post| post.id));
+		log::info!("purge: {} posts removed so far", purged.len());
+	}
+
+	if query.dry_run {
+		return Ok(HttpResponse::Ok()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(serde_json::json!({ "posts": purged, "files": files }).to_string()));
+	}
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(
+			serde_json::json!({
+				"success": "posts purged",
+				"posts": purged,
+				"failed_files": failed,
+			})
+			.to_string(),
+		))
+}
+
+#[derive(serde::Deserialize)]
+struct NewPostDetails {
+	tags: Vec<String>,
+	#[serde(default = "String::new")]
+	description: String,
+	#[serde(default = "Rating::default")]
+	rating: Rating,
+	/// SHA-256 the client expects the images to have, so uploads that got mangled on
+	/// the way are caught
+	checksum: Option<Checksums>,
+}
+
+impl NewPostDetails {
+	fn within_limits(&self) -> bool {
+		limits::within(&self.description, 0, limits::DESCRIPTION_MAX)
+	}
+
+	fn clean_tags(&self) -> Result<Vec<&str>, APIError> {
+		clean_tags(&self.tags)
+	}
+}
+
+/// Check that tags are valid, dropping blanks and repeats so tag counts aren't
+/// bumped twice for the same post
+fn clean_tags(raw: &[String]) -> Result<Vec<&str>, APIError> {
+	let mut tags = Vec::with_capacity(raw.len());
+	for tag in raw {
+		if tag.chars().any(|c| matches!(c, '+' | '!')) {
+			return Err(APIError::BadTags);
+		}
+		let tag = tag.trim();
+		if !limits::within(tag, 0, limits::TAG_MAX) {
+			return Err(APIError::BadRequestData);
+		}
+		if !tag.is_empty() && !tags.contains(&tag) {
+			tags.push(tag);
+		}
+	}
+	Ok(tags)
+}
+
+/// Posts can't be given any of the tags that have been banned
+fn check_banned(tags: &[&str], settings: &RunSettings) -> Result<(), APIError> {
+	if tags
+		.iter()
+		.any(|t| settings.banned_tags.contains(&t.to_lowercase()))
+	{
+		return Err(APIError::BannedTag);
+	}
+	Ok(())
+}
+
+/// A hex SHA-256 for a single image, or one per image of a gallery in upload order
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Checksums {
+	One(String),
+	Many(Vec<String>),
+}
+
+impl Checksums {
+	fn verify(&self, images: &[(Vec<u8>, String)]) -> Result<(), APIError> {
+		use sha2::{Digest, Sha256};
+		let expected = match self {
+			Checksums::One(sum) => std::slice::from_ref(sum),
+			Checksums::Many(sums) => sums.as_slice(),
+		};
+		if expected.len() != images.len() {
+			return Err(APIError::BadRequestData);
+		}
+		for (sum, (data, _)) in expected.iter().zip(images) {
+			let actual = format!("{:x}", Sha256::digest(data));
+			if !actual.eq_ignore_ascii_case(sum.trim()) {
+				return Err(APIError::BadRequestData);
+			}
+		}
+		Ok(())
+	}
+}
+
+pub async fn post_upload(
+	req: HttpRequest,
+	payload: Multipart,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if !auth.can_write() {
+		return Err(APIError::Auth);
+	}
+	// Reject anything that tells us upfront it's too big before reading any of it, the
+	// multipart processing still counts bytes in case this is missing or a lie
+	let content_length = req
+		.headers()
+		.get(header::CONTENT_LENGTH)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse::<usize>().ok());
+	if matches!(content_length, Some(len) if len > settings.max_payload * 1024) {
+		return Err(APIError::PayloadSize);
+	}
+
+	// A retried upload with the same key gets back what the first attempt created
+	let idempotency_key = match req.headers().get("Idempotency-Key") {
+		Some(v) => {
+			let v = v.to_str().map_err(|_| APIError::BadRequestData)?;
+			if v.is_empty() || v.len() > 255 {
+				return Err(APIError::BadRequestData);
+			}
+			Some(format!("idempotency:{}:{}", auth.uid, v))
+		}
+		None => None,
+	};
+	// Claim the key before doing anything so two attempts racing each other can't
+	// both go through
+	let reservation = match &idempotency_key {
+		Some(key) => match auth.get_db().reserve(key, IDEMPOTENCY_PENDING_TTL).await? {
+			Reservation::Reserved(guard) => Some(guard),
+			Reservation::Pending => return Err(APIError::InProgress),
+			Reservation::Done(stored) => return replay_upload(&stored),
+		},
+		None => None,
+	};
+	// Redis only holds onto keys for so long, postgres has the final say on whether
+	// an upload with this key already went through
+	if let Some(key) = &idempotency_key {
+		let conn = try500!(pool.get().await, "post_upload:db pool");
+		let stored = try500!(
+			select_idempotent_upload::<pg::Client>(&conn, key, IDEMPOTENCY_TTL).await,
+			"post_upload:select_idempotent_upload {}",
+			key
+		);
+		if let Some(stored) = stored {
+			if let Some(guard) = reservation {
+				// Not a problem if this fails, we'll just come back here next time
+				let _ = guard.complete(&stored, IDEMPOTENCY_TTL).await;
+			}
+			return replay_upload(&stored);
+		}
+	}
+
+	// Hold onto an upload slot for as long as we are working on this upload
+	let _upload = auth
+		.get_db()
+		.start_upload(auth.uid, settings.max_concurrent_uploads)
+		.await?;
+
+	let (images, json) =
+		process_multipart_image(payload, settings.max_payload, settings.max_group_images).await?;
+	if images.is_empty() {
+		return Err(APIError::BadRequestData);
+	}
+
+	// Items from JSON description
+	let details: NewPostDetails = serde_json::from_value(json)
+		.ok()
+		.ok_or(APIError::BadRequestData)?;
+	if !details.within_limits() {
+		return Err(APIError::BadRequestData);
+	}
+	let tags = details.clean_tags()?;
+	// Reject the upload outright if any of the tags have been banned
+	check_banned(&tags, &settings)?;
+	// A truncated or corrupt upload is cheaper to catch before decoding it
+	if let Some(checksum) = &details.checksum {
+		checksum.verify(&images)?;
+	}
+
+	// Decode every image before touching the database so a bad one fails the lot,
+	// this is CPU heavy so keep it off the worker thread
+	let mut processed = Vec::with_capacity(images.len());
+	for (data, filename) in images {
+		let settings = settings.clone();
+		let image = try500!(
+			web::block(move || process_image(data, &filename, &settings)).await,
+			"post_upload:process_image"
+		)?;
+		processed.push(image);
+	}
+
+	let mut conn = try500!(pool.get().await, "post_upload:db pool");
+	let trans = try500!(conn.transaction().await);
+
+	// More than one image makes a gallery, with every post sharing the same details
+	let group_id = if processed.len() > 1 {
+		Some(try500!(
+			insert_post_group::<pg::Transaction<'_>>(&trans, auth.uid).await,
+			"post_upload:insert_post_group"
+		))
+	} else {
+		None
+	};
+
+	let mut posts = Vec::with_capacity(processed.len());
+	for image in &processed {
+		// Fill in the details for our now post
+		let new_post = NewPost {
+			filename: &image.filename,
+			ext: image.ext,
+			path: "00",
+			size: image.data.len() as i32,
+			dimensions: (image.dimensions.0 as i32, image.dimensions.1 as i32),
+			description: &details.description,
+			rating: details.rating,
+			tags: &tags,
+			poster: auth.uid,
+			group_id,
+			created_ip: client_ip(&req, &settings.trusted_proxies),
+		};
+		match store_post(&trans, &settings, &new_post, image).await {
+			Ok(post) => posts.push(post),
+			Err(e) => {
+				// The transaction rolls back when dropped but files already written don't
+				discard_post_files(&settings.storage_root, &posts).await;
+				return Err(e);
+			}
+		}
+	}
+
+	if !matches!(auth.perms, Perms::Moderator | Perms::Admin) {
+		posts.iter_mut().for_each(PostFull::hide_moderation_info);
+	}
+
+	let body = match group_id {
+		Some(id) => serde_json::to_string(&serde_json::json!({
+			"group_id": id,
+			"posts": posts.iter().map(|p| p.id).collect::<Vec<_>>(),
+			// What postgres stored is what searches match against, so report that
+			"tags": posts[0].tag_vector,
+		})),
+		// Re-encoding means what we stored may not be what was sent, so say what it
+		// ended up as
+		None => {
+			let (width, height) = processed[0].thumbnail_dimensions;
+			let mut body = serde_json::to_value(&posts[0]).unwrap();
+			body["thumb"] = serde_json::json!({ "width": width, "height": height });
+			body["stored_size"] = processed[0].data.len().into();
+			serde_json::to_string(&body)
+		}
+	}
+	.unwrap();
+	// Galleries don't have anywhere of their own to point to
+	let location = match group_id {
+		Some(_) => None,
+		None => Some(format!("{}/post?id={}", settings.public_url, posts[0].id)),
+	};
+	let stored = serde_json::json!({ "location": location, "body": body }).to_string();
+
+	// Keep the answer alongside the posts, so a retry finds it even once redis has
+	// forgotten the key
+	if let Some(key) = &idempotency_key {
+		match insert_idempotent_upload::<pg::Transaction<'_>>(&trans, key, &stored, IDEMPOTENCY_TTL)
+			.await
+		{
+			Ok(true) => {}
+			// Another attempt with the key got through while ours was pending
+			Ok(false) => {
+				discard_post_files(&settings.storage_root, &posts).await;
+				return Err(APIError::InProgress);
+			}
+			Err(e) => {
+				log::error!("({}): Failed to store idempotency key {}", e, key);
+				discard_post_files(&settings.storage_root, &posts).await;
+				return Err(APIError::InternalError);
+			}
+		}
+	}
+
+	// Commit our transaction
+	if let Err(e) = trans.commit().await {
+		log::error!("({}): Failed to commit upload", e);
+		discard_post_files(&settings.storage_root, &posts).await;
+		return Err(APIError::InternalError);
+	}
+
+	if let Some(guard) = reservation {
+		// The upload has already gone through so it mustn't fail now. The key stays
+		// pending in redis until IDEMPOTENCY_PENDING_TTL, after which a retry is
+		// answered from postgres instead
+		if guard.complete(&stored, IDEMPOTENCY_TTL).await.is_err() {
+			log::warn!(
+				"idempotency key for upload of post {} left pending, retries are turned away \
+				 until it expires and then answered from postgres",
+				posts[0].id
+			);
+		}
+	}
+	Ok(upload_response(location.as_deref(), body))
+}
+
+fn upload_response(location: Option<&str>, body: String) -> HttpResponse {
+	let mut res = HttpResponse::Created();
+	if let Some(location) = location {
+		res.append_header((header::LOCATION, location));
+	}
+	res.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(body)
+}
+
+/// Answer a retried upload exactly like the attempt that went through
+fn replay_upload(stored: &str) -> Result<HttpResponse, APIError> {
+	#[derive(serde::Deserialize)]
+	struct Stored {
+		location: Option<String>,
+		body: String,
+	}
+	let stored: Stored = try500!(serde_json::from_str(stored), "replay_upload: {}", stored);
+	Ok(upload_response(stored.location.as_deref(), stored.body))
+}
+
+/// An uploaded image that has been decoded and is ready to be stored
+struct ProcessedImage {
+	data: Vec<u8>,
+	filename: String,
+	ext: ImageExtension,
+	dimensions: (u32, u32),
+	/// Already encoded as a JPEG
+	thumbnail: Vec<u8>,
+	thumbnail_dimensions: (u32, u32),
+}
+
+fn process_image(
+	mut image_data: Vec<u8>,
+	filename: &str,
+	settings: &RunSettings,
+) -> Result<ProcessedImage, APIError> {
+	// Never trust the extension the client gave us, use the one for what we detected
+	let image_type = image::guess_format(&image_data).map_err(|_| APIError::MimeType)?;
+	let mut ext = ImageExtension::try_from(image_type).map_err(|_| APIError::MimeType)?;
+	if !settings.allowed_formats.contains(&ext) {
+		return Err(APIError::MimeType);
+	}
+
+	// Load image into memory for thumbnail/info/hashing
+	let mut image = image::load_from_memory_with_format(&image_data, image_type)
+		.map_err(|_| APIError::BadRequestData)?;
+	let transcode = settings.transcode_to.is_some() && ext != ImageExtension::Webp;
+	// Phone cameras often store images sideways and rely on EXIF to fix it up. The
+	// original is kept byte for byte since viewers honour the tag themselves, only
+	// what we make from it is turned upright
+	image = apply_orientation(image, exif_orientation(&image_data));
+	// The decoded image is already upright so this bakes in any rotation too
+	if transcode {
+		let rgba = image.to_rgba8();
+		// WebP can't go any bigger than this, so these can't be converted
+		if rgba.width() > WEBP_MAX_DIMENSION || rgba.height() > WEBP_MAX_DIMENSION {
+			return Err(APIError::BadRequestData);
+		}
+		let encoded = try500!(
+			webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+				.encode_simple(false, f32::from(settings.reencode_quality)),
+			"webp encode {}x{}",
+			rgba.width(),
+			rgba.height()
+		);
+		image_data = encoded.to_vec();
+		ext = ImageExtension::Webp;
+	}
+
+	// We have to first encoder the thumbnail as a Jpeg before we can write it
+	let thumb = create_thumbnail(&mut image);
+	let mut thumbnail = Cursor::new(Vec::new());
+	try500!(
+		thumb.write_to(&mut thumbnail, image::ImageOutputFormat::Jpeg(90)),
+		"jpeg encode"
+	);
+
+	Ok(ProcessedImage {
+		filename: normalise_filename(filename, ext),
+		ext,
+		dimensions: image::GenericImageView::dimensions(&image),
+		thumbnail: thumbnail.into_inner(),
+		thumbnail_dimensions: image::GenericImageView::dimensions(&thumb),
+		data: image_data,
+	})
+}
+
+/// Insert a post for `image` and write its files out, the caller commits the
+/// transaction once everything has been stored
+async fn store_post(
+	trans: &pg::Transaction<'_>,
+	settings: &RunSettings,
+	new_post: &NewPost<'_>,
+	image: &ProcessedImage,
+) -> Result<PostFull, APIError> {
+	let mut post = try500!(
+		new_post
+			.insert_into::<pg::Transaction<'_>>(trans, &settings.ts_config)
+			.await,
+		"post_upload:insert_into {:?}",
+		new_post
+	);
+
+	// Also insert/update our tags
+	let _ = try500!(
+		Tag::update_tag_count::<pg::Transaction<'_>>(trans, new_post.tags).await,
+		"post_upload:update_tag_count {:?}",
+		new_post.tags
+	);
+
+	let subfolder = image_path(post.id);
+	try500!(
+		Post(post.id)
+			.update_path::<pg::Transaction<'_>>(trans, &subfolder)
+			.await,
+		"post_upload:update_path"
+	);
+	post.path = subfolder;
+
+	let (img_path, tmb_path) =
+		format_paths(&settings.storage_root, &post.path, post.id, &post.filename);
+
+	// Make sure the subfolders exist, this copes with them being created at the same
+	// time by another upload so create-folders doesn't need to have been run
+	for dir in [img_path.parent(), tmb_path.parent()].into_iter().flatten() {
+		try500!(
+			fs::create_dir_all(dir).await,
+			"create dir {}",
+			dir.display()
+		);
+	}
+
+	// Async fs write the main image as it's already encoded
+	let img = fs::write(&img_path, &image.data);
+	let tmb = fs::write(&tmb_path, &image.thumbnail);
+
+	// Take these two futures and wait on them, not leaving half a post behind if
+	// either fails
+	let (img, tmb) = futures::join!(img, tmb);
+	if img.is_err() || tmb.is_err() {
+		discard_post_files(&settings.storage_root, std::slice::from_ref(&post)).await;
+	}
+	try500!(img, "image write {}", img_path.display());
+	try500!(tmb, "thumb write {}", tmb_path.display());
+
+	Ok(post)
+}
+
+/// Read the images and JSON details out of an upload, each `image` field is kept
+/// as its own image along with its filename while more than one `data` field is
+/// rejected
+async fn process_multipart_image(
+	mut payload: Multipart,
+	maximum_size: usize,
+	maximum_images: usize,
+) -> Result<(Vec<(Vec<u8>, String)>, serde_json::Value), APIError> {
+	// Get the multipart data
+	let mut images = Vec::new();
+	let mut json = serde_json::Value::Null;
+	let mut seen_data = false;
+	let mut bytes_read: usize = 0;
+	// Checked at startup to fit, saturate anyway rather than wrap
+	let limit = maximum_size.saturating_mul(1024);
+	// Iterate over incoming data, a broken stream means the upload is incomplete so
+	// it has to fail rather than carry on with whatever arrived
+	while let Some(mut field) = payload.try_next().await.map_err(multipart_error)? {
+		// Get content disposition and then name, return error if invalid/missing
+		let cont_type = field.content_disposition().clone();
+		let name = cont_type.get_name().ok_or(APIError::BadRequestData)?;
+
+		// Counter how many bytes and return err if over sized, a payload of exactly
+		// the limit is fine
+		let mut count_bytes = |new_bytes: usize| -> Result<(), APIError> {
+			bytes_read = bytes_read.saturating_add(new_bytes);
+			if bytes_read > limit {
 				Err(APIError::PayloadSize)
 			} else {
 				Ok(())
@@ -289,20 +1348,30 @@ async fn process_multipart_image(
 		// Iterator over chunks in field
 		match name {
 			"image" => {
+				if images.len() >= maximum_images {
+					return Err(APIError::ImageLimit);
+				}
+				let filename = sanitize_filename::sanitize(
+					cont_type.get_filename().ok_or(APIError::BadRequestData)?,
+				);
 				// Read data and check that is within size limit
-				while let Some(Ok(chunk)) = field.next().await {
+				let mut image_data = Vec::new();
+				while let Some(chunk) = field.try_next().await.map_err(multipart_error)? {
 					count_bytes(chunk.len())?;
 					image_data.extend_from_slice(&chunk);
 				}
-				filename = sanitize_filename::sanitize(
-					cont_type.get_filename().ok_or(APIError::BadRequestData)?,
-				);
+				images.push((image_data, filename));
 			}
 			"data" => {
+				// A second set of details would silently replace the first, so refuse it
+				if seen_data {
+					return Err(APIError::BadRequestData);
+				}
+				seen_data = true;
 				// Temporarily store the data, we could implement a reader to avoid a memcpy but
 				// eh
 				let mut data = Vec::new();
-				while let Some(Ok(chunk)) = field.next().await {
+				while let Some(chunk) = field.try_next().await.map_err(multipart_error)? {
 					count_bytes(chunk.len())?;
 					data.extend_from_slice(&chunk);
 				}
@@ -311,13 +1380,77 @@ async fn process_multipart_image(
 			_ => {
 				// This is effectively ignored, but count the amount of bytes
 				// anyway so we know we aren't being sent to much data
-				while let Some(Ok(chunk)) = field.next().await {
+				while let Some(chunk) = field.try_next().await.map_err(multipart_error)? {
 					count_bytes(chunk.len())?;
 				}
 			}
 		}
 	}
-	Ok((image_data, filename, json))
+	Ok((images, json))
+}
+
+/// A payload that overflowed is too large, anything else means it was cut short or
+/// malformed
+fn multipart_error(e: MultipartError) -> APIError {
+	match e {
+		MultipartError::Payload(PayloadError::Overflow) => APIError::PayloadSize,
+		_ => APIError::BadRequestData,
+	}
+}
+
+/// A post that has been deleted is gone rather than missing, so clients can tell it
+/// was removed from one that never existed
+fn live_post(post: Option<PostFull>) -> Result<Option<PostFull>, APIError> {
+	match post {
+		Some(x) if x.is_deleted => Err(APIError::Gone),
+		post => Ok(post),
+	}
+}
+
+/// The post from `Post::select_can_change` if it may be edited. Once a moderator has
+/// locked a post only moderators get to change it, its owner included
+fn editable_post(
+	post: Option<(bool, PostFull)>,
+	moderator: bool,
+) -> Result<Option<PostFull>, APIError> {
+	match post {
+		Some((false, _)) => Err(APIError::Auth),
+		Some((true, post)) if post.is_locked && !moderator => Err(APIError::Locked),
+		post => Ok(post.map(|(_, post)| post)),
+	}
+}
+
+/// Replace whatever extension a filename has with the one matching `ext`
+fn normalise_filename(filename: &str, ext: ImageExtension) -> String {
+	let stem = std::path::Path::new(filename)
+		.file_stem()
+		.and_then(|s| s.to_str())
+		.filter(|s| !s.is_empty())
+		.unwrap_or("image");
+	format!("{}.{}", stem, ext.as_str())
+}
+
+fn exif_orientation(data: &[u8]) -> u32 {
+	let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+		Ok(exif) => exif,
+		Err(_) => return 1,
+	};
+	exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+		.and_then(|f| f.value.get_uint(0))
+		.unwrap_or(1)
+}
+
+fn apply_orientation(image: image::DynamicImage, orientation: u32) -> image::DynamicImage {
+	match orientation {
+		2 => image.fliph(),
+		3 => image.rotate180(),
+		4 => image.flipv(),
+		5 => image.rotate90().fliph(),
+		6 => image.rotate90(),
+		7 => image.rotate270().fliph(),
+		8 => image.rotate270(),
+		_ => image,
+	}
 }
 
 fn create_thumbnail(image: &mut image::DynamicImage) -> image::DynamicImage {
@@ -337,3 +1470,1005 @@ fn create_thumbnail(image: &mut image::DynamicImage) -> image::DynamicImage {
 	// Alternative thumbnail creation
 	// let thumbnail = image.thumbnail(320, 320);
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actix_web::http::StatusCode;
+
+	const BOUNDARY: &str = "watame-test-boundary";
+
+	/// Encode `(name, filename, data)` fields as a multipart body
+	fn multipart_body(fields: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+		let mut body = Vec::new();
+		for (name, filename, data) in fields {
+			body.extend_from_slice(format!("--{}\r\n", BOUNDARY).as_bytes());
+			match filename {
+				Some(filename) => body.extend_from_slice(
+					format!(
+						"Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n",
+						name, filename
+					)
+					.as_bytes(),
+				),
+				None => body.extend_from_slice(
+					format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name).as_bytes(),
+				),
+			}
+			body.extend_from_slice(data);
+			body.extend_from_slice(b"\r\n");
+		}
+		body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+		body
+	}
+
+	/// Build a multipart payload handed over in small chunks like a slow client would
+	fn multipart(fields: &[(&str, Option<&str>, &[u8])]) -> Multipart {
+		multipart_stream(
+			multipart_body(fields)
+				.chunks(64)
+				.map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+				.collect(),
+		)
+	}
+
+	fn multipart_stream(chunks: Vec<Result<bytes::Bytes, PayloadError>>) -> Multipart {
+		let mut headers = header::HeaderMap::new();
+		headers.insert(
+			header::CONTENT_TYPE,
+			header::HeaderValue::from_str(&format!("multipart/form-data; boundary={}", BOUNDARY))
+				.unwrap(),
+		);
+		Multipart::new(&headers, futures::stream::iter(chunks))
+	}
+
+	#[actix_web::test]
+	async fn multipart_within_limit() {
+		let image = vec![7u8; 1024];
+		let payload = multipart(&[
+			("image", Some("a.png"), &image),
+			("data", None, br#"{"tags":["a"]}"#),
+		]);
+		let (images, json) = process_multipart_image(payload, 2, 4).await.unwrap();
+		assert_eq!(images.len(), 1);
+		assert_eq!(images[0].0, image);
+		assert_eq!(images[0].1, "a.png");
+		assert_eq!(json["tags"][0], "a");
+	}
+
+	#[actix_web::test]
+	async fn multipart_over_limit() {
+		let image = vec![7u8; 2048 + 1];
+		let payload = multipart(&[("image", Some("a.png"), &image)]);
+		let res = process_multipart_image(payload, 2, 4).await;
+		assert!(matches!(res, Err(APIError::PayloadSize)));
+	}
+
+	#[actix_web::test]
+	async fn multipart_exactly_at_limit() {
+		let image = vec![7u8; 2048];
+		let payload = multipart(&[("image", Some("a.png"), &image)]);
+		let (images, _) = process_multipart_image(payload, 2, 4).await.unwrap();
+		assert_eq!(images[0].0.len(), 2048);
+	}
+
+	#[actix_web::test]
+	async fn multipart_limit_spans_fields() {
+		let data = br#"{"tags":["a"]}"#;
+		let image = vec![7u8; 2048 - data.len()];
+		let payload = multipart(&[("image", Some("a.png"), &image), ("data", None, data)]);
+		assert!(process_multipart_image(payload, 2, 4).await.is_ok());
+
+		// One more byte anywhere tips it over
+		let payload = multipart(&[
+			("image", Some("a.png"), &image),
+			("data", None, data),
+			("junk", None, b"x"),
+		]);
+		let res = process_multipart_image(payload, 2, 4).await;
+		assert!(matches!(res, Err(APIError::PayloadSize)));
+	}
+
+	#[actix_web::test]
+	async fn multipart_huge_limit() {
+		// A limit that can't be turned into bytes saturates instead of wrapping to zero
+		let payload = multipart(&[("image", Some("a.png"), b"a")]);
+		let (images, _) = process_multipart_image(payload, usize::MAX, 4)
+			.await
+			.unwrap();
+		assert_eq!(images[0].0, b"a");
+	}
+
+	#[actix_web::test]
+	async fn multipart_ignored_fields_count() {
+		let junk = vec![0u8; 4096];
+		let payload = multipart(&[("junk", None, &junk)]);
+		let res = process_multipart_image(payload, 2, 4).await;
+		assert!(matches!(res, Err(APIError::PayloadSize)));
+	}
+
+	#[actix_web::test]
+	async fn multipart_image_limit() {
+		let payload = multipart(&[
+			("image", Some("a.png"), b"a"),
+			("image", Some("b.png"), b"b"),
+		]);
+		let res = process_multipart_image(payload, 2, 1).await;
+		assert!(matches!(res, Err(APIError::ImageLimit)));
+	}
+
+	#[actix_web::test]
+	async fn multipart_images_kept_apart() {
+		let payload = multipart(&[
+			("image", Some("a.png"), b"first"),
+			("image", Some("b.png"), b"second"),
+		]);
+		let (images, json) = process_multipart_image(payload, 2, 2).await.unwrap();
+		assert_eq!(images.len(), 2);
+		assert_eq!(images[0], (b"first".to_vec(), "a.png".to_owned()));
+		assert_eq!(images[1], (b"second".to_vec(), "b.png".to_owned()));
+		assert!(json.is_null());
+	}
+
+	#[actix_web::test]
+	async fn multipart_duplicate_data() {
+		let payload = multipart(&[
+			("data", None, br#"{"tags":["a"]}"#),
+			("image", Some("a.png"), b"a"),
+			("data", None, br#"{"tags":["b"]}"#),
+		]);
+		let res = process_multipart_image(payload, 2, 4).await;
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+	}
+
+	#[actix_web::test]
+	async fn multipart_broken_stream() {
+		let head = format!(
+			"--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\n\r\nabc",
+			BOUNDARY
+		);
+		let payload = multipart_stream(vec![
+			Ok(bytes::Bytes::from(head)),
+			Err(PayloadError::Incomplete(None)),
+		]);
+		let res = process_multipart_image(payload, 2, 4).await;
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+	}
+
+	#[actix_web::test]
+	async fn multipart_overflowed_stream() {
+		let payload = multipart_stream(vec![Err(PayloadError::Overflow)]);
+		let res = process_multipart_image(payload, 2, 4).await;
+		assert!(matches!(res, Err(APIError::PayloadSize)));
+	}
+
+	/// Encode a `width` x `height` JPEG carrying an EXIF orientation tag
+	fn jpeg_with_orientation(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+		let image = image::DynamicImage::new_rgb8(width, height);
+		let mut jpeg = Cursor::new(Vec::new());
+		image
+			.write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))
+			.unwrap();
+		let jpeg = jpeg.into_inner();
+
+		// Little endian TIFF with a single IFD holding just the orientation
+		let mut tiff = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+		tiff.extend_from_slice(&orientation.to_le_bytes());
+		tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+		let mut app1 = b"Exif\0\0".to_vec();
+		app1.extend_from_slice(&tiff);
+
+		let mut out = jpeg[..2].to_vec();
+		out.extend_from_slice(&[0xff, 0xe1]);
+		out.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+		out.extend_from_slice(&app1);
+		out.extend_from_slice(&jpeg[2..]);
+		out
+	}
+
+	#[test]
+	fn rotated_jpeg_original_kept() {
+		let settings = RunSettings::from(&crate::settings::Settings::default());
+		let data = jpeg_with_orientation(40, 20, 6);
+		assert_eq!(exif_orientation(&data), 6);
+		let processed = process_image(data.clone(), "photo.jpeg", &settings).unwrap();
+		// Stored untouched, but recorded and thumbnailed the way it is viewed
+		assert_eq!(processed.data, data);
+		assert_eq!(processed.ext, ImageExtension::Jpg);
+		assert_eq!(processed.filename, "photo.jpg");
+		assert_eq!(processed.dimensions, (20, 40));
+	}
+
+	#[test]
+	fn unrotated_jpeg() {
+		let settings = RunSettings::from(&crate::settings::Settings::default());
+		let data = jpeg_with_orientation(40, 20, 1);
+		let processed = process_image(data.clone(), "photo.jpg", &settings).unwrap();
+		assert_eq!(processed.data, data);
+		assert_eq!(processed.dimensions, (40, 20));
+	}
+
+	#[test]
+	fn apply_orientation_dimensions() {
+		let image = image::DynamicImage::new_rgb8(4, 2);
+		for orientation in 1..=8 {
+			let dim =
+				image::GenericImageView::dimensions(&apply_orientation(image.clone(), orientation));
+			let expected = if orientation >= 5 { (2, 4) } else { (4, 2) };
+			assert_eq!(dim, expected, "orientation {}", orientation);
+		}
+	}
+
+	#[test]
+	fn apply_orientation_corners() {
+		use image::{GenericImage, GenericImageView, Rgba};
+		const MARK: Rgba<u8> = Rgba([255, 0, 0, 255]);
+		// Where the pixel shown top left is stored for each orientation
+		let stored = |orientation, (w, h): (u32, u32)| match orientation {
+			1 | 5 => (0, 0),
+			2 | 8 => (w - 1, 0),
+			3 | 7 => (w - 1, h - 1),
+			4 | 6 => (0, h - 1),
+			_ => unreachable!(),
+		};
+		for orientation in 1..=8 {
+			let mut image = image::DynamicImage::new_rgba8(3, 2);
+			let (x, y) = stored(orientation, (3, 2));
+			image.put_pixel(x, y, MARK);
+			let oriented = apply_orientation(image, orientation);
+			assert_eq!(
+				oriented.get_pixel(0, 0),
+				MARK,
+				"orientation {}",
+				orientation
+			);
+			let marked = oriented.pixels().filter(|(_, _, p)| *p == MARK).count();
+			assert_eq!(marked, 1, "orientation {}", orientation);
+		}
+	}
+
+	#[test]
+	fn every_exif_orientation() {
+		let settings = RunSettings::from(&crate::settings::Settings::default());
+		for orientation in 1..=8 {
+			let data = jpeg_with_orientation(40, 20, orientation);
+			assert_eq!(exif_orientation(&data), u32::from(orientation));
+			let processed = process_image(data, "photo.jpg", &settings).unwrap();
+			let expected = if orientation >= 5 { (20, 40) } else { (40, 20) };
+			assert_eq!(
+				processed.dimensions, expected,
+				"orientation {}",
+				orientation
+			);
+		}
+	}
+
+	#[test]
+	fn bad_exif_orientation_ignored() {
+		assert_eq!(exif_orientation(&png(4, 4)), 1);
+		let settings = RunSettings::from(&crate::settings::Settings::default());
+		for orientation in [0, 9, 0xffff] {
+			let data = jpeg_with_orientation(40, 20, orientation);
+			let processed = process_image(data, "photo.jpg", &settings).unwrap();
+			assert_eq!(
+				processed.dimensions,
+				(40, 20),
+				"orientation {}",
+				orientation
+			);
+		}
+	}
+
+	#[actix_web::test]
+	async fn replayed_upload_matches_original() {
+		let body = r#"{"id":12}"#.to_owned();
+		let original = upload_response(Some("https://example.com/post?id=12"), body.clone());
+		let stored = serde_json::json!({
+			"location": "https://example.com/post?id=12",
+			"body": body,
+		})
+		.to_string();
+		let replayed = replay_upload(&stored).unwrap();
+		assert_eq!(replayed.status(), StatusCode::CREATED);
+		assert_eq!(replayed.status(), original.status());
+		assert_eq!(
+			replayed.headers().get(header::LOCATION),
+			original.headers().get(header::LOCATION)
+		);
+		let replayed = actix_web::body::to_bytes(replayed.into_body())
+			.await
+			.unwrap();
+		assert_eq!(replayed, body.as_bytes());
+	}
+
+	#[test]
+	fn replayed_gallery_has_no_location() {
+		let stored = serde_json::json!({ "location": null, "body": "{}" }).to_string();
+		let replayed = replay_upload(&stored).unwrap();
+		assert_eq!(replayed.status(), StatusCode::CREATED);
+		assert!(replayed.headers().get(header::LOCATION).is_none());
+	}
+
+	/// Needs postgres and redis running with the default settings, the schema
+	/// installed and a user with id 1 to upload as
+	#[actix_web::test]
+	#[ignore]
+	async fn idempotent_upload_creates_one_post() {
+		use crate::auth::{AuthDb, AuthDbCreator, AuthInfo};
+		use actix_web::{dev::Service, test, web::Data, App, HttpMessage};
+
+		let mut settings = crate::settings::Settings::default();
+		let storage = std::env::temp_dir().join(format!("watame-test-{}", std::process::id()));
+		settings.storage_root = storage.display().to_string();
+		let run_settings = RunSettings::from(&settings);
+		let auth_db = AuthDbCreator::new(&settings.redis_uri, 1, "watame-test:").await;
+		let pool = crate::database::establish_pool(&mut settings);
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(pool.clone()))
+				.app_data(Data::new(AuthDb::new(auth_db)))
+				.app_data(Data::new(run_settings))
+				.wrap_fn(|req, srv| {
+					req.extensions_mut().insert(AuthInfo {
+						uid: 1,
+						perms: Perms::User,
+					});
+					srv.call(req)
+				})
+				.route("/post", web::post().to(post_upload)),
+		)
+		.await;
+
+		let mut png = Cursor::new(Vec::new());
+		image::DynamicImage::new_rgb8(8, 8)
+			.write_to(&mut png, image::ImageOutputFormat::Png)
+			.unwrap();
+		let body = multipart_body(&[
+			("image", Some("a.png"), png.get_ref()),
+			("data", None, br#"{"tags":["idempotency_test"]}"#),
+		]);
+		let key = format!("test-{}", chrono::Utc::now().timestamp_nanos());
+		let upload = || {
+			test::TestRequest::post()
+				.uri("/post")
+				.insert_header((
+					header::CONTENT_TYPE,
+					format!("multipart/form-data; boundary={}", BOUNDARY),
+				))
+				.insert_header(("Idempotency-Key", key.as_str()))
+				.set_payload(body.clone())
+				.to_request()
+		};
+
+		let conn = pool.get().await.unwrap();
+		let before: i64 = conn
+			.query_one("SELECT coalesce(max(id), 0) FROM posts", &[])
+			.await
+			.unwrap()
+			.get(0);
+
+		// Racing each other only one gets to upload, the other is told to wait
+		let (a, b) = futures::join!(
+			test::call_service(&app, upload()),
+			test::call_service(&app, upload())
+		);
+		let mut statuses = [a.status(), b.status()];
+		statuses.sort();
+		assert_eq!(statuses, [StatusCode::CREATED, StatusCode::CONFLICT]);
+		let first = if a.status() == StatusCode::CREATED {
+			a
+		} else {
+			b
+		};
+		let location = first.headers().get(header::LOCATION).cloned();
+		let first = test::read_body(first).await;
+
+		// Once it's done a retry gets the same answer back
+		let retry = test::call_service(&app, upload()).await;
+		assert_eq!(retry.status(), StatusCode::CREATED);
+		assert_eq!(retry.headers().get(header::LOCATION).cloned(), location);
+		assert_eq!(test::read_body(retry).await, first);
+
+		let created: i64 = conn
+			.query_one("SELECT count(*) FROM posts WHERE id > $1", &[&before])
+			.await
+			.unwrap()
+			.get(0);
+		assert_eq!(created, 1);
+		let _ = std::fs::remove_dir_all(storage);
+	}
+
+	/// Needs postgres, see `database::fixtures`
+	#[actix_web::test]
+	#[ignore]
+	async fn failed_store_leaves_no_rows() {
+		use crate::database::{fixtures::insert_user, user::User};
+
+		let mut settings = crate::settings::Settings::default();
+		// A file where the storage folder should be, so writing the image fails after
+		// the post has been inserted
+		let storage = std::env::temp_dir().join(format!("watame-test-file-{}", std::process::id()));
+		std::fs::write(&storage, b"").unwrap();
+		settings.storage_root = storage.display().to_string();
+		let run_settings = RunSettings::from(&settings);
+		let pool = crate::database::establish_pool(&mut settings);
+		let mut conn = pool.get().await.unwrap();
+
+		let name = format!("rollback-test-{}", std::process::id());
+		let user = insert_user::<pg::Client>(&conn, &name).await;
+
+		let image = process_image(png(8, 8), "a.png", &run_settings).unwrap();
+		let tag = name.replace('-', "_");
+		let tags = [tag.as_str()];
+		let new_post = NewPost {
+			filename: &image.filename,
+			ext: image.ext,
+			path: "00",
+			size: image.data.len() as i32,
+			dimensions: (8, 8),
+			description: "",
+			rating: Rating::Safe,
+			tags: &tags,
+			poster: user.id,
+			group_id: None,
+			created_ip: None,
+		};
+		let trans = conn.transaction().await.unwrap();
+		let res = store_post(&trans, &run_settings, &new_post, &image).await;
+		assert!(matches!(res, Err(APIError::InternalError)));
+		// Returning early drops the transaction without committing it
+		drop(trans);
+
+		let posts: i64 = conn
+			.query_one("SELECT count(*) FROM posts WHERE poster=$1", &[&user.id])
+			.await
+			.unwrap()
+			.get(0);
+		assert_eq!(posts, 0);
+		let tags: i64 = conn
+			.query_one("SELECT count(*) FROM tags WHERE name=$1", &[&tag])
+			.await
+			.unwrap()
+			.get(0);
+		assert_eq!(tags, 0);
+
+		User::delete::<pg::Client>(&conn, user.id).await.unwrap();
+		std::fs::remove_file(storage).unwrap();
+	}
+
+	#[test]
+	fn deleted_post_gone() {
+		let post = live_post(Some(post_fixture("a.png"))).unwrap();
+		assert_eq!(post.map(|p| p.id), Some(12));
+		assert!(live_post(None).unwrap().is_none());
+		let mut post = post_fixture("a.png");
+		post.is_deleted = true;
+		match live_post(Some(post)) {
+			Err(err @ APIError::Gone) => {
+				assert_eq!(
+					actix_web::ResponseError::status_code(&err),
+					StatusCode::GONE
+				)
+			}
+			_ => panic!("deleted post wasn't gone"),
+		}
+	}
+
+	#[test]
+	fn locked_posts_only_edited_by_moderators() {
+		let locked = || {
+			let mut post = post_fixture("a.png");
+			post.is_locked = true;
+			post
+		};
+		let status = |res: Result<Option<PostFull>, APIError>| match res {
+			Err(err) => actix_web::ResponseError::status_code(&err),
+			Ok(_) => panic!("edit wasn't refused"),
+		};
+		// Owners can't edit once it is locked, moderators still can
+		assert_eq!(
+			status(editable_post(Some((true, locked())), false)),
+			StatusCode::LOCKED
+		);
+		let post = editable_post(Some((true, locked())), true).unwrap();
+		assert_eq!(post.map(|p| p.id), Some(12));
+		let post = editable_post(Some((true, post_fixture("a.png"))), false).unwrap();
+		assert_eq!(post.map(|p| p.id), Some(12));
+		// Anyone else is turned away whether or not it is locked
+		assert_eq!(
+			status(editable_post(Some((false, locked())), false)),
+			StatusCode::UNAUTHORIZED
+		);
+		assert_eq!(
+			status(editable_post(Some((false, post_fixture("a.png"))), false)),
+			StatusCode::UNAUTHORIZED
+		);
+		assert!(editable_post(None, false).unwrap().is_none());
+	}
+
+	/// Needs redis running with the default settings, the guest is turned away before
+	/// the database is touched
+	#[actix_web::test]
+	#[ignore]
+	async fn guests_cannot_write() {
+		use crate::auth::{AuthDb, AuthDbCreator, AuthInfo};
+		use actix_web::{dev::Service, test, web::Data, App, HttpMessage};
+
+		let mut settings = crate::settings::Settings::default();
+		let run_settings = RunSettings::from(&settings);
+		let auth_db = AuthDbCreator::new(&settings.redis_uri, 1, "watame-test:").await;
+		let pool = crate::database::establish_pool(&mut settings);
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(pool))
+				.app_data(Data::new(AuthDb::new(auth_db)))
+				.app_data(Data::new(run_settings))
+				.wrap_fn(|req, srv| {
+					req.extensions_mut().insert(AuthInfo {
+						uid: 1,
+						perms: Perms::Guest,
+					});
+					srv.call(req)
+				})
+				.route("/post", web::post().to(post_upload))
+				.route("/post", web::delete().to(delete_post)),
+		)
+		.await;
+
+		let body = multipart_body(&[("image", Some("a.png"), &png(8, 8))]);
+		let upload = test::TestRequest::post()
+			.uri("/post")
+			.insert_header((
+				header::CONTENT_TYPE,
+				format!("multipart/form-data; boundary={}", BOUNDARY),
+			))
+			.set_payload(body)
+			.to_request();
+		let delete = test::TestRequest::delete().uri("/post?id=1").to_request();
+		for req in [upload, delete] {
+			let res = test::call_service(&app, req).await;
+			assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+			let body: serde_json::Value =
+				serde_json::from_slice(&test::read_body(res).await).unwrap();
+			assert_eq!(body["code"], "UNAUTHORISED");
+		}
+	}
+
+	/// Needs postgres and redis running, see `database::fixtures`. Handlers commit so
+	/// this cleans up after itself
+	#[actix_web::test]
+	#[ignore]
+	async fn edits_and_restores_are_audited() {
+		use crate::auth::{AuthDb, AuthDbCreator, AuthInfo};
+		use crate::database::{fixtures, user::User};
+		use actix_web::{dev::Service, test, web::Data, App, HttpMessage};
+
+		let settings = crate::settings::Settings::default();
+		let auth_db = AuthDbCreator::new(&settings.redis_uri, 1, "watame-test:").await;
+		let pool = fixtures::pool();
+		let conn = pool.get().await.unwrap();
+		let name = format!("edit-test-{}", std::process::id());
+		let post = fixtures::insert_post_fixture::<pg::Client>(&conn, &name, &[]).await;
+		let owner = post.poster;
+		let moderator = fixtures::insert_user::<pg::Client>(&conn, &format!("{}-mod", name)).await;
+		User::update_perms::<pg::Client>(&conn, moderator.id, Perms::Moderator)
+			.await
+			.unwrap();
+		let tag = format!("auditedit{}", std::process::id());
+
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(pool.clone()))
+				.app_data(Data::new(AuthDb::new(auth_db)))
+				.app_data(Data::new(RunSettings::from(&settings)))
+				// Whoever the request says it is from
+				.wrap_fn(|req, srv| {
+					let uid: i32 = req
+						.headers()
+						.get("x-uid")
+						.unwrap()
+						.to_str()
+						.unwrap()
+						.parse()
+						.unwrap();
+					let perms = if req.headers().contains_key("x-moderator") {
+						Perms::Moderator
+					} else {
+						Perms::User
+					};
+					req.extensions_mut().insert(AuthInfo { uid, perms });
+					srv.call(req)
+				})
+				.route("/post", web::patch().to(patch_post))
+				.route("/post", web::delete().to(delete_post))
+				.route("/post/lock", web::post().to(post_lock))
+				.route("/post/restore", web::post().to(post_restore)),
+		)
+		.await;
+		let as_owner = |req: test::TestRequest| req.insert_header(("x-uid", owner.to_string()));
+		let as_moderator = |req: test::TestRequest| {
+			req.insert_header(("x-uid", moderator.id.to_string()))
+				.insert_header(("x-moderator", "1"))
+		};
+		let uri = |path: &str| format!("{}?id={}", path, post.id);
+		let edit =
+			|body: serde_json::Value| test::TestRequest::patch().uri(&uri("/post")).set_json(body);
+
+		let steps = [
+			(
+				as_owner(edit(
+					serde_json::json!({ "tags": [tag], "description": "edited" }),
+				)),
+				StatusCode::OK,
+			),
+			(
+				as_moderator(
+					test::TestRequest::post().uri(&format!("{}&locked=true", uri("/post/lock"))),
+				),
+				StatusCode::OK,
+			),
+			(
+				as_owner(edit(serde_json::json!({ "description": "again" }))),
+				StatusCode::LOCKED,
+			),
+			(
+				as_moderator(edit(serde_json::json!({ "rating": "Explicit" }))),
+				StatusCode::OK,
+			),
+			(
+				as_owner(test::TestRequest::delete().uri(&uri("/post"))),
+				StatusCode::OK,
+			),
+			(
+				as_owner(test::TestRequest::post().uri(&uri("/post/restore"))),
+				StatusCode::UNAUTHORIZED,
+			),
+			(
+				as_moderator(test::TestRequest::post().uri(&uri("/post/restore"))),
+				StatusCode::OK,
+			),
+			// Only deleted posts can be restored
+			(
+				as_moderator(test::TestRequest::post().uri(&uri("/post/restore"))),
+				StatusCode::NOT_FOUND,
+			),
+		];
+		for (i, (req, status)) in steps.into_iter().enumerate() {
+			let res = test::call_service(&app, req.to_request()).await;
+			assert_eq!(res.status(), status, "step {}", i);
+		}
+
+		let history = AuditEntry::select_post_history::<pg::Client>(&conn, post.id)
+			.await
+			.unwrap();
+		let actions: Vec<_> = history.iter().map(|e| format!("{:?}", e.action)).collect();
+		assert_eq!(actions, ["Edit", "Lock", "Edit", "Delete", "Restore"]);
+		assert_eq!(history[0].uid, owner);
+		assert_eq!(history[0].detail["old"]["description"], "fixture");
+		assert_eq!(history[0].detail["new"]["tags"], serde_json::json!([tag]));
+		assert_eq!(history[2].uid, moderator.id);
+		assert_eq!(history[2].detail["new"]["rating"], "Explicit");
+		assert_eq!(history[4].uid, moderator.id);
+		// Restoring put the post back on its tag's count
+		let count: i64 = conn
+			.query_one("SELECT count FROM tags WHERE name=$1", &[&tag])
+			.await
+			.unwrap()
+			.get(0);
+		assert_eq!(count, 1);
+
+		Post(post.id).delete_now::<pg::Client>(&conn).await.unwrap();
+		conn.execute("DELETE FROM post_audit WHERE post_id=$1", &[&post.id])
+			.await
+			.unwrap();
+		conn.execute("DELETE FROM tags WHERE name=$1", &[&tag])
+			.await
+			.unwrap();
+		for uid in [owner, moderator.id] {
+			User::delete::<pg::Client>(&conn, uid).await.unwrap();
+		}
+	}
+
+	fn post_fixture(filename: &str) -> PostFull {
+		PostFull {
+			id: 12,
+			poster: 1,
+			tag_vector: crate::database::tag::TagVector(vec!["a".to_owned()]),
+			create_date: chrono::Utc::now(),
+			modified_date: chrono::Utc::now(),
+			create_ts: 0,
+			modified_ts: 0,
+			description: None,
+			rating: Rating::Safe,
+			score: 0,
+			views: 0,
+			source: None,
+			filename: filename.to_owned(),
+			path: "00".to_owned(),
+			ext: ImageExtension::Png,
+			size: 0,
+			width: 1,
+			height: 1,
+			is_deleted: false,
+			is_locked: false,
+			group_id: None,
+			created_ip: None,
+		}
+	}
+
+	#[test]
+	fn post_json_both_date_formats() {
+		use chrono::TimeZone;
+		let mut post = post_fixture("a.png");
+		post.create_date = chrono::Utc.ymd(2021, 2, 3).and_hms(4, 5, 6);
+		post.create_ts = 1612325106;
+		let json = post_json(&post, None);
+		// RFC 3339 stays the default, the epoch seconds sit alongside it
+		assert_eq!(json["create_date"], "2021-02-03T04:05:06Z");
+		assert_eq!(json["create_ts"], 1612325106);
+		assert!(json["modified_ts"].is_i64());
+
+		let raw = Some("id,create_ts".to_owned());
+		let fields = parse_fields(&raw).unwrap();
+		let json = post_json(&post, fields.as_deref());
+		assert_eq!(
+			json,
+			serde_json::json!({ "id": 12, "create_ts": 1612325106 })
+		);
+	}
+
+	#[actix_web::test]
+	async fn remove_post_files_already_missing() {
+		let root = std::env::temp_dir().join(format!("watame-missing-{}", std::process::id()));
+		let root = root.to_str().unwrap();
+		let post = post_fixture("a.png");
+		let (img, tmb) = format_paths(root, &post.path, post.id, &post.filename);
+		std::fs::create_dir_all(tmb.parent().unwrap()).unwrap();
+		std::fs::write(&tmb, b"x").unwrap();
+		// A purge that got as far as the image last time still removes the thumbnail
+		assert!(!img.exists());
+		assert!(remove_post_files(root, &post).await.is_ok());
+		assert!(!tmb.exists());
+		// And going again with both gone is fine too
+		assert!(remove_post_files(root, &post).await.is_ok());
+
+		// Anything other than the file being gone is still an error
+		std::fs::create_dir_all(&img).unwrap();
+		let res = remove_post_files(root, &post).await;
+		assert!(matches!(res, Err(APIError::InternalError)));
+		assert!(remove_if_exists(&img).await.is_err());
+		std::fs::remove_dir_all(root).unwrap();
+	}
+
+	#[actix_web::test]
+	async fn remove_files_clears_every_post() {
+		let root = std::env::temp_dir().join(format!("watame-purge-{}", std::process::id()));
+		let mut paths = Vec::new();
+		for id in 0..10 {
+			let (img, tmb) = format_paths(root.to_str().unwrap(), &image_path(id), id, "a.png");
+			for path in [img, tmb] {
+				std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+				std::fs::write(&path, b"x").unwrap();
+				paths.push(path);
+			}
+		}
+		// Already gone counts as removed
+		paths.push(root.join("img/00/missing.png"));
+		// A folder can't be removed as a file, so is reported back
+		let stuck = root.join("img/00/stuck.png");
+		std::fs::create_dir_all(&stuck).unwrap();
+		paths.push(stuck.clone());
+
+		let failed = remove_files(paths.clone().into_iter(), 3).await;
+		assert_eq!(failed, [stuck]);
+		for path in &paths[..20] {
+			assert!(!path.exists(), "{}", path.display());
+		}
+		std::fs::remove_dir_all(root).unwrap();
+	}
+
+	#[test]
+	fn image_path_bounded() {
+		assert_eq!(image_path(0), "00");
+		assert_eq!(image_path(0xffff), "00");
+		assert_eq!(image_path(0x1_0000), "01");
+		assert_eq!(image_path(0xff_0000), "ff");
+		// Past 2^24 the buckets wrap around rather than growing another digit
+		assert_eq!(image_path(0x100_0000), "00");
+		assert_eq!(image_path(0x123_4567), "23");
+		// Every bucket is one of the folders create-folders makes
+		let folders: Vec<String> = (0..256).map(|i| format!("{:02x}", i)).collect();
+		for id in [0x100_0000, 0xdead_beef, i64::MAX] {
+			assert!(folders.contains(&image_path(id)), "{}", id);
+		}
+	}
+
+	#[test]
+	fn format_paths_share_bucket() {
+		let id = 0x100_0000 + 0x2_0000;
+		let (img, tmb) = format_paths("/s", &image_path(id), id, "a.png");
+		assert_eq!(img, PathBuf::from(format!("/s/img/02/{}-a.png", id)));
+		assert_eq!(tmb, PathBuf::from(format!("/s/tmb/02/{}.jpg", id)));
+	}
+
+	#[test]
+	fn image_url_encodes_filename() {
+		let post = post_fixture("a b#c?d%e/f.png");
+		assert_eq!(
+			image_url("https://example.com", &post),
+			"https://example.com/s/img/00/12-a%20b%23c%3Fd%25e%2Ff.png"
+		);
+		let post = post_fixture("猫.png");
+		assert_eq!(image_url("", &post), "/s/img/00/12-%E7%8C%AB.png");
+		let post = post_fixture("plain-name_1.png");
+		assert_eq!(image_url("", &post), "/s/img/00/12-plain-name_1.png");
+	}
+
+	fn png(width: u32, height: u32) -> Vec<u8> {
+		let image = image::RgbImage::from_fn(width, height, |x, y| {
+			image::Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+		});
+		let mut png = Cursor::new(Vec::new());
+		image::DynamicImage::ImageRgb8(image)
+			.write_to(&mut png, image::ImageOutputFormat::Png)
+			.unwrap();
+		png.into_inner()
+	}
+
+	#[test]
+	fn normalise_filename_replaces_extension() {
+		for (claimed, stored) in [
+			("evil.php", "evil.png"),
+			("photo.jpg", "photo.png"),
+			("photo.PNG", "photo.png"),
+			("archive.tar.gz", "archive.tar.png"),
+			("noext", "noext.png"),
+			("trailing.", "trailing.png"),
+			("", "image.png"),
+			("..", "image.png"),
+		] {
+			assert_eq!(normalise_filename(claimed, ImageExtension::Png), stored);
+		}
+	}
+
+	#[test]
+	fn claimed_extension_ignored() {
+		let settings = RunSettings::from(&crate::settings::Settings::default());
+		let processed = process_image(png(4, 4), "evil.php", &settings).unwrap();
+		assert_eq!(processed.ext, ImageExtension::Png);
+		assert_eq!(processed.filename, "evil.png");
+		let processed = process_image(png(4, 4), "picture", &settings).unwrap();
+		assert_eq!(processed.filename, "picture.png");
+		let data = jpeg_with_orientation(4, 4, 1);
+		let processed = process_image(data, "photo.png", &settings).unwrap();
+		assert_eq!(processed.ext, ImageExtension::Jpg);
+		assert_eq!(processed.filename, "photo.jpg");
+	}
+
+	#[test]
+	fn not_an_image() {
+		let settings = RunSettings::from(&crate::settings::Settings::default());
+		let res = process_image(b"<?php echo 1; ?>".to_vec(), "image.png", &settings);
+		assert!(matches!(res, Err(APIError::MimeType)));
+	}
+
+	#[test]
+	fn png_transcoded_to_webp() {
+		let mut settings = RunSettings::from(&crate::settings::Settings::default());
+		settings.transcode_to = Some(ImageExtension::Webp);
+		let processed = process_image(png(40, 20), "image.png", &settings).unwrap();
+		assert_eq!(processed.ext, ImageExtension::Webp);
+		assert_eq!(processed.filename, "image.webp");
+		assert_eq!(processed.dimensions, (40, 20));
+
+		let decoded = webp::Decoder::new(&processed.data).decode().unwrap();
+		assert_eq!((decoded.width(), decoded.height()), (40, 20));
+		assert_eq!(
+			image::guess_format(&processed.data).unwrap(),
+			image::ImageFormat::WebP
+		);
+	}
+
+	#[test]
+	fn webp_too_large_rejected() {
+		let mut settings = RunSettings::from(&crate::settings::Settings::default());
+		settings.transcode_to = Some(ImageExtension::Webp);
+		let res = process_image(png(WEBP_MAX_DIMENSION + 1, 1), "wide.png", &settings);
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+		// Left alone without transcoding
+		settings.transcode_to = None;
+		let processed =
+			process_image(png(WEBP_MAX_DIMENSION + 1, 1), "wide.png", &settings).unwrap();
+		assert_eq!(processed.ext, ImageExtension::Png);
+	}
+
+	const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+	fn checksum(json: serde_json::Value) -> Checksums {
+		let details: NewPostDetails =
+			serde_json::from_value(serde_json::json!({ "tags": [], "checksum": json })).unwrap();
+		details.checksum.unwrap()
+	}
+
+	#[test]
+	fn checksum_matches() {
+		let images = [(b"abc".to_vec(), "a.png".to_owned())];
+		assert!(checksum(ABC_SHA256.into()).verify(&images).is_ok());
+		// Case and surrounding whitespace don't matter
+		let shouty = format!(" {} ", ABC_SHA256.to_uppercase());
+		assert!(checksum(shouty.into()).verify(&images).is_ok());
+	}
+
+	#[test]
+	fn checksum_wrong() {
+		let images = [(b"abc".to_vec(), "a.png".to_owned())];
+		let wrong = ABC_SHA256.replace('b', "c");
+		let res = checksum(wrong.into()).verify(&images);
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+		// A truncated upload hashes differently
+		let truncated = [(b"ab".to_vec(), "a.png".to_owned())];
+		let res = checksum(ABC_SHA256.into()).verify(&truncated);
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+	}
+
+	#[test]
+	fn checksum_per_image() {
+		let images = [
+			(b"abc".to_vec(), "a.png".to_owned()),
+			(b"abc".to_vec(), "b.png".to_owned()),
+		];
+		let both = checksum(serde_json::json!([ABC_SHA256, ABC_SHA256]));
+		assert!(both.verify(&images).is_ok());
+		// Every image needs its own checksum
+		let res = checksum(ABC_SHA256.into()).verify(&images);
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+		let res = checksum(serde_json::json!([ABC_SHA256])).verify(&images);
+		assert!(matches!(res, Err(APIError::BadRequestData)));
+	}
+
+	fn details(tags: &[&str], description: &str) -> NewPostDetails {
+		NewPostDetails {
+			tags: tags.iter().map(|t| t.to_string()).collect(),
+			description: description.to_owned(),
+			rating: Rating::Safe,
+			checksum: None,
+		}
+	}
+
+	#[test]
+	fn description_limit() {
+		assert!(details(&[], &"d".repeat(limits::DESCRIPTION_MAX)).within_limits());
+		assert!(!details(&[], &"d".repeat(limits::DESCRIPTION_MAX + 1)).within_limits());
+	}
+
+	#[test]
+	fn tag_limit() {
+		let longest = "t".repeat(limits::TAG_MAX);
+		assert_eq!(
+			details(&[&longest], "").clean_tags().unwrap(),
+			[longest.as_str()]
+		);
+		// Surrounding whitespace doesn't count towards it
+		let padded = format!("  {}  ", longest);
+		assert!(details(&[&padded], "").clean_tags().is_ok());
+		let over = "t".repeat(limits::TAG_MAX + 1);
+		assert!(matches!(
+			details(&["fine", &over], "").clean_tags(),
+			Err(APIError::BadRequestData)
+		));
+	}
+
+	#[test]
+	fn clean_tags_drops_blanks_and_repeats() {
+		let repeated = details(&["a", " b ", "", "a", "b"], "");
+		assert_eq!(repeated.clean_tags().unwrap(), ["a", "b"]);
+		assert!(matches!(
+			details(&["a+b"], "").clean_tags(),
+			Err(APIError::BadTags)
+		));
+		assert!(matches!(
+			details(&["!a"], "").clean_tags(),
+			Err(APIError::BadTags)
+		));
+	}
+}
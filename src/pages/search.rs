@@ -1,7 +1,25 @@
-use crate::database::{pg, post::Post, Pool as DbPool};
+use crate::auth::MaybeAuthenticated;
+use crate::database::{
+	enums::Rating,
+	pg,
+	post::{Post, PostFull, SearchFilter, Timestamp},
+	tag::Tag,
+	user::User,
+	Pool as DbPool,
+};
+use crate::pages::post::{parse_fields, post_json};
+use crate::pages::{enum_param, is_moderator, number_param, with_timeout};
+use crate::settings::RunSettings;
 use crate::{error::APIError, try500};
 
 use actix_web::{http::header, web, HttpResponse};
+use serde::{
+	de::{
+		value::{self, StrDeserializer},
+		Error as _, IntoDeserializer,
+	},
+	Deserialize, Deserializer,
+};
 
 #[derive(Debug, Copy, Clone, serde::Deserialize)]
 pub enum PostSorting {
@@ -9,13 +27,31 @@ pub enum PostSorting {
 	DateAscending,
 	#[serde(rename = "dd")]
 	DateDescending,
+	/// Last changed, such as by being retagged or restored
+	#[serde(rename = "ma")]
+	ModifiedAscending,
+	#[serde(rename = "md")]
+	ModifiedDescending,
 	#[serde(rename = "va")]
 	VoteAscending,
 	#[serde(rename = "vd")]
 	VoteDescending,
+	/// Score decayed by age so newer well voted posts come first
+	#[serde(rename = "hot")]
+	Hot,
 }
 
-fn default_tags() -> String {
+/// How searches treat deleted posts, only moderators may see them
+#[derive(Debug, Copy, Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedMode {
+	#[default]
+	Exclude,
+	Include,
+	Only,
+}
+
+pub fn default_tags() -> String {
 	"[]".into()
 }
 fn default_page() -> u32 {
@@ -24,51 +60,229 @@ fn default_page() -> u32 {
 fn default_limit() -> u32 {
 	20
 }
-fn default_sort() -> PostSorting {
+pub fn default_sort() -> PostSorting {
 	PostSorting::DateDescending
 }
 
+/// Deserialise a comma separated list of ratings such as `Safe,Sketchy`
+pub fn deserialize_ratings<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Rating>, D::Error> {
+	let raw = String::deserialize(d)?;
+	raw.split(',')
+		.map(str::trim)
+		.filter(|r| !r.is_empty())
+		.map(|r| {
+			let de: StrDeserializer<'_, value::Error> = r.into_deserializer();
+			Rating::deserialize(de).map_err(|e| D::Error::custom(format_args!("ratings: {}", e)))
+		})
+		.collect()
+}
+
+pub fn deserialize_sort<'de, D: Deserializer<'de>>(d: D) -> Result<PostSorting, D::Error> {
+	enum_param(d, "sort")
+}
+
+pub fn deserialize_page<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+	number_param(d, "page")
+}
+
+pub fn deserialize_limit<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+	number_param(d, "limit")
+}
+
+fn deserialize_min_score<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i32>, D::Error> {
+	number_param(d, "min_score").map(Some)
+}
+
+fn deserialize_min_views<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i32>, D::Error> {
+	number_param(d, "min_views").map(Some)
+}
+
+fn deserialize_deleted<'de, D: Deserializer<'de>>(d: D) -> Result<DeletedMode, D::Error> {
+	enum_param(d, "deleted")
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct SearchPostQuery {
 	#[serde(alias = "t", default = "default_tags")]
 	tags: String,
-	#[serde(alias = "p", default = "default_page")]
+	#[serde(default, deserialize_with = "deserialize_ratings")]
+	ratings: Vec<Rating>,
+	#[serde(default)]
+	untagged: bool,
+	/// Match posts with exactly the given tags
+	#[serde(default)]
+	exact: bool,
+	/// Also report which of the searched tags don't exist, this costs an extra query
+	#[serde(default)]
+	unknown_tags: bool,
+	/// Ignored unless a moderator is searching
+	#[serde(default, deserialize_with = "deserialize_deleted")]
+	deleted: DeletedMode,
+	/// Leave out posts scoring or viewed less than this
+	#[serde(default, deserialize_with = "deserialize_min_score")]
+	min_score: Option<i32>,
+	#[serde(default, deserialize_with = "deserialize_min_views")]
+	min_views: Option<i32>,
+	/// Comma separated list of the post fields to send back
+	fields: Option<String>,
+	#[serde(
+		alias = "p",
+		default = "default_page",
+		deserialize_with = "deserialize_page"
+	)]
 	page: u32,
-	#[serde(alias = "l", default = "default_limit")]
+	#[serde(
+		alias = "l",
+		default = "default_limit",
+		deserialize_with = "deserialize_limit"
+	)]
 	limit: u32,
-	#[serde(alias = "s", default = "default_sort")]
+	#[serde(
+		alias = "s",
+		default = "default_sort",
+		deserialize_with = "deserialize_sort"
+	)]
 	sort: PostSorting,
 }
 
+/// Parse the JSON array of tags used by search style queries, dropping any
+/// that are blank
+pub fn parse_tags(raw: &str, max_tags: usize) -> Result<Vec<&str>, APIError> {
+	let mut tags: Vec<&str> = serde_json::from_str(raw).map_err(|_| APIError::BadRequestData)?;
+	tags = tags.into_iter().map(str::trim).collect();
+	tags.retain(|t| !t.is_empty());
+	if tags.len() > max_tags {
+		return Err(APIError::TagLimit);
+	}
+	// A prefix search needs something to be a prefix of
+	if tags
+		.iter()
+		.any(|t| t.trim_start_matches('!').trim_end_matches('*').is_empty())
+	{
+		return Err(APIError::BadRequestData);
+	}
+	Ok(tags)
+}
+
+/// Work out which ratings to search, ratings asked for in the request win over the
+/// users saved preference, which wins over the instance default
+pub async fn resolve_ratings<C: pg::GenericClient>(
+	client: &C,
+	requested: &[Rating],
+	auth: &MaybeAuthenticated,
+	settings: &RunSettings,
+) -> Result<Vec<Rating>, APIError> {
+	if !requested.is_empty() {
+		return Ok(requested.to_vec());
+	}
+	if let Some(info) = auth.as_ref() {
+		let saved = try500!(
+			User::select_rating_filter(client, info.uid).await,
+			"resolve_ratings:select_rating_filter {}",
+			info.uid
+		);
+		if let Some(saved) = saved.filter(|r| !r.is_empty()) {
+			return Ok(saved);
+		}
+	}
+	Ok(settings.default_ratings.clone())
+}
+
 pub async fn get_search(
 	query: web::Query<SearchPostQuery>,
 	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: MaybeAuthenticated,
 ) -> Result<HttpResponse, APIError> {
-	let mut tags: Vec<&str> =
-		serde_json::from_str(&query.tags).map_err(|_| APIError::BadRequestData)?;
-	for i in 0..tags.len() {
-		tags[i] = tags[i].trim();
-		if tags[i].is_empty() {
-			tags.remove(i);
-		}
-	}
-	if tags.len() > 10 {
-		return Err(APIError::TagLimit);
-	}
-	if query.limit > 50 {
+	let tags = parse_tags(&query.tags, settings.max_search_tags)?;
+	if query.limit > settings.max_search_limit {
 		return Err(APIError::PageSize);
 	}
+	let fields = parse_fields(&query.fields)?;
 
 	// Query database for post
-	let conn = try500!(pool.get().await, "get_search:db pool");
-	let posts = try500!(
-		Post::select_fulltext_tags::<pg::Client>(&conn, &tags, query.page, query.limit, query.sort)
-			.await,
+	let timeout = settings.query_timeout;
+	let conn = try500!(
+		with_timeout(timeout, pool.get()).await?,
+		"get_search:db pool"
+	);
+	let ratings = with_timeout(
+		timeout,
+		resolve_ratings(&**conn, &query.ratings, &auth, &settings),
+	)
+	.await??;
+	let search = SearchFilter {
+		tags: &tags,
+		ratings: &ratings,
+		untagged: query.untagged,
+		exact: query.exact,
+		deleted: if is_moderator(&auth) {
+			query.deleted
+		} else {
+			DeletedMode::Exclude
+		},
+		min_score: query.min_score,
+		min_views: query.min_views,
+	};
+	let mut posts = try500!(
+		with_timeout(
+			timeout,
+			Post::select_fulltext_tags::<pg::Client>(
+				&conn,
+				&settings.ts_config,
+				&search,
+				query.page,
+				query.limit,
+				query.sort
+			)
+		)
+		.await?,
 		"get_search:select_fulltext_tags {:?}",
 		query
 	);
 
-	if posts.len() == 0 {
+	if !is_moderator(&auth) {
+		posts.iter_mut().for_each(PostFull::hide_moderation_info);
+	}
+	let posts: Vec<serde_json::Value> = posts
+		.iter()
+		.map(|p| post_json(p, fields.as_deref()))
+		.collect();
+
+	// Only wrap the results up when asked to, so existing clients keep their array
+	if query.unknown_tags {
+		// Prefix tags aren't names of tags so can't be unknown
+		let names: Vec<&str> = tags
+			.iter()
+			.map(|t| t.trim_start_matches('!'))
+			.filter(|t| !t.ends_with('*'))
+			.collect();
+		let existing = try500!(
+			with_timeout(timeout, Tag::existing_names::<pg::Client>(&conn, &names)).await?,
+			"get_search:existing_names {:?}",
+			names
+		);
+		let unknown: Vec<&str> = names
+			.into_iter()
+			.filter(|n| !existing.iter().any(|e| e == n))
+			.collect();
+		let (mut res, body) = if posts.is_empty() {
+			(
+				HttpResponse::NotFound(),
+				serde_json::json!({ "error": "no posts found", "unknown_tags": unknown }),
+			)
+		} else {
+			(
+				HttpResponse::Ok(),
+				serde_json::json!({ "posts": posts, "unknown_tags": unknown }),
+			)
+		};
+		return Ok(res
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(body.to_string()));
+	}
+
+	if posts.is_empty() {
 		Ok(HttpResponse::NotFound()
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 			.body(r#"{"error":"no posts found"}"#))
@@ -79,21 +293,203 @@ pub async fn get_search(
 	}
 }
 
-pub async fn get_random_post(pool: web::Data<DbPool>) -> Result<HttpResponse, APIError> {
+#[derive(Debug, serde::Deserialize)]
+pub struct RandomPostQuery {
+	#[serde(alias = "t", default = "default_tags")]
+	tags: String,
+	#[serde(default, deserialize_with = "deserialize_ratings")]
+	ratings: Vec<Rating>,
+}
+
+pub async fn get_random_post(
+	query: web::Query<RandomPostQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: MaybeAuthenticated,
+) -> Result<HttpResponse, APIError> {
+	let tags = parse_tags(&query.tags, settings.max_search_tags)?;
+
 	// Query database for post
 	let conn = try500!(pool.get().await, "get_search:db pool");
+	let ratings = resolve_ratings(&**conn, &query.ratings, &auth, &settings).await?;
+	let search = SearchFilter {
+		tags: &tags,
+		ratings: &ratings,
+		..Default::default()
+	};
 	let post = try500!(
-		Post::select_post_random::<pg::Client>(&conn).await,
-		"get_random_post:select_post_random"
+		Post::select_post_random_filtered::<pg::Client>(&conn, &settings.ts_config, &search).await,
+		"get_random_post:select_post_random_filtered {:?}",
+		query
 	);
 
 	// Check to see if we actually found a post
 	match post {
-		Some(x) => Ok(HttpResponse::Ok()
-			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-			.body(serde_json::to_string(x.as_full()).unwrap())),
+		Some(mut x) => {
+			if !is_moderator(&auth) {
+				x.hide_moderation_info();
+			}
+			Ok(HttpResponse::Ok()
+				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+				.body(serde_json::to_string(&x).unwrap()))
+		}
 		None => Ok(HttpResponse::NotFound()
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 			.body(r#"{"error":"no posts found"}"#)),
 	}
 }
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChangesQuery {
+	since: Timestamp,
+	#[serde(
+		alias = "l",
+		default = "default_limit",
+		deserialize_with = "deserialize_limit"
+	)]
+	limit: u32,
+}
+
+pub async fn get_changes(
+	query: web::Query<ChangesQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: MaybeAuthenticated,
+) -> Result<HttpResponse, APIError> {
+	if query.limit > settings.max_search_limit {
+		return Err(APIError::PageSize);
+	}
+
+	let conn = try500!(pool.get().await, "get_changes:db pool");
+	let mut posts = try500!(
+		Post::select_modified_since::<pg::Client>(&conn, query.since, query.limit).await,
+		"get_changes:select_modified_since {:?}",
+		query
+	);
+	if !is_moderator(&auth) {
+		posts.iter_mut().for_each(PostFull::hide_moderation_info);
+	}
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::to_string(&posts).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actix_web::{http::StatusCode, test, web::QueryConfig, App, HttpResponse};
+
+	async fn search_error(query: &str) -> (StatusCode, serde_json::Value) {
+		let app = test::init_service(
+			App::new()
+				.app_data(QueryConfig::default().error_handler(crate::pages::query_error))
+				.route(
+					"/search",
+					web::get()
+						.to(|_: web::Query<SearchPostQuery>| async { HttpResponse::Ok().finish() }),
+				),
+		)
+		.await;
+		let req = test::TestRequest::get()
+			.uri(&format!("/search?{}", query))
+			.to_request();
+		let res = test::call_service(&app, req).await;
+		let status = res.status();
+		let body = test::read_body(res).await;
+		(status, serde_json::from_slice(&body).unwrap_or_default())
+	}
+
+	#[actix_web::test]
+	async fn search_bad_limit() {
+		let (status, body) = search_error("limit=abc").await;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+		assert_eq!(body["code"], "INVALID_QUERY");
+		assert_eq!(
+			body["error"],
+			"invalid query parameter: limit: invalid digit found in string"
+		);
+	}
+
+	#[actix_web::test]
+	async fn search_bad_numbers_name_the_parameter() {
+		for param in ["page", "p", "l", "min_score", "min_views"] {
+			let (status, body) = search_error(&format!("{}=abc", param)).await;
+			assert_eq!(status, StatusCode::BAD_REQUEST, "{}", param);
+			let error = body["error"].as_str().unwrap();
+			let name = match param {
+				"p" => "page",
+				"l" => "limit",
+				name => name,
+			};
+			assert!(
+				error.starts_with(&format!("invalid query parameter: {}: ", name)),
+				"{}",
+				error
+			);
+		}
+	}
+
+	#[actix_web::test]
+	async fn search_good_numbers() {
+		let (status, _) = search_error("limit=5&page=2&min_score=-3&min_views=%2010").await;
+		assert_eq!(status, StatusCode::OK);
+		let (status, _) = search_error("").await;
+		assert_eq!(status, StatusCode::OK);
+	}
+
+	#[actix_web::test]
+	async fn modified_sorts() {
+		use crate::database::enums::{SortColumn, SortDirection};
+		for (raw, direction) in [
+			("ma", SortDirection::Ascending),
+			("md", SortDirection::Descending),
+		] {
+			let query =
+				web::Query::<SearchPostQuery>::from_query(&format!("sort={}", raw)).unwrap();
+			let (column, dir) = query.sort.order();
+			assert!(matches!(column, SortColumn::ModifiedDate), "{}", raw);
+			assert_eq!(dir, direction);
+		}
+	}
+
+	#[actix_web::test]
+	async fn search_bad_enums() {
+		for (query, error) in [
+			("sort=zz", "sort: unknown variant `zz`"),
+			("s=zz", "sort: unknown variant `zz`"),
+			("ratings=Spicy", "ratings: unknown variant `Spicy`"),
+			("ratings=Safe,Spicy", "ratings: unknown variant `Spicy`"),
+			("deleted=sometimes", "deleted: unknown variant `sometimes`"),
+		] {
+			let (status, body) = search_error(query).await;
+			assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+			assert_eq!(body["code"], "INVALID_QUERY", "{}", query);
+			let message = body["error"].as_str().unwrap();
+			assert!(
+				message.starts_with(&format!("invalid query parameter: {}", error)),
+				"{} gave {}",
+				query,
+				message
+			);
+		}
+	}
+
+	#[actix_web::test]
+	async fn search_good_enums() {
+		for query in [
+			"sort=vd",
+			"sort=%20hot%20",
+			"ratings=Safe,%20Explicit,",
+			"deleted=only",
+		] {
+			let (status, _) = search_error(query).await;
+			assert_eq!(status, StatusCode::OK, "{}", query);
+		}
+		let query = web::Query::<SearchPostQuery>::from_query("ratings=Safe,Explicit").unwrap();
+		assert!(matches!(
+			query.ratings[..],
+			[Rating::Safe, Rating::Explicit]
+		));
+	}
+}
@@ -0,0 +1,32 @@
+use crate::auth::AuthDb;
+use crate::database::{pg, stats::Stats, Pool as DbPool};
+use crate::{error::APIError, try500};
+
+use actix_web::{http::header, web, HttpResponse};
+
+// How long the aggregated stats are kept in redis before being recomputed
+const STATS_TTL: u64 = 60;
+
+pub async fn get_stats(
+	pool: web::Data<DbPool>,
+	auth_db: web::Data<AuthDb>,
+) -> Result<HttpResponse, APIError> {
+	// The sums touch every post, so serve a cached copy when we have one
+	let body = match auth_db.cache_get("cache:stats").await? {
+		Some(body) => body,
+		None => {
+			let conn = try500!(pool.get().await, "get_stats:db pool");
+			let stats = try500!(
+				Stats::select_stats::<pg::Client>(&conn).await,
+				"get_stats:select_stats"
+			);
+			let body = serde_json::to_string(&stats).unwrap();
+			auth_db.cache_set("cache:stats", &body, STATS_TTL).await?;
+			body
+		}
+	};
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(body))
+}
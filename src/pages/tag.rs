@@ -1,4 +1,6 @@
-use crate::database::{pg, tag::Tag, Pool as DbPool};
+use crate::auth::Authenticated;
+use crate::database::{enums::Perms, pg, tag::Tag, Pool as DbPool};
+use crate::settings::RunSettings;
 use crate::{error::APIError, try500};
 
 use actix_web::{http::header, web, HttpResponse};
@@ -31,3 +33,126 @@ pub async fn get_info(
 			.body(r#"{"error":"tag not found"}"#)),
 	}
 }
+
+#[derive(serde::Deserialize)]
+pub struct TagListQuery {
+	names: String,
+}
+
+pub async fn get_infos(
+	query: web::Query<TagListQuery>,
+	pool: web::Data<DbPool>,
+) -> Result<HttpResponse, APIError> {
+	const MAX_NAMES: usize = 100;
+
+	let mut names: Vec<&str> = query.names.split(',').map(str::trim).collect();
+	names.retain(|n| !n.is_empty());
+	if names.len() > MAX_NAMES {
+		return Err(APIError::TagLimit);
+	}
+
+	// Query database for the tags
+	let conn = try500!(pool.get().await, "get_tags:db pool");
+	let tags = try500!(
+		Tag::select_tag_names::<pg::Client>(&conn, &names).await,
+		"get_tags:select_tag_names {:?}",
+		names
+	);
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::to_string(&tags).unwrap()))
+}
+
+#[derive(Debug, Copy, Clone, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagSorting {
+	Count,
+	Name,
+}
+
+fn default_page() -> u32 {
+	0
+}
+fn default_limit() -> u32 {
+	20
+}
+fn default_sort() -> TagSorting {
+	TagSorting::Count
+}
+
+fn deserialize_sort<'de, D: serde::Deserializer<'de>>(d: D) -> Result<TagSorting, D::Error> {
+	super::enum_param(d, "sort")
+}
+
+#[derive(serde::Deserialize)]
+pub struct TagPageQuery {
+	#[serde(
+		alias = "p",
+		default = "default_page",
+		deserialize_with = "super::search::deserialize_page"
+	)]
+	page: u32,
+	#[serde(
+		alias = "l",
+		default = "default_limit",
+		deserialize_with = "super::search::deserialize_limit"
+	)]
+	limit: u32,
+	#[serde(
+		alias = "s",
+		default = "default_sort",
+		deserialize_with = "deserialize_sort"
+	)]
+	sort: TagSorting,
+}
+
+pub async fn get_list(
+	query: web::Query<TagPageQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+) -> Result<HttpResponse, APIError> {
+	if query.limit > settings.max_search_limit {
+		return Err(APIError::PageSize);
+	}
+
+	let conn = try500!(pool.get().await, "get_list:db pool");
+	let tags = try500!(
+		Tag::select_page::<pg::Client>(&conn, query.page, query.limit, query.sort).await,
+		"get_list:select_page {} {} {:?}",
+		query.page,
+		query.limit,
+		query.sort
+	);
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::to_string(&tags).unwrap()))
+}
+
+pub async fn post_recount(
+	query: web::Query<TagInfoQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if auth.perms != Perms::Admin {
+		return Err(APIError::Auth);
+	}
+
+	let conn = try500!(pool.get().await, "post_recount:db pool");
+	let counts = try500!(
+		Tag::recount_one::<pg::Client>(&conn, &settings.ts_config, &query.name).await,
+		"post_recount:recount_one {}",
+		query.name
+	);
+
+	match counts {
+		Some((old, new)) => Ok(HttpResponse::Ok()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(serde_json::json!({ "name": query.name, "old": old, "new": new }).to_string())),
+		None => Ok(HttpResponse::NotFound()
+			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+			.body(r#"{"error":"tag not found"}"#)),
+	}
+}
@@ -1,16 +1,22 @@
-use crate::auth::{AuthDb, Authenticated, MaybeAuthenticated};
+use crate::auth::{self, AuthDb, AuthInfo, Authenticated, MaybeAuthenticated};
 use crate::database::{
-	enums::Perms,
+	enums::{Perms, Rating},
+	invite::Invite,
 	pg,
-	user::{NewUser, User},
+	user::{NewUser, User, DELETED_USER},
 	Pool as DbPool,
 };
-use crate::{error::APIError, try500};
+use crate::pages::no_store;
+use crate::proxy::client_ip;
+use crate::settings::{PasswordPolicy, RunSettings, UserDeletePolicy};
+use crate::{error::APIError, limits, try500};
 
 use actix_web::{http::header, web, HttpRequest, HttpResponse};
 use argon2::{self, Config};
 use rand::Rng;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
 
 #[derive(Serialize)]
 pub struct UserAPI {
@@ -19,6 +25,10 @@ pub struct UserAPI {
 	pub email: Option<String>,
 	pub picture: String,
 	pub perms: Perms,
+	pub default_rating_filter: Vec<Rating>,
+	/// Where the account was registered from, only ever shown to moderators
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub created_ip: Option<IpAddr>,
 }
 
 impl core::convert::From<User> for UserAPI {
@@ -29,48 +39,116 @@ impl core::convert::From<User> for UserAPI {
 			email: u.email,
 			picture: u.picture,
 			perms: u.perms,
+			default_rating_filter: u.default_rating_filter,
+			created_ip: u.created_ip,
 		}
 	}
 }
 
+impl UserAPI {
+	/// Turn a picture path relative to the server into an absolute URL
+	fn with_public_url(mut self, public_url: &str) -> Self {
+		if self.picture.starts_with('/') {
+			self.picture.insert_str(0, public_url);
+		}
+		self
+	}
+
+	/// Strip out anything only moderators are allowed to see, unless `perms` is one
+	fn visible_to(mut self, perms: Perms) -> Self {
+		if !matches!(perms, Perms::Moderator | Perms::Admin) {
+			self.created_ip = None;
+		}
+		self
+	}
+}
+
+/// Check a new password against the configured policy, saying which rule it broke
+fn validate_password(pass: &str, policy: &PasswordPolicy) -> Result<(), APIError> {
+	let length = pass.chars().count();
+	if length < policy.min_length {
+		return Err(APIError::BadPassword("it is too short"));
+	}
+	if length > policy.max_length {
+		return Err(APIError::BadPassword("it is too long"));
+	}
+	if policy.require_digit && !pass.chars().any(|c| c.is_ascii_digit()) {
+		return Err(APIError::BadPassword("it needs a digit"));
+	}
+	if policy.require_upper && !pass.chars().any(char::is_uppercase) {
+		return Err(APIError::BadPassword("it needs an uppercase letter"));
+	}
+	if policy.require_symbol && pass.chars().all(char::is_alphanumeric) {
+		return Err(APIError::BadPassword("it needs a symbol"));
+	}
+	Ok(())
+}
+
+/// Hash a new password for storing with a fresh salt, this is the only way a
+/// password gets set so it is checked against the policy here
+pub fn hash_password(pass: &str, policy: &PasswordPolicy) -> Result<String, APIError> {
+	validate_password(pass, policy)?;
+	let config = Config::default();
+	let salt = rand::thread_rng().gen::<[u8; 16]>(); // yell at me later
+	Ok(argon2::hash_encoded(pass.as_bytes(), &salt, &config).unwrap())
+}
+
 #[derive(serde::Deserialize)]
 pub struct RegisterUserQuery {
 	user: String,
 	pass: String,
 	email: String,
+	invite: Option<String>,
+}
+
+impl RegisterUserQuery {
+	fn within_limits(&self) -> bool {
+		limits::within(&self.user, limits::USERNAME_MIN, limits::USERNAME_MAX)
+			&& limits::within(&self.email, 0, limits::EMAIL_MAX)
+			&& self
+				.invite
+				.as_ref()
+				.is_none_or(|i| limits::within(i, 0, limits::INVITE_MAX))
+	}
 }
 
 pub async fn post_register(
+	req: HttpRequest,
 	query: web::Json<RegisterUserQuery>,
 	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
 ) -> Result<HttpResponse, APIError> {
-	// Check that none of fields are reasonable sizes
-	if query.user.len() <= 3 || query.pass.len() < 8 {
+	// Check that all of the fields are reasonable sizes
+	if !query.within_limits() {
 		return Err(APIError::BadRequestData);
 	}
+	// Closed instances only take people who have been invited
+	let invite = match (settings.registration_open, &query.invite) {
+		(true, _) => None,
+		(false, Some(code)) => Some(code.as_str()),
+		(false, None) => return Err(APIError::RegistrationClosed),
+	};
 	// Check that email looks valid
 	/* if email is valid {
 		// this will be of concern later, for testing is fine
 	} */
+	let hash = hash_password(&query.pass, &settings.password_policy)?;
 	// Check that the username or email haven't been used before
 	let mut conn = try500!(pool.get().await, "post_register:db pool");
 	let trans = try500!(conn.transaction().await);
-	if try500!(
+	if let Some(field) = try500!(
 		User::check_existence::<pg::Transaction<'_>>(&trans, &query.user, Some(&query.email)).await,
 		"post_register:check_existence"
 	) {
-		return Err(APIError::UserExists);
+		return Err(APIError::UserExists(field));
 	}
 
-	let config = Config::default();
-	let salt = rand::thread_rng().gen::<[u8; 16]>(); // yell at me later
-	let hash = argon2::hash_encoded(&query.pass.as_bytes(), &salt, &config).unwrap();
-
 	let new_user = NewUser {
 		name: &query.user,
 		email: Some(&query.email),
 		pass: &hash,
-		picture: None,
+		picture: &settings.default_pfp,
+		created_ip: client_ip(&req, &settings.trusted_proxies),
 	};
 
 	let user = try500!(
@@ -78,12 +156,27 @@ pub async fn post_register(
 		"post_register:insert_into {:?}",
 		new_user
 	);
-	let user: UserAPI = user.into();
+	if let Some(code) = invite {
+		let claimed = try500!(
+			Invite::claim::<pg::Transaction<'_>>(&trans, code, user.id).await,
+			"post_register:claim invite"
+		);
+		// Dropping the transaction takes the new user back out
+		if !claimed {
+			return Err(APIError::RegistrationClosed);
+		}
+	}
+	let perms = user.perms;
+	let user = UserAPI::from(user)
+		.with_public_url(&settings.public_url)
+		.visible_to(perms);
 
 	// Commit our transaction
 	try500!(trans.commit().await);
 
-	Ok(HttpResponse::Ok()
+	// Users can only be fetched as themselves once they have logged in
+	Ok(no_store(HttpResponse::Created())
+		.append_header((header::LOCATION, format!("{}/user", settings.public_url)))
 		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 		.body(serde_json::to_vec(&user).unwrap()))
 }
@@ -95,10 +188,27 @@ pub struct LoginUserQuery {
 }
 
 pub async fn post_login(
+	req: HttpRequest,
 	pool: web::Data<DbPool>,
 	auth_db: web::Data<AuthDb>,
+	settings: web::Data<RunSettings>,
 	query: web::Json<LoginUserQuery>,
 ) -> Result<HttpResponse, APIError> {
+	// Attempts are tracked per account and per address until one succeeds, the
+	// username is hashed so the names people try don't end up sitting in redis
+	let name_hash = Sha256::digest(query.user.to_lowercase().as_bytes());
+	let mut failure_keys = vec![format!("login_fail:name:{:x}", name_hash)];
+	if let Some(ip) = client_ip(&req, &settings.trusted_proxies) {
+		failure_keys.push(format!("login_fail:ip:{}", ip));
+	}
+	auth_db
+		.count_login_attempt(
+			&failure_keys,
+			settings.login_max_attempts,
+			settings.login_lockout,
+		)
+		.await?;
+
 	// Attempt to get our user from the database
 	let conn = try500!(pool.get().await, "post_login:db pool");
 	let user = try500!(
@@ -106,16 +216,17 @@ pub async fn post_login(
 		"post_login:select_name {:?}",
 		query.user
 	);
-	// Check to see we found a user, otherwise return bad credentials
+	// Check to see we found a user and that our password matches the hash,
+	// otherwise return bad credentials
+	let verified = match &user {
+		Some(user) => try500!(argon2::verify_encoded(&user.pass, query.pass.as_bytes())),
+		None => false,
+	};
 	let user = match user {
-		Some(x) => x,
-		None => return Err(APIError::BadCredentials),
+		Some(user) if verified => user,
+		_ => return Err(APIError::BadCredentials),
 	};
-
-	// Check that our password matches the hash
-	if !try500!(argon2::verify_encoded(&user.pass, query.pass.as_bytes())) {
-		return Err(APIError::BadCredentials);
-	}
+	auth_db.clear_login_failures(&failure_keys).await?;
 
 	// Generate a token for the user
 	let mut token = [0u8; 40];
@@ -127,30 +238,53 @@ pub async fn post_login(
 	base64::encode_config_buf(token, base64::STANDARD, &mut key);
 
 	// Don't bother checking if it's not taken, just error
-	let user = user.into();
-	auth_db.remember(&key, &user).await?;
+	let user: AuthInfo = user.into();
+	auth_db.remember(&key, &user, settings.session_ttl).await?;
 
-	Ok(HttpResponse::Ok()
+	// Let clients know when they will need to log in again, if ever
+	let expires_at = match settings.session_ttl {
+		0 => None,
+		ttl => Some(chrono::Utc::now() + chrono::Duration::seconds(ttl as i64)),
+	};
+
+	let token = &key[5..key.len()];
+	let mut res = no_store(HttpResponse::Ok());
+	if settings.cookie_auth {
+		res.cookie(auth::session_cookie(token, settings.session_ttl));
+	}
+	Ok(res
 		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-		.body(format!(
-			r#"{{"success":"user logged in","token":"{}","data":{}}}"#,
-			&key[5..key.len()],
-			serde_json::to_string(&user).unwrap()
-		)))
+		.body(
+			serde_json::json!({
+				"success": "user logged in",
+				"token": token,
+				"expires_at": expires_at,
+				"perms": user.perms,
+				"data": user,
+			})
+			.to_string(),
+		))
 }
 
 pub async fn delete_logout(
 	req: HttpRequest,
+	settings: web::Data<RunSettings>,
 	auth: Authenticated,
 ) -> Result<HttpResponse, APIError> {
+	let uid = auth.uid;
 	auth.forget(&req).await?;
-	Ok(HttpResponse::Ok()
+	let mut res = no_store(HttpResponse::Ok());
+	if settings.cookie_auth {
+		res.cookie(auth::removal_cookie());
+	}
+	Ok(res
 		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-		.body(r#"{"success":"user logged out"}"#))
+		.body(serde_json::json!({ "success": "user logged out", "id": uid }).to_string()))
 }
 
 pub async fn get_self(
 	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
 	auth: Authenticated,
 ) -> Result<HttpResponse, APIError> {
 	let conn = try500!(pool.get().await, "post_login:db pool");
@@ -160,23 +294,427 @@ pub async fn get_self(
 		auth.uid
 	);
 	let user = match user {
-		Some(u) => UserAPI::from(u),
+		Some(u) => UserAPI::from(u)
+			.with_public_url(&settings.public_url)
+			.visible_to(auth.perms),
 		None => return Err(APIError::BadRequestData),
 	};
 
-	Ok(HttpResponse::Ok()
+	Ok(no_store(HttpResponse::Ok())
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::to_string(&user).unwrap()))
+}
+
+#[derive(serde::Deserialize)]
+pub struct UpdateUserQuery {
+	default_rating_filter: Option<Vec<Rating>>,
+}
+
+pub async fn patch_self(
+	query: web::Json<UpdateUserQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	let mut conn = try500!(pool.get().await, "patch_self:db pool");
+	let trans = try500!(conn.transaction().await);
+	if let Some(ratings) = &query.default_rating_filter {
+		try500!(
+			User::update_rating_filter::<pg::Transaction<'_>>(&trans, auth.uid, ratings).await,
+			"patch_self:update_rating_filter {} {:?}",
+			auth.uid,
+			ratings
+		);
+	}
+	let user = try500!(
+		User::select_id::<pg::Transaction<'_>>(&trans, auth.uid).await,
+		"patch_self:select_id {:?}",
+		auth.uid
+	);
+	let user = match user {
+		Some(u) => UserAPI::from(u)
+			.with_public_url(&settings.public_url)
+			.visible_to(auth.perms),
+		None => return Err(APIError::BadRequestData),
+	};
+	try500!(trans.commit().await);
+
+	Ok(no_store(HttpResponse::Ok())
 		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 		.body(serde_json::to_string(&user).unwrap()))
 }
 
+#[derive(serde::Deserialize)]
+pub struct DeleteUserQuery {
+	pass: String,
+}
+
+pub async fn delete_self(
+	query: web::Json<DeleteUserQuery>,
+	pool: web::Data<DbPool>,
+	settings: web::Data<RunSettings>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if auth.uid == DELETED_USER {
+		return Err(APIError::Forbidden);
+	}
+
+	let mut conn = try500!(pool.get().await, "delete_self:db pool");
+	let trans = try500!(conn.transaction().await);
+	let user = try500!(
+		User::select_id::<pg::Transaction<'_>>(&trans, auth.uid).await,
+		"delete_self:select_id {}",
+		auth.uid
+	);
+	let user = match user {
+		Some(u) => u,
+		None => return Err(APIError::BadRequestData),
+	};
+	// Make them prove it's really them before throwing the account away
+	if !try500!(argon2::verify_encoded(&user.pass, query.pass.as_bytes())) {
+		return Err(APIError::BadCredentials);
+	}
+	// Never leave the site without an admin
+	if user.perms == Perms::Admin {
+		let admins = try500!(
+			User::count_admins::<pg::Transaction<'_>>(&trans).await,
+			"delete_self:count_admins"
+		);
+		if admins <= 1 {
+			return Err(APIError::Forbidden);
+		}
+	}
+
+	let soft_delete = matches!(settings.user_delete_policy, UserDeletePolicy::SoftDelete);
+	let posts = try500!(
+		User::anonymize_posts::<pg::Transaction<'_>>(&trans, auth.uid, soft_delete).await,
+		"delete_self:anonymize_posts {}",
+		auth.uid
+	);
+	try500!(
+		User::delete::<pg::Transaction<'_>>(&trans, auth.uid).await,
+		"delete_self:delete {}",
+		auth.uid
+	);
+	try500!(trans.commit().await);
+
+	// The account is gone either way, any sessions left over just won't find it
+	auth.get_db().forget_user(auth.uid).await?;
+
+	let mut res = no_store(HttpResponse::Ok());
+	if settings.cookie_auth {
+		res.cookie(auth::removal_cookie());
+	}
+	Ok(res
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(
+			serde_json::json!({
+				"success": "user deleted",
+				"id": auth.uid,
+				"posts": posts,
+				"posts_deleted": soft_delete,
+			})
+			.to_string(),
+		))
+}
+
 pub async fn get_logged_in(auth: MaybeAuthenticated) -> HttpResponse {
 	if auth.is_authenticated() {
-		HttpResponse::Ok()
+		no_store(HttpResponse::Ok())
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 			.body(r#"{"status": "logged in"}"#)
 	} else {
-		HttpResponse::Ok()
+		no_store(HttpResponse::Ok())
 			.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
 			.body(r#"{"status": "logged out"}"#)
 	}
 }
+
+#[derive(serde::Deserialize)]
+pub struct UpdatePermsQuery {
+	id: i32,
+	perms: Perms,
+}
+
+pub async fn post_perms(
+	query: web::Json<UpdatePermsQuery>,
+	pool: web::Data<DbPool>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if auth.perms != Perms::Admin {
+		return Err(APIError::Auth);
+	}
+
+	let mut conn = try500!(pool.get().await, "post_perms:db pool");
+	let trans = try500!(conn.transaction().await);
+	let user = try500!(
+		User::select_id::<pg::Transaction<'_>>(&trans, query.id).await,
+		"post_perms:select_id {}",
+		query.id
+	);
+	let user = match user {
+		Some(u) => u,
+		None => {
+			return Ok(HttpResponse::NotFound()
+				.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+				.body(r#"{"error":"user not found"}"#))
+		}
+	};
+	// Never leave the site without an admin
+	if user.perms == Perms::Admin && query.perms != Perms::Admin {
+		let admins = try500!(
+			User::count_admins::<pg::Transaction<'_>>(&trans).await,
+			"post_perms:count_admins"
+		);
+		if admins <= 1 {
+			return Err(APIError::Forbidden);
+		}
+	}
+	try500!(
+		User::update_perms::<pg::Transaction<'_>>(&trans, query.id, query.perms).await,
+		"post_perms:update_perms {} {:?}",
+		query.id,
+		query.perms
+	);
+	try500!(trans.commit().await);
+
+	// Sessions hold onto the perms they were made with, so make them log in again
+	auth.get_db().forget_user(query.id).await?;
+
+	Ok(HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(r#"{"success":"permissions updated"}"#))
+}
+
+pub async fn post_invite(
+	pool: web::Data<DbPool>,
+	auth: Authenticated,
+) -> Result<HttpResponse, APIError> {
+	if auth.perms != Perms::Admin {
+		return Err(APIError::Auth);
+	}
+
+	let mut code = [0u8; 18];
+	rand::thread_rng().fill(&mut code[..]);
+	let code = base64::encode_config(code, base64::URL_SAFE_NO_PAD);
+
+	let conn = try500!(pool.get().await, "post_invite:db pool");
+	try500!(
+		Invite::insert(&**conn, &code, auth.uid).await,
+		"post_invite:insert"
+	);
+
+	Ok(no_store(HttpResponse::Ok())
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(serde_json::json!({ "code": code }).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actix_web::http::StatusCode;
+
+	fn reason(res: Result<(), APIError>) -> Option<&'static str> {
+		match res {
+			Ok(()) => None,
+			Err(APIError::BadPassword(reason)) => Some(reason),
+			Err(e) => panic!("unexpected error {:?}", e),
+		}
+	}
+
+	#[test]
+	fn password_length() {
+		let policy = PasswordPolicy {
+			min_length: 8,
+			max_length: 12,
+			..PasswordPolicy::default()
+		};
+		assert_eq!(
+			reason(validate_password("1234567", &policy)),
+			Some("it is too short")
+		);
+		assert_eq!(reason(validate_password("12345678", &policy)), None);
+		assert_eq!(reason(validate_password("123456789012", &policy)), None);
+		assert_eq!(
+			reason(validate_password("1234567890123", &policy)),
+			Some("it is too long")
+		);
+		// Counted in characters rather than bytes
+		assert_eq!(
+			reason(validate_password("ééééééé", &policy)),
+			Some("it is too short")
+		);
+	}
+
+	#[test]
+	fn password_digit() {
+		let policy = PasswordPolicy {
+			require_digit: true,
+			..PasswordPolicy::default()
+		};
+		assert_eq!(
+			reason(validate_password("password", &policy)),
+			Some("it needs a digit")
+		);
+		assert_eq!(reason(validate_password("passw0rd", &policy)), None);
+	}
+
+	#[test]
+	fn password_upper() {
+		let policy = PasswordPolicy {
+			require_upper: true,
+			..PasswordPolicy::default()
+		};
+		assert_eq!(
+			reason(validate_password("password", &policy)),
+			Some("it needs an uppercase letter")
+		);
+		assert_eq!(reason(validate_password("Password", &policy)), None);
+	}
+
+	#[test]
+	fn password_symbol() {
+		let policy = PasswordPolicy {
+			require_symbol: true,
+			..PasswordPolicy::default()
+		};
+		assert_eq!(
+			reason(validate_password("password", &policy)),
+			Some("it needs a symbol")
+		);
+		assert_eq!(reason(validate_password("pass word", &policy)), None);
+		assert_eq!(reason(validate_password("pass-word", &policy)), None);
+	}
+
+	#[test]
+	fn password_default_policy() {
+		let policy = PasswordPolicy::default();
+		assert_eq!(
+			reason(validate_password("short", &policy)),
+			Some("it is too short")
+		);
+		assert_eq!(reason(validate_password("longenough", &policy)), None);
+	}
+
+	#[test]
+	fn hash_password_checks_policy() {
+		let policy = PasswordPolicy::default();
+		assert!(matches!(
+			hash_password("short", &policy),
+			Err(APIError::BadPassword(_))
+		));
+		let hash = hash_password("longenough", &policy).unwrap();
+		assert!(argon2::verify_encoded(&hash, b"longenough").unwrap());
+	}
+
+	fn register(user: usize, email: usize, invite: Option<usize>) -> RegisterUserQuery {
+		RegisterUserQuery {
+			user: "u".repeat(user),
+			pass: String::new(),
+			email: "e".repeat(email),
+			invite: invite.map(|n| "i".repeat(n)),
+		}
+	}
+
+	#[test]
+	fn register_limits() {
+		assert!(register(limits::USERNAME_MIN, 10, None).within_limits());
+		assert!(register(
+			limits::USERNAME_MAX,
+			limits::EMAIL_MAX,
+			Some(limits::INVITE_MAX)
+		)
+		.within_limits());
+		assert!(!register(limits::USERNAME_MIN - 1, 10, None).within_limits());
+		assert!(!register(limits::USERNAME_MAX + 1, 10, None).within_limits());
+		assert!(!register(8, limits::EMAIL_MAX + 1, None).within_limits());
+		assert!(!register(8, 10, Some(limits::INVITE_MAX + 1)).within_limits());
+	}
+
+	async fn body_error(
+		path: &str,
+		content_type: &str,
+		body: &str,
+	) -> (StatusCode, serde_json::Value) {
+		use actix_web::{test, web::JsonConfig, App};
+
+		let ok = || async { HttpResponse::Ok().finish() };
+		let app = test::init_service(
+			App::new()
+				.app_data(JsonConfig::default().error_handler(crate::pages::json_error))
+				.route(
+					"/register",
+					web::post().to(move |_: web::Json<RegisterUserQuery>| ok()),
+				)
+				.route(
+					"/login",
+					web::post().to(move |_: web::Json<LoginUserQuery>| ok()),
+				)
+				.route(
+					"/perms",
+					web::post().to(move |_: web::Json<UpdatePermsQuery>| ok()),
+				),
+		)
+		.await;
+		let req = test::TestRequest::post()
+			.uri(path)
+			.insert_header((header::CONTENT_TYPE, content_type))
+			.set_payload(body.to_owned())
+			.to_request();
+		let res = test::call_service(&app, req).await;
+		let status = res.status();
+		let body = test::read_body(res).await;
+		(status, serde_json::from_slice(&body).unwrap_or_default())
+	}
+
+	#[actix_web::test]
+	async fn malformed_bodies() {
+		const JSON: &str = "application/json";
+		for (path, content_type, body, error) in [
+			(
+				"/register",
+				JSON,
+				r#"{"user":"a","pass":"b"}"#,
+				"missing field `email`",
+			),
+			("/login", JSON, r#"{"pass":"b"}"#, "missing field `user`"),
+			(
+				"/login",
+				JSON,
+				r#"{"user":1,"pass":"b"}"#,
+				"invalid type: integer `1`, expected a string at line 1 column 9",
+			),
+			(
+				"/perms",
+				JSON,
+				r#"{"id":1,"perms":"Emperor"}"#,
+				"unknown variant `Emperor`",
+			),
+			(
+				"/login",
+				JSON,
+				r#"{"user":"a","#,
+				"EOF while parsing a value",
+			),
+			(
+				"/login",
+				"text/plain",
+				r#"{"user":"a","pass":"b"}"#,
+				"expected application/json",
+			),
+		] {
+			let (status, json) = body_error(path, content_type, body).await;
+			assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+			assert_eq!(json["code"], "INVALID_BODY", "{}", body);
+			let message = json["error"].as_str().unwrap();
+			assert!(message.contains(error), "{} gave {}", body, message);
+		}
+	}
+
+	#[actix_web::test]
+	async fn well_formed_body() {
+		let (status, _) =
+			body_error("/login", "application/json", r#"{"user":"a","pass":"b"}"#).await;
+		assert_eq!(status, StatusCode::OK);
+	}
+}
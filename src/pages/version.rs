@@ -0,0 +1,24 @@
+use actix_web::{http::header, HttpResponse};
+use chrono::TimeZone;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const COMMIT: &str = env!("WATAME_COMMIT");
+const BUILT_AT: &str = env!("WATAME_BUILT_AT");
+
+pub async fn get_version() -> HttpResponse {
+	let built_at = BUILT_AT
+		.parse()
+		.ok()
+		.map(|secs| chrono::Utc.timestamp(secs, 0).to_rfc3339());
+
+	HttpResponse::Ok()
+		.append_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+		.body(
+			serde_json::json!({
+				"version": VERSION,
+				"commit": COMMIT,
+				"built_at": built_at,
+			})
+			.to_string(),
+		)
+}
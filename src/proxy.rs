@@ -0,0 +1,246 @@
+use actix_web::{
+	http::header::{self, HeaderMap},
+	HttpRequest,
+};
+
+use std::net::{IpAddr, SocketAddr};
+
+/// A block of addresses such as `10.0.0.0/8`, a bare address is a block of one
+#[derive(Debug, Copy, Clone)]
+pub struct Cidr {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.addr, canonical(ip)) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX
+					.checked_shl(32 - u32::from(self.prefix))
+					.unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX
+					.checked_shl(128 - u32::from(self.prefix))
+					.unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+impl std::str::FromStr for Cidr {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr.parse().map_err(|_| ())?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(p) => p.parse().map_err(|_| ())?,
+			None => max,
+		};
+		if prefix > max {
+			return Err(());
+		}
+		Ok(Cidr {
+			addr: canonical(addr),
+			prefix,
+		})
+	}
+}
+
+/// Treat IPv4 addresses mapped into IPv6 as plain IPv4 so they match v4 blocks
+fn canonical(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+		ip => ip,
+	}
+}
+
+/// Parse one hop out of `X-Forwarded-For` or a `for=` in `Forwarded`, these may
+/// be quoted, bracketed and carry a port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+	let hop = hop.trim().trim_matches('"');
+	hop.parse::<IpAddr>()
+		.or_else(|_| hop.parse::<SocketAddr>().map(|a| a.ip()))
+		.or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse())
+		.ok()
+		.map(canonical)
+}
+
+/// The hops a request claims to have come through, closest to the client first
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+	if headers.contains_key(header::FORWARDED) {
+		headers
+			.get_all(header::FORWARDED)
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.filter_map(|element| {
+				element.split(';').find_map(|pair| {
+					let (key, value) = pair.trim().split_once('=')?;
+					key.eq_ignore_ascii_case("for").then(|| value.to_owned())
+				})
+			})
+			.collect()
+	} else {
+		headers
+			.get_all("x-forwarded-for")
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.map(str::to_owned)
+			.collect()
+	}
+}
+
+/// Work out the address a request came from. Forwarded headers are only believed
+/// for hops added by one of our trusted proxies, walking back from the socket
+/// address until we reach one that we don't trust, so a client can't spoof its
+/// address by sending the headers itself
+pub fn client_ip(req: &HttpRequest, trusted: &[Cidr]) -> Option<IpAddr> {
+	resolve_client_ip(req.peer_addr(), req.headers(), trusted)
+}
+
+/// `client_ip` for when there is no `HttpRequest` to hand, such as in middleware
+pub fn resolve_client_ip(
+	peer: Option<SocketAddr>,
+	headers: &HeaderMap,
+	trusted: &[Cidr],
+) -> Option<IpAddr> {
+	let mut ip = canonical(peer?.ip());
+	if trusted.is_empty() {
+		return Some(ip);
+	}
+	for hop in forwarded_hops(headers).iter().rev() {
+		if !trusted.iter().any(|c| c.contains(ip)) {
+			break;
+		}
+		match parse_hop(hop) {
+			Some(hop) => ip = hop,
+			// Anything unparsable, such as an obfuscated identifier, ends the chain
+			None => break,
+		}
+	}
+	Some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actix_web::http::header::HeaderValue;
+
+	fn cidrs(list: &[&str]) -> Vec<Cidr> {
+		list.iter().map(|c| c.parse().unwrap()).collect()
+	}
+
+	fn headers(name: &str, value: &str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+			HeaderValue::from_str(value).unwrap(),
+		);
+		headers
+	}
+
+	fn resolve(peer: &str, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr {
+		resolve_client_ip(Some(peer.parse().unwrap()), headers, trusted).unwrap()
+	}
+
+	#[test]
+	fn untrusted_peer_forged_header() {
+		let trusted = cidrs(&["10.0.0.0/8"]);
+		let forged = headers("x-forwarded-for", "1.2.3.4");
+		assert_eq!(
+			resolve("203.0.113.7:5000", &forged, &trusted),
+			"203.0.113.7".parse::<IpAddr>().unwrap()
+		);
+		// Nothing trusted means the headers are never looked at
+		assert_eq!(
+			resolve("10.0.0.1:5000", &forged, &[]),
+			"10.0.0.1".parse::<IpAddr>().unwrap()
+		);
+	}
+
+	#[test]
+	fn trusted_chain() {
+		let trusted = cidrs(&["10.0.0.0/8", "192.168.1.1"]);
+		// The client prepended a forged hop, we stop at the first one we don't trust
+		let chain = headers("x-forwarded-for", "6.6.6.6, 198.51.100.2, 192.168.1.1");
+		assert_eq!(
+			resolve("10.1.2.3:443", &chain, &trusted),
+			"198.51.100.2".parse::<IpAddr>().unwrap()
+		);
+		// Every hop trusted ends on the furthest one
+		let chain = headers("x-forwarded-for", "198.51.100.2, 10.0.0.5");
+		assert_eq!(
+			resolve("10.0.0.9:443", &chain, &trusted),
+			"198.51.100.2".parse::<IpAddr>().unwrap()
+		);
+		let forwarded = headers(
+			"forwarded",
+			"for=198.51.100.2;proto=https, for=\"10.0.0.5:8080\"",
+		);
+		assert_eq!(
+			resolve("10.0.0.9:443", &forwarded, &trusted),
+			"198.51.100.2".parse::<IpAddr>().unwrap()
+		);
+		// Unparsable hops end the chain at the last proxy we trusted
+		let obfuscated = headers("forwarded", "for=_hidden, for=10.0.0.5");
+		assert_eq!(
+			resolve("10.0.0.9:443", &obfuscated, &trusted),
+			"10.0.0.5".parse::<IpAddr>().unwrap()
+		);
+	}
+
+	#[test]
+	fn malformed_cidrs() {
+		for bad in [
+			"",
+			"10.0.0.0/",
+			"10.0.0.0/33",
+			"10.0.0/8",
+			"10.0.0.0/-1",
+			"10.0.0.0/8/8",
+			"::1/129",
+			"example.com",
+			"10.0.0.0 /8",
+		] {
+			assert!(bad.parse::<Cidr>().is_err(), "{:?} parsed", bad);
+		}
+		let bare: Cidr = "10.0.0.1".parse().unwrap();
+		assert!(bare.contains("10.0.0.1".parse().unwrap()));
+		assert!(!bare.contains("10.0.0.2".parse().unwrap()));
+		let all: Cidr = "0.0.0.0/0".parse().unwrap();
+		assert!(all.contains("203.0.113.7".parse().unwrap()));
+	}
+
+	#[test]
+	fn ipv6() {
+		let net: Cidr = "2001:db8::/32".parse().unwrap();
+		assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+		assert!(!net.contains("2001:db9::1".parse().unwrap()));
+		assert!(!net.contains("10.0.0.1".parse().unwrap()));
+
+		// IPv4 mapped addresses match IPv4 blocks
+		let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+		assert!(v4.contains("::ffff:10.1.2.3".parse().unwrap()));
+
+		let trusted = cidrs(&["2001:db8::/32"]);
+		let chain = headers("x-forwarded-for", "2001:db8:ffff::1, 2606:4700::1111");
+		assert_eq!(
+			resolve("[2001:db8::5]:443", &chain, &trusted),
+			"2606:4700::1111".parse::<IpAddr>().unwrap()
+		);
+		let forwarded = headers("forwarded", "for=\"[2606:4700::1111]:4711\"");
+		assert_eq!(
+			resolve("[2001:db8::5]:443", &forwarded, &trusted),
+			"2606:4700::1111".parse::<IpAddr>().unwrap()
+		);
+	}
+}
@@ -1,17 +1,32 @@
+use serde::de::{value::StrDeserializer, IntoDeserializer};
 use structopt::StructOpt;
 
+use crate::database::enums::{ImageExtension, Rating};
+use crate::pages::search::deserialize_ratings;
+use crate::proxy::Cidr;
+
+/// Longest a session can live for, roughly ten years, so expiry times always fit
+pub const SESSION_TTL_MAX: u64 = 60 * 60 * 24 * 365 * 10;
+
+#[derive(Default)]
 pub enum Action {
 	ClearSessions,
+	CreateAdmin,
 	CreateFolders,
 	DropTables,
 	InstallSchema,
+	#[default]
 	RunServer,
 }
 
-impl std::default::Default for Action {
-	fn default() -> Action {
-		Action::RunServer
-	}
+/// What happens to the posts of a user that deletes their account
+#[derive(Debug, Copy, Clone, Default)]
+pub enum UserDeletePolicy {
+	/// Keep them up, but no longer attributed to anyone
+	#[default]
+	Anonymize,
+	/// Take them down as if they had been deleted
+	SoftDelete,
 }
 
 impl std::str::FromStr for Action {
@@ -19,6 +34,7 @@ impl std::str::FromStr for Action {
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		let res = match s {
 			"clear-sessions" => Action::ClearSessions,
+			"create-admin" => Action::CreateAdmin,
 			"create-folders" => Action::CreateFolders,
 			"drop-tables" => Action::DropTables,
 			"install-schema" => Action::InstallSchema,
@@ -30,9 +46,13 @@ impl std::str::FromStr for Action {
 }
 
 #[derive(StructOpt)]
+#[structopt(version = concat!(env!("CARGO_PKG_VERSION"), " (", env!("WATAME_COMMIT"), ")"))]
 struct CliOptions {
 	#[structopt(long = "action", default_value = "run")]
 	action: Action,
+	/// Report what a destructive action would do without doing it
+	#[structopt(long = "dry-run")]
+	dry_run: bool,
 }
 
 pub struct Settings {
@@ -42,14 +62,76 @@ pub struct Settings {
 	pub database_credentials: (String, String),
 	pub database_name: String,
 	pub storage_root: String,
+	/// Serve `storage_root` under `/s`, turn off when something else such as a
+	/// reverse proxy or CDN serves it. Needs the `host-storage` feature
+	pub serve_storage: bool,
+	/// Base URL clients use to reach us, used to build absolute links
+	pub public_url: String,
+	/// Picture given to new users, relative to `public_url` unless absolute
+	pub default_pfp: String,
 	pub redis_uri: String,
+	/// Put in front of every redis key, so one redis can be shared between instances
+	/// or with other applications
+	pub redis_prefix: String,
+	/// How many connections are kept open to redis, requests take turns using them
+	pub redis_pool_size: usize,
+	/// Seconds a handler waits on the database before giving up, 0 to wait forever
+	pub query_timeout: u64,
+	/// How long a login session lasts in seconds, 0 for no expiry
+	pub session_ttl: u64,
+	/// Also hand out sessions as an HttpOnly cookie on login and accept them back
+	pub cookie_auth: bool,
+	/// Text search configuration used for tag vectors and queries
+	pub ts_config: String,
 	/// Max payload of multipart structures in KiB
 	pub max_payload: usize,
+	/// How many uploads a single user may have in progress at once, 0 for no limit
+	pub max_concurrent_uploads: u32,
+	/// Quality used when transcoding an original, this is lossy so keep it high
+	pub reencode_quality: u8,
+	/// Format to convert every upload into before storing it, only WebP is supported.
+	/// This is lossy, uses `reencode_quality`, and the original file is not kept
+	pub transcode_to: Option<ImageExtension>,
+	/// Most images a single gallery upload may contain, at least 1
+	pub max_group_images: usize,
+	/// Failed logins allowed before an account or address is locked out, 0 to never
+	/// lock anyone out
+	pub login_max_attempts: u32,
+	/// Seconds the first lockout lasts, doubling with each attempt made during it
+	pub login_lockout: u64,
+	/// Seconds a deleted post is kept around for before a purge will remove it
+	pub purge_grace: u64,
+	/// How many files a purge removes at once
+	pub purge_concurrency: usize,
+	/// What passwords need to contain to be accepted
+	pub password_policy: PasswordPolicy,
+	/// Let anyone register, when off a valid invite code is needed instead
+	pub registration_open: bool,
+	pub user_delete_policy: UserDeletePolicy,
+	/// Largest page size searches may ask for
+	pub max_search_limit: u32,
+	/// Most tags a single search may contain
+	pub max_search_tags: usize,
+	/// Image formats uploads may be in
+	pub allowed_formats: Vec<ImageExtension>,
+	/// Tags that uploads are not allowed to contain, stored lowercase
+	pub banned_tags: Vec<String>,
+	/// Ratings searched when neither the request nor the user ask for any, empty
+	/// for all of them
+	pub default_ratings: Vec<Rating>,
+	/// Origins allowed by CORS, any origin is allowed if this is empty
+	pub allowed_origins: Vec<String>,
+	/// Reject state changing requests whose Origin/Referer isn't allowed
+	pub enforce_origin: bool,
+	/// Proxies whose X-Forwarded-For/Forwarded hops we believe when working out
+	/// client addresses, empty to only ever use the socket address
+	pub trusted_proxies: Vec<Cidr>,
 	pub use_https: bool,
 	pub priv_key: String,
 	pub cert: String,
 
 	pub action: Action,
+	pub dry_run: bool,
 }
 
 impl std::default::Default for Settings {
@@ -61,12 +143,41 @@ impl std::default::Default for Settings {
 			database_credentials: ("postgres".to_owned(), "password".to_owned()),
 			database_name: "watame".to_owned(),
 			storage_root: "./storage/".to_owned(),
+			serve_storage: true,
+			public_url: String::new(),
+			default_pfp: "/s/pfp/default.png".to_owned(),
 			redis_uri: "redis://127.0.0.1:6379".to_owned(),
+			redis_prefix: String::new(),
+			redis_pool_size: 1,
+			query_timeout: 30,
+			session_ttl: 0,
+			cookie_auth: false,
+			ts_config: "simple".to_owned(),
 			max_payload: 1024 * 64, // 64MiB
+			max_concurrent_uploads: 4,
+			reencode_quality: 95,
+			transcode_to: None,
+			max_group_images: 10,
+			max_search_limit: 50,
+			max_search_tags: 10,
+			password_policy: PasswordPolicy::default(),
+			registration_open: true,
+			user_delete_policy: UserDeletePolicy::default(),
+			login_max_attempts: 5,
+			login_lockout: 60,
+			purge_grace: 60 * 60 * 24 * 7,
+			purge_concurrency: 16,
+			allowed_formats: ImageExtension::ALL.to_vec(),
+			banned_tags: Vec::new(),
+			default_ratings: Vec::new(),
+			allowed_origins: Vec::new(),
+			enforce_origin: false,
+			trusted_proxies: Vec::new(),
 			use_https: false,
 			priv_key: "key.pem".to_owned(),
 			cert: "cert.pem".to_owned(),
 			action: Action::default(),
+			dry_run: false,
 		}
 	}
 }
@@ -98,13 +209,239 @@ impl Settings {
 		if let Ok(v) = std::env::var("WATAME_REDIS_URI") {
 			settings.redis_uri = v;
 		}
+		if let Ok(v) = std::env::var("WATAME_REDIS_PREFIX") {
+			settings.redis_prefix = v;
+		}
+		if let Ok(v) = std::env::var("WATAME_REDIS_POOL_SIZE") {
+			match v.parse() {
+				Ok(v) if v > 0 => settings.redis_pool_size = v,
+				_ => log::warn!("invalid redis pool size: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_QUERY_TIMEOUT") {
+			match v.parse() {
+				Ok(v) => settings.query_timeout = v,
+				Err(_) => log::warn!("invalid query timeout: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_SESSION_TTL") {
+			match v.parse() {
+				Ok(v) if v > SESSION_TTL_MAX => {
+					log::warn!("session ttl '{}' is too long, using {}", v, SESSION_TTL_MAX);
+					settings.session_ttl = SESSION_TTL_MAX;
+				}
+				Ok(v) => settings.session_ttl = v,
+				Err(_) => log::warn!("invalid session ttl: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_COOKIE_AUTH") {
+			match v.parse() {
+				Ok(v) => settings.cookie_auth = v,
+				Err(_) => {
+					log::warn!("unknown value for WATAME_COOKIE_AUTH, must be 'true' or 'false'")
+				}
+			}
+		}
+		// Built-in configs like `simple` split words on `-`, `_` and `.`, so a tag like
+		// `long_hair` is stored as `long` and `hair` and matches searches for either
+		// one. Only `tag_parser` keeps every tag as a single lexeme
+		if let Ok(v) = std::env::var("WATAME_TS_CONFIG") {
+			settings.ts_config = v;
+		}
 		if let Ok(v) = std::env::var("WATAME_STORAGE_ROOT") {
 			settings.storage_root = v;
 		}
+		if let Ok(v) = std::env::var("WATAME_SERVE_STORAGE") {
+			match v.parse() {
+				Ok(v) => settings.serve_storage = v,
+				Err(_) => {
+					log::warn!("unknown value for WATAME_SERVE_STORAGE, must be 'true' or 'false'")
+				}
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_PUBLIC_URL") {
+			settings.public_url = v;
+		}
+		if let Ok(v) = std::env::var("WATAME_DEFAULT_PFP") {
+			settings.default_pfp = v;
+		}
 		if let Ok(v) = std::env::var("WATAME_MAX_PAYLOAD") {
+			// The limit is given in KiB, make sure it still fits once it's in bytes
+			match v.parse::<usize>() {
+				Ok(n) if n.checked_mul(1024).is_some() => settings.max_payload = n,
+				_ => log::warn!("invalid max payload: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_MAX_CONCURRENT_UPLOADS") {
+			match v.parse() {
+				Ok(v) => settings.max_concurrent_uploads = v,
+				Err(_) => log::warn!("invalid max concurrent uploads: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_REENCODE_QUALITY") {
+			match v.parse() {
+				Ok(v @ 1..=100) => settings.reencode_quality = v,
+				_ => log::warn!("invalid re-encode quality: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_TRANSCODE_TO") {
+			match v.to_lowercase().as_str() {
+				"none" => settings.transcode_to = None,
+				"webp" => settings.transcode_to = Some(ImageExtension::Webp),
+				_ => log::warn!("unknown value for WATAME_TRANSCODE_TO, must be 'none' or 'webp'"),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_MAX_GROUP_IMAGES") {
+			match v.parse() {
+				// Every upload has at least one image so 0 would refuse them all
+				Ok(v) if v > 0 => settings.max_group_images = v,
+				_ => log::warn!("invalid max group images: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_LOGIN_MAX_ATTEMPTS") {
+			match v.parse() {
+				Ok(v) => settings.login_max_attempts = v,
+				Err(_) => log::warn!("invalid login max attempts: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_LOGIN_LOCKOUT") {
+			match v.parse() {
+				Ok(v) => settings.login_lockout = v,
+				Err(_) => log::warn!("invalid login lockout: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_PURGE_GRACE") {
+			match v.parse() {
+				Ok(v) => settings.purge_grace = v,
+				Err(_) => log::warn!("invalid purge grace: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_PURGE_CONCURRENCY") {
+			match v.parse() {
+				Ok(v) if v > 0 => settings.purge_concurrency = v,
+				_ => log::warn!("invalid purge concurrency: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_PASSWORD_MIN_LENGTH") {
+			match v.parse() {
+				Ok(v) => settings.password_policy.min_length = v,
+				Err(_) => log::warn!("invalid password min length: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_PASSWORD_MAX_LENGTH") {
+			match v.parse() {
+				Ok(v) => settings.password_policy.max_length = v,
+				Err(_) => log::warn!("invalid password max length: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_PASSWORD_REQUIRE") {
+			for rule in v.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+				match rule {
+					"digit" => settings.password_policy.require_digit = true,
+					"upper" => settings.password_policy.require_upper = true,
+					"symbol" => settings.password_policy.require_symbol = true,
+					_ => log::warn!("unknown password requirement: '{}'", rule),
+				}
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_REGISTRATION_OPEN") {
+			match v.parse() {
+				Ok(v) => settings.registration_open = v,
+				Err(_) => {
+					log::warn!(
+						"unknown value for WATAME_REGISTRATION_OPEN, must be 'true' or 'false'"
+					)
+				}
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_USER_DELETE_POLICY") {
+			match v.to_lowercase().as_str() {
+				"anonymize" => settings.user_delete_policy = UserDeletePolicy::Anonymize,
+				"delete" => settings.user_delete_policy = UserDeletePolicy::SoftDelete,
+				_ => log::warn!(
+					"unknown value for WATAME_USER_DELETE_POLICY, must be 'anonymize' or 'delete'"
+				),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_MAX_SEARCH_LIMIT") {
+			match v.parse() {
+				Ok(v) => settings.max_search_limit = v,
+				Err(_) => log::warn!("invalid max search limit: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_MAX_SEARCH_TAGS") {
+			match v.parse() {
+				Ok(v) => settings.max_search_tags = v,
+				Err(_) => log::warn!("invalid max search tags: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_ALLOWED_FORMATS") {
+			let mut formats = Vec::new();
+			for name in v.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+				match ImageExtension::from_name(name) {
+					Some(f) => formats.push(f),
+					None => {
+						log::error!("unknown image format in WATAME_ALLOWED_FORMATS: '{}'", name);
+						std::process::exit(1);
+					}
+				}
+			}
+			settings.allowed_formats = formats;
+		}
+		if let Ok(v) = std::env::var("WATAME_BANNED_TAGS") {
+			settings.banned_tags = v
+				.split(',')
+				.map(|t| t.trim().to_lowercase())
+				.filter(|t| !t.is_empty())
+				.collect();
+		}
+		if let Ok(v) = std::env::var("WATAME_DEFAULT_RATINGS") {
+			let de: StrDeserializer<'_, serde::de::value::Error> = v.as_str().into_deserializer();
+			match deserialize_ratings(de) {
+				Ok(v) => settings.default_ratings = v,
+				Err(_) => log::warn!("invalid default ratings: '{}'", v),
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_ALLOWED_ORIGINS") {
+			settings.allowed_origins = v
+				.split(',')
+				.map(|o| o.trim().trim_end_matches('/').to_owned())
+				.filter(|o| !o.is_empty())
+				.collect();
+		}
+		if let Ok(v) = std::env::var("WATAME_ENFORCE_ORIGIN") {
 			match v.parse() {
-				Ok(v) => settings.max_payload = v,
-				Err(_) => log::warn!("invalid database address format: '{}'", v),
+				Ok(v) => settings.enforce_origin = v,
+				Err(_) => {
+					log::warn!("unknown value for WATAME_ENFORCE_ORIGIN, must be 'true' or 'false'")
+				}
+			}
+		}
+		// Trusting any proxy is kept around from before proxies could be listed
+		if let Ok(v) = std::env::var("WATAME_TRUST_PROXY") {
+			match v.parse() {
+				Ok(true) => {
+					settings.trusted_proxies =
+						vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]
+				}
+				Ok(false) => {}
+				Err(_) => {
+					log::warn!("unknown value for WATAME_TRUST_PROXY, must be 'true' or 'false'")
+				}
+			}
+		}
+		if let Ok(v) = std::env::var("WATAME_TRUSTED_PROXIES") {
+			for net in v.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+				match net.parse() {
+					Ok(net) => settings.trusted_proxies.push(net),
+					Err(_) => {
+						log::error!(
+							"invalid address or CIDR in WATAME_TRUSTED_PROXIES: '{}'",
+							net
+						);
+						std::process::exit(1);
+					}
+				}
 			}
 		}
 		if let Ok(v) = std::env::var("WATAME_USE_HTTPS") {
@@ -122,27 +459,152 @@ impl Settings {
 			settings.cert = v;
 		}
 
+		// Without an explicit public URL assume we are being accessed directly
+		if settings.public_url.is_empty() {
+			let scheme = if settings.use_https { "https" } else { "http" };
+			settings.public_url = format!("{}://{}", scheme, settings.server_host);
+		}
+		settings.public_url = settings.public_url.trim_end_matches('/').to_owned();
+
 		settings.merge_cli_opts(CliOptions::from_args());
 
 		settings
 	}
 
+	/// Check the settings we can without connecting to anything, logging every
+	/// problem found and exiting rather than panicking somewhere later on
+	pub fn validate(&self) {
+		use redis::IntoConnectionInfo;
+
+		let mut errors = Vec::new();
+		if let Err(e) = self.redis_uri.as_str().into_connection_info() {
+			errors.push(format!("WATAME_REDIS_URI is not a valid redis URI ({})", e));
+		}
+		if self.database_host.is_empty() {
+			errors.push("WATAME_DB_HOST must be set".to_owned());
+		}
+		if self.database_port == 0 {
+			errors.push("WATAME_DB_PORT must not be 0".to_owned());
+		}
+		if self.database_name.is_empty() {
+			errors.push("WATAME_DB_NAME must be set".to_owned());
+		}
+		if self.database_credentials.0.is_empty() {
+			errors.push("WATAME_DB_USER must be set".to_owned());
+		}
+		// Only the server and create-folders touch storage
+		if matches!(self.action, Action::RunServer | Action::CreateFolders) {
+			if let Err(e) = std::fs::create_dir_all(&self.storage_root) {
+				errors.push(format!(
+					"WATAME_STORAGE_ROOT '{}' can't be created ({})",
+					self.storage_root, e
+				));
+			}
+		}
+		if matches!(self.action, Action::RunServer) && self.use_https {
+			for (var, path) in [
+				("WATAME_CERT", &self.cert),
+				("WATAME_PRIV_KEY", &self.priv_key),
+			] {
+				if !std::path::Path::new(path).is_file() {
+					errors.push(format!("{} '{}' does not exist", var, path));
+				}
+			}
+		}
+
+		if !errors.is_empty() {
+			for e in errors {
+				log::error!("{}", e);
+			}
+			std::process::exit(1);
+		}
+	}
+
 	fn merge_cli_opts(&mut self, opts: CliOptions) {
 		self.action = opts.action;
+		self.dry_run = opts.dry_run;
 	}
 }
 
 #[derive(Clone)]
 pub struct RunSettings {
 	pub storage_root: String,
+	pub public_url: String,
+	pub default_pfp: String,
 	pub max_payload: usize,
+	pub ts_config: String,
+	pub banned_tags: Vec<String>,
+	pub default_ratings: Vec<Rating>,
+	pub session_ttl: u64,
+	pub cookie_auth: bool,
+	pub max_concurrent_uploads: u32,
+	pub reencode_quality: u8,
+	pub transcode_to: Option<ImageExtension>,
+	pub max_group_images: usize,
+	pub allowed_formats: Vec<ImageExtension>,
+	pub max_search_limit: u32,
+	pub max_search_tags: usize,
+	pub password_policy: PasswordPolicy,
+	pub registration_open: bool,
+	pub user_delete_policy: UserDeletePolicy,
+	pub login_max_attempts: u32,
+	pub login_lockout: u64,
+	pub purge_grace: u64,
+	pub purge_concurrency: usize,
+	pub trusted_proxies: Vec<Cidr>,
+	pub query_timeout: u64,
 }
 
 impl RunSettings {
 	pub fn from(settings: &Settings) -> Self {
 		Self {
 			storage_root: settings.storage_root.clone(),
+			public_url: settings.public_url.clone(),
+			default_pfp: settings.default_pfp.clone(),
 			max_payload: settings.max_payload,
+			ts_config: settings.ts_config.clone(),
+			banned_tags: settings.banned_tags.clone(),
+			default_ratings: settings.default_ratings.clone(),
+			session_ttl: settings.session_ttl,
+			cookie_auth: settings.cookie_auth,
+			max_concurrent_uploads: settings.max_concurrent_uploads,
+			reencode_quality: settings.reencode_quality,
+			transcode_to: settings.transcode_to,
+			max_group_images: settings.max_group_images,
+			allowed_formats: settings.allowed_formats.clone(),
+			max_search_limit: settings.max_search_limit,
+			max_search_tags: settings.max_search_tags,
+			password_policy: settings.password_policy.clone(),
+			registration_open: settings.registration_open,
+			user_delete_policy: settings.user_delete_policy,
+			login_max_attempts: settings.login_max_attempts,
+			login_lockout: settings.login_lockout,
+			purge_grace: settings.purge_grace,
+			purge_concurrency: settings.purge_concurrency,
+			trusted_proxies: settings.trusted_proxies.clone(),
+			query_timeout: settings.query_timeout,
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct PasswordPolicy {
+	pub min_length: usize,
+	/// Upper bound so that hashing a password can't be used to tie up the server
+	pub max_length: usize,
+	pub require_digit: bool,
+	pub require_upper: bool,
+	pub require_symbol: bool,
+}
+
+impl std::default::Default for PasswordPolicy {
+	fn default() -> Self {
+		Self {
+			min_length: 8,
+			max_length: 1024,
+			require_digit: false,
+			require_upper: false,
+			require_symbol: false,
 		}
 	}
 }